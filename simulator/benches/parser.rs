@@ -0,0 +1,56 @@
+use std::convert::TryFrom;
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use simulator::parser::{ParseMode, Request, Session, Tagged};
+
+#[path = "../tests/common/reference.rs"]
+mod reference;
+
+const LINES: &[(&str, &[u8])] = &[
+    ("get", b"GET SERVO\n"),
+    ("set", b"SET RED_LED 1000\r\n"),
+    ("batch", b"GET RED_LED,YELLOW_LED,GREEN_LED,SERVO,TEMP_AND_HUM,SELF_TEST\n"),
+    ("bad_verb", b"FOO BAR\n"),
+    ("bad_syntax", b"GET SERVO"),
+];
+
+fn request(c: &mut Criterion) {
+    let mut group = c.benchmark_group("Request::try_from");
+    for (name, line) in LINES {
+        group.bench_function(*name, |b| b.iter(|| Request::try_from(black_box(*line))));
+    }
+    // No request in it, from any start: the worst case of a parser that retries each one
+    let long = [&[b'A'; 254][..], b" \n"].concat();
+    group.bench_function("long_bad_line", |b| b.iter(|| Request::try_from(black_box(&long[..]))));
+    group.finish();
+
+    let mut group = c.benchmark_group("reference");
+    for (name, line) in LINES {
+        group.bench_function(*name, |b| b.iter(|| reference::parse(black_box(line))));
+    }
+    group.finish();
+}
+
+fn tagged(c: &mut Criterion) {
+    c.bench_function("Tagged<Request>::try_from", |b| {
+        b.iter(|| Tagged::<Request>::try_from(black_box(&b"#1234 SET SERVO 90\n"[..])))
+    });
+}
+
+fn session(c: &mut Criterion) {
+    let stream: Vec<u8> = LINES.iter().flat_map(|(_, line)| line.iter().copied()).chain(*b"\n").collect();
+
+    for (name, mode) in [("strict", ParseMode::Strict), ("firmware", ParseMode::Firmware)] {
+        c.bench_function(&format!("Session::push/{}", name), |b| {
+            let mut session = Session::<256>::new(mode);
+            b.iter(|| {
+                for &c in &stream {
+                    black_box(session.push(c));
+                }
+            })
+        });
+    }
+}
+
+criterion_group!(benches, request, tagged, session);
+criterion_main!(benches);
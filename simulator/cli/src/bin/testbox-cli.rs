@@ -0,0 +1,154 @@
+use std::{borrow::Cow, path::PathBuf, process::ExitCode};
+
+use clap::Parser;
+use rustyline::{
+    completion::{Completer, Pair}, error::ReadlineError, highlight::Highlighter, hint::{Hint, Hinter},
+    validate::Validator, Context, Editor, Helper,
+};
+
+use simulator_cli::{check, complete, connection::Options, pretty};
+
+const HELP: &str = "\
+Requests are checked before they are sent, e.g. `GET SERVO`, `#4 SET RED_LED 10`.
+Tab completes verbs and nouns. Other commands:
+  !<line>   send the line as it is, without checking it
+  .help     show this help
+  .quit     exit, like Ctrl-D";
+
+/// Interactive client for a TestBox board or simulator
+#[derive(Parser)]
+struct Args {
+    #[command(flatten)]
+    connection: Options,
+
+    /// File to keep the history in, `~/.testbox-cli-history` by default
+    #[arg(long, value_name = "PATH")]
+    history: Option<PathBuf>,
+}
+
+struct Range(String);
+
+impl Hint for Range {
+    fn display(&self) -> &str {
+        &self.0
+    }
+
+    fn completion(&self) -> Option<&str> {
+        None
+    }
+}
+
+struct Protocol;
+
+impl Completer for Protocol {
+    type Candidate = Pair;
+
+    fn complete(&self, line: &str, pos: usize, _: &Context<'_>) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let (start, candidates) = complete::complete(line, pos);
+        let pairs = candidates.into_iter()
+            .map(|c| Pair { display: c.trim_end().into(), replacement: c })
+            .collect();
+        Ok((start, pairs))
+    }
+}
+
+impl Hinter for Protocol {
+    type Hint = Range;
+
+    fn hint(&self, line: &str, pos: usize, _: &Context<'_>) -> Option<Range> {
+        (pos == line.len()).then(|| complete::hint(line)).flatten().map(Range)
+    }
+}
+
+impl Highlighter for Protocol {
+    fn highlight_hint<'h>(&self, hint: &'h str) -> Cow<'h, str> {
+        Cow::Owned(format!("\x1b[2m<{}>\x1b[0m", hint))
+    }
+}
+
+impl Validator for Protocol {}
+
+impl Helper for Protocol {}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+    let target = args.connection.target();
+    let mut connection = match args.connection.open() {
+        Ok(connection) => connection,
+        Err(e) => {
+            eprintln!("Failed to connect to {}: {}", target, e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let mut editor = match Editor::new() {
+        Ok(editor) => editor,
+        Err(e) => {
+            eprintln!("Failed to set up the terminal: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    editor.set_helper(Some(Protocol));
+    let history = args.history.or_else(|| {
+        std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".testbox-cli-history"))
+    });
+    if let Some(history) = &history {
+        // Missing on the first run
+        let _ = editor.load_history(history);
+    }
+
+    println!("Connected to {}, `.help` for help", target);
+    loop {
+        let line = match editor.readline("testbox> ") {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => {
+                eprintln!("Failed to read the line: {}", e);
+                break;
+            }
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let _ = editor.add_history_entry(line);
+
+        let (line, request) = match line {
+            ".quit" => break,
+            ".help" => {
+                println!("{}", HELP);
+                continue;
+            }
+            raw if raw.starts_with('!') => (raw[1..].to_string(), None),
+            // Verbs and nouns are completed whatever their case, and sent upper case
+            line => match check::check(&line.to_uppercase()) {
+                Ok(request) => (line.to_uppercase(), Some(request)),
+                Err(e) => {
+                    println!("{}", e);
+                    continue;
+                }
+            },
+        };
+
+        match connection.request(&line) {
+            Ok(response) => match request {
+                Some(request) => pretty::render(&request, &response).iter().for_each(|l| println!("{}", l)),
+                None => print!("{}", String::from_utf8_lossy(&response)),
+            },
+            Err(e) => {
+                eprintln!("Failed to get a response from {}: {}", target, e);
+                if e.kind() != std::io::ErrorKind::TimedOut {
+                    break;
+                }
+            }
+        }
+    }
+
+    if let Some(history) = &history {
+        if let Err(e) = editor.save_history(history) {
+            eprintln!("Failed to save the history to {}: {}", history.display(), e);
+        }
+    }
+    ExitCode::SUCCESS
+}
@@ -0,0 +1,208 @@
+use std::{path::PathBuf, process::ExitCode, thread, time::{Duration, Instant}};
+
+use clap::{Parser, Subcommand};
+use serde_json::{json, Map};
+
+use simulator::{
+    lease::{Holder, LeaseMode, LeaseRequest, Token},
+    parser::{Request, RequestNoun, Response, ResponseError},
+};
+use simulator_cli::{
+    check, connection::{self, Connection, Options, Target}, discovery::{self, Probe}, script::{self, exchange, Failure}
+};
+
+/// How often the self test is polled while waiting for it
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Longest a self test is waited for
+const SELF_TEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// One-shot requests to a TestBox board or simulator, for scripts. Values are
+/// printed one per line, and the exit code says what went wrong: 1 connection
+/// failure or unexpected response, 2 invalid arguments, 3 timeout, 4 no board
+/// found, 10 to 15 `ERR BAD_SYNTAX`, `BAD_VERB`, `BAD_NOUN`, `BAD_VALUE`,
+/// `BAD_CHECKSUM` or `LEASED`.
+#[derive(Parser)]
+struct Args {
+    #[command(flatten)]
+    connection: Options,
+
+    /// Print the result, or the failure, as a JSON object
+    #[arg(long, global = true)]
+    json: bool,
+
+    /// Lease holder to act for, e.g. a CI job, so requests go through its lease
+    #[arg(long, global = true, env = "TESTBOX_HOLDER", value_parser = check::holder)]
+    holder: Option<Holder>,
+
+    /// Token of the holder's lease, as printed when it was taken
+    #[arg(long, global = true, env = "TESTBOX_TOKEN", value_parser = check::token, requires = "holder")]
+    token: Option<Token>,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Print the board's identity
+    Id,
+    /// Read nouns, e.g. `servo` or `temp-and-hum`
+    Get {
+        #[arg(required = true, value_parser = check::noun)]
+        nouns: Vec<RequestNoun>,
+    },
+    /// Set a noun, and print the value it was set to
+    Set {
+        #[arg(value_parser = check::noun)]
+        noun: RequestNoun,
+        #[arg(allow_negative_numbers = true)]
+        value: i64,
+    },
+    /// Start the self test
+    SelfTest {
+        /// Wait until it is done, up to a minute
+        #[arg(long)]
+        wait: bool,
+    },
+    /// Read the identity and every noun that can be read
+    Dump,
+    /// Show who holds the lease on the board, or take or release it for `--holder`
+    Lease {
+        #[command(subcommand)]
+        action: Option<LeaseAction>,
+    },
+    /// Find the boards on the local serial ports, and print their port and ID
+    Discover {
+        /// Ports to probe instead of all USB serial adapters
+        ports: Vec<PathBuf>,
+        /// Seconds a board is given to boot after its port is opened, which resets it
+        #[arg(long, value_name = "SECONDS", default_value = "1.5", value_parser = connection::seconds)]
+        settle: Duration,
+    },
+}
+
+#[derive(Subcommand)]
+enum LeaseAction {
+    /// Take or renew a lease nobody else holds, which refuses all other clients
+    Exclusive { seconds: u32 },
+    /// Take or renew a lease others may hold too, which refuses `SET` from other clients
+    Shared { seconds: u32 },
+    /// Give the lease up
+    Release,
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+
+    let result = match &args.command {
+        Command::Discover { ports, settle } => discover(ports, *settle),
+        command => args.connection.open()
+            .map_err(Failure::from)
+            .and_then(|mut connection| {
+                // Boards don't hold leases, only simulators and bridges do
+                let lease = match args.connection.target() {
+                    Target::Serial(_) => None,
+                    _ => args.holder.zip(args.token),
+                };
+                run(&mut connection, command, args.holder, lease)
+            }),
+    };
+
+    match result {
+        Ok(values) if args.json => {
+            let object: Map<_, _> = values.iter().map(|(key, response)| (key.clone(), script::json(response))).collect();
+            println!("{}", serde_json::Value::Object(object));
+            ExitCode::SUCCESS
+        }
+        Ok(values) => {
+            for (key, response) in &values {
+                match args.command {
+                    Command::Dump | Command::Discover { .. } => println!("{} {}", key, script::text(response)),
+                    _ => println!("{}", script::text(response)),
+                }
+            }
+            ExitCode::SUCCESS
+        }
+        Err(failure) => {
+            if args.json {
+                println!("{}", json!({ "error": failure.kind(), "message": failure.to_string() }));
+            } else {
+                eprintln!("testbox: {}", failure);
+            }
+            ExitCode::from(failure.exit_code())
+        }
+    }
+}
+
+/// Responses to the command, by the key they are printed under. With a
+/// lease, the connection first acts for its holder.
+fn run(
+    connection: &mut Connection, command: &Command, holder: Option<Holder>, lease: Option<(Holder, Token)>
+) -> Result<Vec<(String, Response)>, Failure> {
+    if let Some((holder, token)) = lease {
+        // Fails if the holder's lease expired, or with firmware that doesn't know leases, the
+        // request may still go through without it
+        match exchange(connection, &Request::Lease(LeaseRequest::As(holder, token))) {
+            Ok(_) | Err(Failure::Error(ResponseError::BadValue | ResponseError::BadVerb)) => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    match command {
+        Command::Id => Ok(vec![("id".into(), exchange(connection, &Request::Id)?)]),
+        // One request per noun, as the firmware doesn't take batches
+        Command::Get { nouns } => nouns.iter()
+            .map(|&noun| Ok((script::key(noun), exchange(connection, &Request::Get(noun))?)))
+            .collect(),
+        Command::Set { noun, value } => {
+            let request = check::check(&Request::Set(*noun, *value).to_string()).map_err(Failure::Invalid)?;
+            Ok(vec![(script::key(*noun), exchange(connection, &request.inner)?)])
+        }
+        Command::SelfTest { wait } => {
+            let key = script::key(RequestNoun::SelfTest);
+            let mut state = exchange(connection, &Request::Set(RequestNoun::SelfTest, 1))?;
+
+            let deadline = Instant::now() + SELF_TEST_TIMEOUT;
+            while *wait && matches!(state, Response::SelfTest(true, _)) {
+                if Instant::now() >= deadline {
+                    return Err(Failure::Timeout);
+                }
+                thread::sleep(POLL_INTERVAL);
+                state = exchange(connection, &Request::Get(RequestNoun::SelfTest))?;
+            }
+            Ok(vec![(key, state)])
+        }
+        Command::Dump => {
+            let mut values = vec![("id".into(), exchange(connection, &Request::Id)?)];
+            for noun in check::nouns("GET") {
+                values.push((script::key(noun), exchange(connection, &Request::Get(noun))?));
+            }
+            Ok(values)
+        }
+        Command::Lease { action } => {
+            let holder = || holder.ok_or_else(|| Failure::Invalid("a lease is taken for --holder or TESTBOX_HOLDER".into()));
+            let request = match action {
+                None => LeaseRequest::Status,
+                Some(LeaseAction::Exclusive { seconds }) => LeaseRequest::Acquire(LeaseMode::Exclusive, holder()?, *seconds),
+                Some(LeaseAction::Shared { seconds }) => LeaseRequest::Acquire(LeaseMode::Shared, holder()?, *seconds),
+                Some(LeaseAction::Release) => LeaseRequest::Release(holder()?),
+            };
+            Ok(vec![("lease".into(), exchange(connection, &Request::Lease(request))?)])
+        }
+        Command::Discover { .. } => unreachable!("discovery doesn't connect"),
+    }
+}
+
+/// IDs of the boards found, by port
+fn discover(ports: &[PathBuf], settle: Duration) -> Result<Vec<(String, Response)>, Failure> {
+    let ports = match ports {
+        [] => discovery::candidates()?,
+        ports => ports.to_vec(),
+    };
+    let found = discovery::discover(&ports, Probe { settle, ..Probe::default() });
+    if found.is_empty() {
+        return Err(Failure::NotFound);
+    }
+    Ok(found.into_iter().map(|found| (found.path.display().to_string(), Response::Id(found.id))).collect())
+}
@@ -0,0 +1,96 @@
+//! Checks of requests before they are sent, against the protocol definitions
+//! of the simulator, with messages that say what is wrong.
+
+use std::convert::TryFrom;
+
+use simulator::{
+    lease::{self, Holder, Token, HOLDER_LEN},
+    parser::{Request, RequestNoun, ResponseError, Tagged, VERBS},
+    testbox::TestBox,
+};
+
+/// Nouns a verb takes, empty for verbs without a noun
+pub fn nouns(verb: &str) -> Vec<RequestNoun> {
+    RequestNoun::ALL.into_iter()
+        .filter(|&noun| match verb {
+            "GET" => TestBox::describe(noun).gettable,
+            "SET" => TestBox::describe(noun).settable,
+            "DESCRIBE" => true,
+            _ => false,
+        })
+        .collect()
+}
+
+/// Noun named on the command line, like `red-led` or `RED_LED`
+pub fn noun(name: &str) -> Result<RequestNoun, String> {
+    RequestNoun::try_from(name.to_uppercase().replace('-', "_").as_bytes())
+        .map_err(|_| format!("unknown noun '{}'", name))
+}
+
+/// Lease holder named on the command line
+pub fn holder(name: &str) -> Result<Holder, String> {
+    Holder::try_from(name.as_bytes())
+        .map_err(|_| format!("invalid holder '{}', expected up to {} letters, digits, '-', '_', '.' or ':'", name, HOLDER_LEN))
+}
+
+/// Lease token given on the command line, as printed when the lease was taken
+pub fn token(s: &str) -> Result<Token, String> {
+    lease::token(s.as_bytes()).map_err(|_| format!("invalid token '{}', expected up to 16 hexadecimal digits", s))
+}
+
+/// Smallest and largest value a noun can be set to
+pub fn range(noun: RequestNoun) -> Option<(i64, i64)> {
+    TestBox::describe(noun).range.map(|(min, max, _)| (min, max))
+}
+
+/// Parses a request line, without its line ending, like the simulator in
+/// strict mode. Unlike the simulator, values out of range are refused
+/// instead of clamped.
+pub fn check(line: &str) -> Result<Tagged<Request>, String> {
+    let request = Tagged::<Request>::try_from(format!("{}\n", line).as_bytes())
+        .map_err(|e| explain(line, e.inner))?;
+
+    let items: Vec<(RequestNoun, i64)> = match request.inner {
+        Request::Set(noun, value) => vec![(noun, value)],
+        Request::SetMany(items) => items.iter().collect(),
+        _ => Vec::new(),
+    };
+    for (noun, value) in items {
+        if let Some((min, max)) = range(noun).filter(|(min, max)| !(*min..=*max).contains(&value)) {
+            return Err(format!("{} takes values from {} to {}, not {}", <&str>::from(noun), min, max, value));
+        }
+    }
+
+    Ok(request)
+}
+
+/// Says what is wrong with a line the parser refused with `error`
+fn explain(line: &str, error: ResponseError) -> String {
+    let payload = match line.strip_prefix('#') {
+        Some(tagged) => tagged.split_once(' ').map_or("", |(_, payload)| payload),
+        None => line,
+    };
+    let mut tokens = payload.split(' ');
+    let verb = tokens.next().unwrap_or_default();
+
+    match error {
+        ResponseError::BadVerb => format!("unknown verb '{}', expected one of {}", verb, VERBS.join(", ")),
+        ResponseError::BadNoun | ResponseError::BadValue if verb == "LEASE" => {
+            "LEASE takes EXCLUSIVE or SHARED with a holder and seconds, AS with a holder and its token, RELEASE with a holder, or nothing".into()
+        }
+        ResponseError::BadNoun => {
+            let expected: Vec<&str> = nouns(verb).into_iter().map(<&str>::from).collect();
+            match expected[..] {
+                [] => format!("{} takes no noun", verb),
+                _ => format!("{} takes one of {}", verb, expected.join(", ")),
+            }
+        }
+        ResponseError::BadValue if verb == "SET" => "SET takes a noun and an integer value, e.g. SET SERVO 90".into(),
+        ResponseError::BadValue => format!("{} takes no value", verb),
+        ResponseError::BadSyntax | ResponseError::BadChecksum => {
+            "not a request: tokens are separated by single spaces, and a tag is '#<number> '".into()
+        }
+        ResponseError::Leased => "someone else holds a lease on the board".into(),
+        ResponseError::NoResponse => "the board didn't answer in time".into(),
+    }
+}
@@ -0,0 +1,53 @@
+//! Completion of verbs and nouns, from the protocol definitions.
+
+use simulator::parser::VERBS;
+
+use crate::check::{nouns, range};
+
+/// Completions of the word before `pos`: where the word starts, and what it
+/// can be replaced with. Words are matched regardless of case.
+pub fn complete(line: &str, pos: usize) -> (usize, Vec<String>) {
+    let (offset, payload) = untagged(&line[..pos]);
+    let start = payload.rfind([' ', ',']).map_or(0, |i| i + 1);
+    let word = payload[start..].to_uppercase();
+    let matching = |candidates: Vec<String>| -> Vec<String> {
+        candidates.into_iter().filter(|c| c.starts_with(&word)).collect()
+    };
+
+    let verb = payload.split(' ').next().unwrap_or_default().to_uppercase();
+    let candidates = match payload[..start].matches(' ').count() {
+        0 => VERBS.iter()
+            .map(|&v| if nouns(v).is_empty() && !matches!(v, "FRAMING" | "LEASE") { v.to_string() } else { format!("{} ", v) })
+            .collect(),
+        1 if verb == "FRAMING" => vec!["PLAIN".into(), "CHECKSUM".into()],
+        1 if verb == "LEASE" => ["EXCLUSIVE ", "SHARED ", "AS ", "RELEASE "].map(String::from).into(),
+        // Values of a batch are given after `=`, that of a single noun after a space
+        1 if verb == "SET" && payload[start..].contains('=') => Vec::new(),
+        1 if verb == "SET" && payload[..start].ends_with(',') => {
+            nouns(&verb).into_iter().map(|n| format!("{}=", <&str>::from(n))).collect()
+        }
+        1 => nouns(&verb).into_iter().map(|n| <&str>::from(n).to_string()).collect(),
+        _ => Vec::new(),
+    };
+
+    (offset + start, matching(candidates))
+}
+
+/// What the value at the end of `line` can be, e.g. `0 to 180` after `SET SERVO `
+pub fn hint(line: &str) -> Option<String> {
+    let (_, payload) = untagged(line);
+    let noun = match payload.strip_prefix("SET ")? {
+        batch if batch.contains(',') || batch.ends_with('=') => batch.strip_suffix('=')?.rsplit(',').next()?,
+        single => single.strip_suffix(' ')?,
+    };
+    let noun = nouns("SET").into_iter().find(|&n| <&str>::from(n) == noun)?;
+    range(noun).map(|(min, max)| format!("{} to {}", min, max))
+}
+
+/// Offset of the request after its tag, if any, and the request
+fn untagged(line: &str) -> (usize, &str) {
+    match line.strip_prefix('#').and_then(|tagged| tagged.split_once(' ')) {
+        Some((tag, payload)) if tag.bytes().all(|c| c.is_ascii_digit()) => (tag.len() + 2, payload),
+        _ => (0, line),
+    }
+}
@@ -0,0 +1,251 @@
+//! Connection to a board or simulator, over TCP, a Unix socket or a serial port.
+
+use std::{
+    fmt, io::{self, Read, Write}, net::TcpStream, path::{Path, PathBuf}, time::{Duration, Instant}
+};
+
+use simulator::parser::{self, Framing};
+
+/// How long a single read may block, so the response timeout is checked in time
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Time a board is given to boot after its port is opened
+pub const SETTLE: Duration = Duration::from_millis(1500);
+
+#[derive(Clone, Debug)]
+pub enum Target {
+    /// A simulator or bridge, as `host:port`
+    Tcp(String),
+    #[cfg(unix)]
+    Unix(PathBuf),
+    /// A board on a serial port
+    Serial(PathBuf),
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Target::Tcp(addr) => write!(f, "{}", addr),
+            #[cfg(unix)]
+            Target::Unix(path) => write!(f, "{}", path.display()),
+            Target::Serial(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+/// Where to connect, as given on the command line
+#[derive(clap::Args)]
+pub struct Options {
+    /// Host of the simulator or bridge
+    #[arg(long, default_value = "localhost")]
+    host: String,
+
+    /// TCP port of the simulator or bridge
+    #[arg(long, default_value_t = 12345)]
+    port: u16,
+
+    /// Connect to a simulator listening on a Unix domain socket at this path instead
+    #[cfg(unix)]
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["host", "port"])]
+    unix_socket: Option<PathBuf>,
+
+    /// Talk to a board on this serial port instead
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["host", "port", "unix_socket"])]
+    serial: Option<PathBuf>,
+
+    /// Seconds to wait for a response
+    #[arg(long, value_name = "SECONDS", default_value = "1", value_parser = seconds)]
+    timeout: Duration,
+}
+
+impl Options {
+    pub fn target(&self) -> Target {
+        #[cfg(unix)]
+        if let Some(path) = &self.unix_socket {
+            return Target::Unix(path.clone());
+        }
+        match &self.serial {
+            Some(path) => Target::Serial(path.clone()),
+            None => Target::Tcp(format!("{}:{}", self.host, self.port)),
+        }
+    }
+
+    pub fn open(&self) -> io::Result<Connection> {
+        Connection::open(&self.target(), self.timeout)
+    }
+}
+
+/// Parses a duration given in seconds, like `1.5`
+pub fn seconds(s: &str) -> Result<Duration, String> {
+    s.parse::<f64>().ok()
+        .and_then(|s| Duration::try_from_secs_f64(s).ok())
+        .ok_or_else(|| format!("invalid duration '{}'", s))
+}
+
+/// Opens the port of a board at 115200 8N1. Opening the port resets most
+/// boards, which then print boot messages at another baud rate, so the board
+/// is given `settle` to boot and whatever arrives meanwhile is dropped.
+pub fn open_serial(path: &Path, settle: Duration) -> io::Result<Box<dyn serialport::SerialPort>> {
+    let mut port = serialport::new(path.to_string_lossy(), 115200)
+        .data_bits(serialport::DataBits::Eight)
+        .parity(serialport::Parity::None)
+        .stop_bits(serialport::StopBits::One)
+        .flow_control(serialport::FlowControl::None)
+        .timeout(POLL_INTERVAL)
+        .open()?;
+
+    let deadline = Instant::now() + settle;
+    let mut buffer = [0u8; 256];
+    while Instant::now() < deadline {
+        match port.read(&mut buffer) {
+            Ok(_) => {}
+            Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(port)
+}
+
+trait Port: Read + Write + Send {
+    /// Makes reads return right away when nothing was received, or wait for
+    /// up to `POLL_INTERVAL` again
+    fn set_waiting(&mut self, wait: bool) -> io::Result<()>;
+}
+
+impl Port for TcpStream {
+    fn set_waiting(&mut self, wait: bool) -> io::Result<()> {
+        self.set_nonblocking(!wait)
+    }
+}
+
+#[cfg(unix)]
+impl Port for std::os::unix::net::UnixStream {
+    fn set_waiting(&mut self, wait: bool) -> io::Result<()> {
+        self.set_nonblocking(!wait)
+    }
+}
+
+impl Port for Box<dyn serialport::SerialPort> {
+    fn set_waiting(&mut self, wait: bool) -> io::Result<()> {
+        Ok(self.set_timeout(if wait { POLL_INTERVAL } else { Duration::ZERO })?)
+    }
+}
+
+pub struct Connection {
+    port: Box<dyn Port>,
+    /// Framing of the lines in both directions, as switched to with `FRAMING`
+    framing: Framing,
+    timeout: Duration,
+    /// What arrived so far of the answer to a request that timed out
+    late: Option<Vec<u8>>,
+}
+
+impl Connection {
+    /// Connects to `target`. Requests not answered within `timeout` fail.
+    pub fn open(target: &Target, timeout: Duration) -> io::Result<Self> {
+        let port: Box<dyn Port> = match target {
+            Target::Tcp(addr) => {
+                let stream = TcpStream::connect(addr)?;
+                stream.set_read_timeout(Some(POLL_INTERVAL))?;
+                stream.set_nodelay(true)?;
+                Box::new(stream)
+            }
+            #[cfg(unix)]
+            Target::Unix(path) => {
+                let stream = std::os::unix::net::UnixStream::connect(path)?;
+                stream.set_read_timeout(Some(POLL_INTERVAL))?;
+                Box::new(stream)
+            }
+            Target::Serial(path) => Box::new(open_serial(path, SETTLE)?),
+        };
+
+        Ok(Self { port, framing: Framing::Plain, timeout, late: None })
+    }
+
+    /// Sends a request line, without its line ending, and returns the response
+    /// up to its final `OK` or `ERR` line, with the framing removed
+    pub fn request(&mut self, line: &str) -> io::Result<Vec<u8>> {
+        // Waits for the late answer a while longer, rather than take it for this one
+        if let Some(partial) = self.late.take() {
+            self.receive(partial)?.ok();
+        }
+        self.drain()?;
+
+        self.port.write_all(&self.framing.encode(format!("{}\r\n", line).into_bytes()))?;
+
+        let response = match self.receive(Vec::new())? {
+            Ok(response) => response,
+            Err(partial) => {
+                self.late = Some(partial);
+                return Err(io::Error::new(io::ErrorKind::TimedOut, format!("no response within {:?}", self.timeout)));
+            }
+        };
+
+        let response = self.unframe(response);
+        if let Some(framing) = switched_to(line, &response) {
+            self.framing = framing;
+        }
+        Ok(response)
+    }
+
+    /// Reads the response lines that follow `response`, the partial response on timeout
+    fn receive(&mut self, mut response: Vec<u8>) -> io::Result<Result<Vec<u8>, Vec<u8>>> {
+        let deadline = Instant::now() + self.timeout;
+        let mut buffer = [0u8; 256];
+
+        while !parser::is_complete(&response) {
+            if Instant::now() >= deadline {
+                return Ok(Err(response));
+            }
+            match self.port.read(&mut buffer) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => response.extend_from_slice(&buffer[..n]),
+                Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(Ok(response))
+    }
+
+    /// Drops whatever already arrived, so it isn't taken for the next response
+    fn drain(&mut self) -> io::Result<()> {
+        self.port.set_waiting(false)?;
+        let mut buffer = [0u8; 256];
+        let drained = loop {
+            match self.port.read(&mut buffer) {
+                Ok(0) => break Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(_) => {}
+                Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => break Ok(()),
+                Err(e) => break Err(e),
+            }
+        };
+        self.port.set_waiting(true)?;
+        drained
+    }
+
+    fn unframe(&self, response: Vec<u8>) -> Vec<u8> {
+        if self.framing == Framing::Plain {
+            return response;
+        }
+        response.split_inclusive(|&c| c == b'\n')
+            .flat_map(|line| {
+                let mut line = line.to_vec();
+                match self.framing.decode(&mut line) {
+                    // Restores the line ending the checksum took the place of
+                    Ok(payload) => [payload.strip_suffix(b"\n").unwrap_or(payload), b"\r\n"].concat(),
+                    Err(_) => line,
+                }
+            })
+            .collect()
+    }
+}
+
+/// Framing the board switched to, if `line` was an accepted `FRAMING` request
+fn switched_to(line: &str, response: &[u8]) -> Option<Framing> {
+    let request = parser::Tagged::<parser::Request>::try_from(format!("{}\n", line).as_bytes()).ok()?;
+    let (_, last) = parser::split_response(response);
+    match parser::Tagged::<parser::Response>::decode_line(&request.inner, last)?.inner {
+        parser::Response::Framing(framing) => Some(framing),
+        _ => None,
+    }
+}
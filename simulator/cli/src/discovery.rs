@@ -0,0 +1,112 @@
+//! Discovery of boards on the local serial ports. Every candidate port is
+//! opened at 115200 baud and asked for its `ID`, in parallel.
+//!
+//! Opening a port resets most boards, which then print boot messages at
+//! another baud rate, so the probe first lets the board settle and discards
+//! whatever arrived, then tries `ID` a few times. Lines that don't answer it
+//! are ignored, and noise in front of the answer is skipped.
+
+use std::{
+    io::{self, Read, Write}, path::{Path, PathBuf}, thread, time::{Duration, Instant}
+};
+
+use simulator::parser::{Request, Response};
+
+use crate::connection;
+
+/// How a port is probed
+#[derive(Debug, Clone, Copy)]
+pub struct Probe {
+    /// Time the board is given to boot after the port is opened
+    pub settle: Duration,
+    /// `ID` requests sent before giving up
+    pub attempts: u32,
+    /// Time each `ID` request is given to be answered
+    pub timeout: Duration,
+}
+
+impl Default for Probe {
+    fn default() -> Self {
+        Self { settle: connection::SETTLE, attempts: 3, timeout: Duration::from_millis(500) }
+    }
+}
+
+/// A port with a board on it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Found {
+    pub path: PathBuf,
+    /// What the board answered to `ID`
+    pub id: String,
+}
+
+/// USB serial adapters, where boards are plugged in. Built-in UARTs are left
+/// out, as there are many of them and probing them is slow.
+pub fn candidates() -> io::Result<Vec<PathBuf>> {
+    let ports = serialport::available_ports()?;
+    Ok(ports.into_iter()
+        .filter(|port| matches!(port.port_type, serialport::SerialPortType::UsbPort(_)))
+        .map(|port| PathBuf::from(port.port_name))
+        .collect())
+}
+
+/// Probes all `ports` at once, and returns those with a board on them, in order
+pub fn discover(ports: &[PathBuf], probe: Probe) -> Vec<Found> {
+    let probes: Vec<_> = ports.iter()
+        .map(|path| {
+            let path = path.clone();
+            thread::spawn(move || identify(&path, probe).ok().flatten().map(|id| Found { path, id }))
+        })
+        .collect();
+
+    probes.into_iter().filter_map(|probe| probe.join().ok().flatten()).collect()
+}
+
+/// ID of the board on the port at `path`, `None` if nothing there answers like a board
+pub fn identify(path: &Path, probe: Probe) -> io::Result<Option<String>> {
+    let mut port = connection::open_serial(path, probe.settle)?;
+
+    for _ in 0..probe.attempts {
+        // The line ending first ends whatever noise the board has buffered
+        port.write_all(format!("\r\n{}\r\n", Request::Id).as_bytes())?;
+
+        let mut id = None;
+        read_until(&mut port, Instant::now() + probe.timeout, |line| {
+            id = answer(line);
+            id.is_some()
+        })?;
+        if id.is_some() {
+            return Ok(id);
+        }
+    }
+    Ok(None)
+}
+
+/// Reads lines until `done` accepts one, or `deadline`
+fn read_until(port: &mut impl Read, deadline: Instant, mut done: impl FnMut(&[u8]) -> bool) -> io::Result<()> {
+    let mut buffer = Vec::new();
+    let mut chunk = [0u8; 256];
+
+    while Instant::now() < deadline {
+        match port.read(&mut chunk) {
+            Ok(n) => buffer.extend_from_slice(&chunk[..n]),
+            Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => continue,
+            Err(e) => return Err(e),
+        }
+        while let Some(end) = buffer.iter().position(|&c| c == b'\n') {
+            let line: Vec<u8> = buffer.drain(..=end).collect();
+            if done(&line) {
+                return Ok(());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// ID in `line`, if it answers `ID`, possibly after noise
+fn answer(line: &[u8]) -> Option<String> {
+    let start = line.windows(3).position(|w| w == b"OK ")?;
+    match Response::decode_line(&Request::Id, &line[start..])? {
+        Response::Id(id) => Some(id),
+        _ => None,
+    }
+}
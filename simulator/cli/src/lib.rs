@@ -0,0 +1,10 @@
+//! Client side of the TestBox protocol, shared by the command line tools: a
+//! connection to a board or simulator, checks of requests before they are
+//! sent, and renderings of the responses for people and for scripts.
+
+pub mod check;
+pub mod complete;
+pub mod connection;
+pub mod discovery;
+pub mod pretty;
+pub mod script;
@@ -0,0 +1,82 @@
+//! Readable renderings of the responses.
+
+use simulator::parser::{self, Request, RequestNoun, Response, ResponseError, Tagged};
+
+/// Lines describing what `request` was answered with. Falls back to the
+/// response as it was received if it can't be decoded.
+pub fn render(request: &Tagged<Request>, response: &[u8]) -> Vec<String> {
+    let (diagnostics, last) = parser::split_response(response);
+    let mut lines: Vec<String> = String::from_utf8_lossy(diagnostics).lines().map(String::from).collect();
+
+    match Tagged::<Response>::decode_line(&request.inner, last) {
+        Some(decoded) => {
+            let tag = decoded.tag.map(|tag| format!("#{} ", tag)).unwrap_or_default();
+            lines.extend(fields(&request.inner, decoded.inner).into_iter().map(|f| format!("{}{}", tag, f)));
+        }
+        None => lines.push(String::from_utf8_lossy(last).trim_end().into()),
+    }
+    lines
+}
+
+fn fields(request: &Request, response: Response) -> Vec<String> {
+    match (request, response) {
+        (_, Response::Error(e)) => vec![format!("error {}: {}", <&str>::from(e), explain(e))],
+        (Request::GetMany(nouns), Response::Many(responses)) => {
+            nouns.iter().zip(responses).flat_map(|(noun, r)| value(noun, r)).collect()
+        }
+        (Request::SetMany(items), Response::Many(responses)) => {
+            items.iter().zip(responses).flat_map(|((noun, _), r)| value(noun, r)).collect()
+        }
+        (Request::Get(noun) | Request::Set(noun, _), r) => value(*noun, r),
+        (_, Response::Id(id)) => vec![format!("board: {}", id)],
+        (_, Response::Framing(f)) => vec![format!("framing: {}", <&str>::from(f))],
+        (Request::Help, Response::Names(names)) => vec![format!("verbs: {}", names.join(", "))],
+        (_, Response::Names(names)) => vec![format!("nouns: {}", names.join(", "))],
+        (Request::Describe(noun), Response::Description(d)) => {
+            let access = match (d.gettable, d.settable) {
+                (true, true) => "read/write",
+                (false, true) => "write only",
+                _ => "read only",
+            };
+            let mut description = format!("{}: {}, {}", <&str>::from(*noun), <&str>::from(d.kind).to_lowercase(), access);
+            if let Some((min, max, def)) = d.range {
+                description += &format!(", {} to {}, {} at boot", min, max, def);
+            }
+            vec![description]
+        }
+        (_, Response::Lease(None)) => vec!["lease: none".into()],
+        (_, Response::Lease(Some(state))) => state.holders.iter()
+            .map(|(holder, left)| format!("lease: {} to {}, {} s left", <&str>::from(state.mode).to_lowercase(), holder, left))
+            .chain(state.token.map(|token| format!("token: {:016X}, for LEASE AS", token)))
+            .collect(),
+        (_, Response::Version(protocol, firmware)) => vec![format!("protocol: {}", protocol), format!("firmware: {}", firmware)],
+        (_, r) => vec![String::from_utf8_lossy(&Vec::<u8>::from(r)).trim_end().into()],
+    }
+}
+
+fn value(noun: RequestNoun, response: Response) -> Vec<String> {
+    let name: &str = noun.into();
+    match response {
+        Response::Value(v) => vec![format!("{}: {}", name, v)],
+        Response::TempAndHum(status, temperature, humidity) => vec![
+            format!("{} status: {}", name, status),
+            format!("{} temperature: {:.2} °C", name, temperature),
+            format!("{} humidity: {:.2} %", name, humidity),
+        ],
+        Response::SelfTest(true, progress) => vec![format!("{}: running, {}% done", name, progress)],
+        Response::SelfTest(false, _) => vec![format!("{}: not running", name)],
+        r => vec![format!("{}: {}", name, String::from_utf8_lossy(&Vec::<u8>::from(r)).trim_end())],
+    }
+}
+
+fn explain(e: ResponseError) -> &'static str {
+    match e {
+        ResponseError::BadSyntax => "the request is not well formed",
+        ResponseError::BadVerb => "unknown verb",
+        ResponseError::BadNoun => "unknown noun, or not one this verb takes",
+        ResponseError::BadValue => "invalid value",
+        ResponseError::BadChecksum => "the checksum doesn't match",
+        ResponseError::Leased => "someone else holds a lease on the board, LEASE says who",
+        ResponseError::NoResponse => "the board didn't answer in time",
+    }
+}
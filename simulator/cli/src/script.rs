@@ -0,0 +1,142 @@
+//! Requests made by scripts: their outcome maps to an exit code, and their
+//! responses render as bare values or JSON.
+
+use std::{fmt, io};
+
+use serde_json::{json, Value};
+use simulator::parser::{split_response, Request, RequestNoun, Response, ResponseError};
+
+use crate::connection::Connection;
+
+/// Why a request didn't get a good response
+#[derive(Debug)]
+pub enum Failure {
+    /// Refused before it was sent
+    Invalid(String),
+    /// The board or simulator couldn't be reached, or went away
+    Connection(io::Error),
+    /// No response in time
+    Timeout,
+    /// The response doesn't answer the request
+    Unexpected(String),
+    /// The board answered `ERR`
+    Error(ResponseError),
+    /// No board on any of the serial ports
+    NotFound,
+}
+
+impl Failure {
+    /// Exit code of the process. 2 is left to usage errors, as reported by clap.
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Failure::Connection(_) | Failure::Unexpected(_) => 1,
+            Failure::Invalid(_) => 2,
+            Failure::Timeout | Failure::Error(ResponseError::NoResponse) => 3,
+            Failure::NotFound => 4,
+            Failure::Error(ResponseError::BadSyntax) => 10,
+            Failure::Error(ResponseError::BadVerb) => 11,
+            Failure::Error(ResponseError::BadNoun) => 12,
+            Failure::Error(ResponseError::BadValue) => 13,
+            Failure::Error(ResponseError::BadChecksum) => 14,
+            Failure::Error(ResponseError::Leased) => 15,
+        }
+    }
+
+    /// Short name of the failure, for JSON output
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Failure::Invalid(_) => "INVALID",
+            Failure::Connection(_) => "CONNECTION",
+            Failure::Timeout => "TIMEOUT",
+            Failure::Unexpected(_) => "UNEXPECTED",
+            Failure::NotFound => "NOT_FOUND",
+            Failure::Error(e) => (*e).into(),
+        }
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Failure::Invalid(message) => write!(f, "{}", message),
+            Failure::Connection(e) => write!(f, "connection failed: {}", e),
+            Failure::Timeout => write!(f, "no response in time"),
+            Failure::Unexpected(line) => write!(f, "unexpected response {:?}", line),
+            Failure::Error(ResponseError::Leased) => {
+                write!(f, "someone else holds a lease on the board, `testbox lease` says who")
+            }
+            Failure::Error(ResponseError::NoResponse) => write!(f, "the board behind the bridge didn't answer in time"),
+            Failure::Error(e) => write!(f, "the board answered ERR {}", <&str>::from(*e)),
+            Failure::NotFound => write!(f, "no board found"),
+        }
+    }
+}
+
+impl From<io::Error> for Failure {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::TimedOut => Failure::Timeout,
+            _ => Failure::Connection(e),
+        }
+    }
+}
+
+/// Sends `request` and decodes the response. Diagnostic lines before it are dropped.
+pub fn exchange(connection: &mut Connection, request: &Request) -> Result<Response, Failure> {
+    let response = connection.request(&request.to_string())?;
+    let (_, last) = split_response(&response);
+
+    match Response::decode_line(request, last) {
+        Some(Response::Error(e)) => Err(Failure::Error(e)),
+        Some(response) => Ok(response),
+        None => Err(Failure::Unexpected(String::from_utf8_lossy(last).trim_end().into())),
+    }
+}
+
+/// Key of a noun in JSON output, e.g. `red_led`
+pub fn key(noun: RequestNoun) -> String {
+    <&str>::from(noun).to_lowercase()
+}
+
+/// A response as it is printed for a shell: the value alone, fields separated by spaces
+pub fn text(response: &Response) -> String {
+    match response {
+        Response::Id(id) => id.clone(),
+        Response::Value(v) => v.to_string(),
+        Response::TempAndHum(status, temperature, humidity) => format!("{} {:.2} {:.2}", status, temperature, humidity),
+        Response::SelfTest(active, progress) => format!("{} {}", if *active { "ACTIVE" } else { "INACTIVE" }, progress),
+        Response::Version(protocol, firmware) => format!("{} {}", protocol, firmware),
+        Response::Lease(state) => state.as_ref().map_or_else(|| "NONE".into(), |s| s.to_string()),
+        r => {
+            let line = Vec::<u8>::from(r.clone());
+            let line = String::from_utf8_lossy(&line);
+            line.trim_end().trim_start_matches("OK ").into()
+        }
+    }
+}
+
+/// A response as JSON: values are numbers, sensor readings and self test states objects
+pub fn json(response: &Response) -> Value {
+    match response {
+        Response::Id(id) => json!(id),
+        Response::Value(v) => json!(v),
+        Response::TempAndHum(status, temperature, humidity) => {
+            json!({ "status": status, "temperature": temperature, "humidity": humidity })
+        }
+        Response::SelfTest(active, progress) => json!({ "active": active, "progress": progress }),
+        Response::Version(protocol, firmware) => json!({ "protocol": protocol, "firmware": firmware }),
+        Response::Lease(None) => Value::Null,
+        Response::Lease(Some(state)) => {
+            let holders: serde_json::Map<_, _> = state.holders.iter()
+                .map(|(holder, left)| (holder.to_string(), json!(left)))
+                .collect();
+            let mut lease = json!({ "mode": <&str>::from(state.mode), "holders": holders });
+            if let Some(token) = state.token {
+                lease["token"] = json!(format!("{:016X}", token));
+            }
+            lease
+        }
+        Response::Many(responses) => responses.iter().map(json).collect(),
+        r => json!(text(r)),
+    }
+}
@@ -0,0 +1,76 @@
+//! Checks, completions and renderings of the client, and a connection to a
+//! simulated board.
+
+mod common;
+
+use std::time::Duration;
+
+use simulator_cli::{check::check, complete::{complete, hint}, connection::{Connection, Target}, pretty::render};
+
+#[test]
+fn requests_out_of_range_are_refused() {
+    assert!(check("SET SERVO 180").is_ok());
+    assert!(check("#3 SET RED_LED=0,SERVO=90").is_ok());
+    assert_eq!(check("SET SERVO 181").unwrap_err(), "SERVO takes values from 0 to 180, not 181");
+    assert_eq!(check("SET RED_LED=1,SERVO=-1").unwrap_err(), "SERVO takes values from 0 to 180, not -1");
+
+    assert!(check("FETCH SERVO").unwrap_err().starts_with("unknown verb 'FETCH'"));
+    assert!(check("SET TEMP_AND_HUM 1").unwrap_err().starts_with("SET takes one of "));
+    assert_eq!(check("ID SERVO").unwrap_err(), "ID takes no noun");
+}
+
+#[test]
+fn verbs_and_nouns_complete() {
+    let completions = |line: &str| complete(line, line.len());
+
+    assert_eq!(completions("GE"), (0, vec!["GET ".into()]));
+    assert_eq!(completions("#12 ve"), (4, vec!["VERSION".into()]));
+    assert_eq!(completions("GET TEMP"), (4, vec!["TEMP_AND_HUM".into()]));
+    assert_eq!(completions("GET SERVO,RED"), (10, vec!["RED_LED".into()]));
+    assert_eq!(completions("SET RED_LED=1,SER"), (14, vec!["SERVO=".into()]));
+    assert_eq!(completions("FRAMING "), (8, vec!["PLAIN".into(), "CHECKSUM".into()]));
+    // Only nouns that can be set
+    assert!(completions("SET TEMP").1.is_empty());
+    assert!(completions("SET SERVO 1").1.is_empty());
+
+    assert_eq!(hint("SET SERVO "), Some("0 to 180".into()));
+    assert_eq!(hint("SET RED_LED=1,SERVO="), Some("0 to 180".into()));
+    assert_eq!(hint("SET SERVO 9"), None);
+}
+
+#[test]
+fn responses_render_by_field() {
+    let request = check("GET SERVO,TEMP_AND_HUM").unwrap();
+    assert_eq!(render(&request, b"OK 90,OK 21.50 40.00\r\n"), [
+        "SERVO: 90", "TEMP_AND_HUM status: OK", "TEMP_AND_HUM temperature: 21.50 °C", "TEMP_AND_HUM humidity: 40.00 %",
+    ]);
+
+    let request = check("#7 GET SERVO").unwrap();
+    assert_eq!(render(&request, b"Failed to find noun\r\n#7 ERR BAD_NOUN\r\n"), [
+        "Failed to find noun", "#7 error BAD_NOUN: unknown noun, or not one this verb takes",
+    ]);
+    // Kept as received when it doesn't answer the request
+    assert_eq!(render(&request, b"#7 OK ACTIVE\r\n"), ["#7 OK ACTIVE"]);
+}
+
+#[test]
+fn connection_follows_framing_switches() {
+    let mut connection = Connection::open(&Target::Tcp(common::board()), Duration::from_secs(1)).unwrap();
+
+    assert_eq!(connection.request("SET SERVO 45").unwrap(), b"OK 45\r\n");
+    assert_eq!(connection.request("FRAMING CHECKSUM").unwrap(), b"OK CHECKSUM\r\n");
+    assert_eq!(connection.request("#2 GET SERVO").unwrap(), b"#2 OK 45\r\n");
+    assert_eq!(connection.request("FRAMING PLAIN").unwrap(), b"OK PLAIN\r\n");
+    assert_eq!(connection.request("GET SERVO").unwrap(), b"OK 45\r\n");
+}
+
+#[test]
+fn late_responses_are_not_taken_for_the_next_ones() {
+    let timeout = Duration::from_millis(200);
+    let mut connection = Connection::open(&Target::Tcp(common::slow_board(timeout*2)), timeout).unwrap();
+
+    let e = connection.request("GET SERVO").unwrap_err();
+    assert_eq!(e.kind(), std::io::ErrorKind::TimedOut);
+    assert_eq!(connection.request("SET SERVO 45").unwrap(), b"OK 45\r\n");
+    assert_eq!(connection.request("GET RED_LED").unwrap(), b"OK 0\r\n");
+}
@@ -0,0 +1,56 @@
+//! Boards for the client to talk to, each answering a single connection.
+
+#![allow(dead_code)] // Not every test uses every board
+
+use std::{io::{Read, Write}, net::{TcpListener, TcpStream}, thread, time::{Duration, Instant}};
+
+use simulator::{device::Device, parser::ParseMode, testbox::TestBox};
+
+fn serve(handle: impl FnOnce(TcpStream) + Send + 'static) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    thread::spawn(move || handle(listener.accept().unwrap().0));
+    addr
+}
+
+/// Address of a simulated board
+pub fn board() -> String {
+    board_parsing(ParseMode::Strict)
+}
+
+/// Address of a simulated board, parsing like `mode`
+pub fn board_parsing(mode: ParseMode) -> String {
+    board_with(mode, Duration::ZERO)
+}
+
+/// Address of a simulated board that answers its first request only after `delay`
+pub fn slow_board(delay: Duration) -> String {
+    board_with(ParseMode::Strict, delay)
+}
+
+fn board_with(mode: ParseMode, mut delay: Duration) -> String {
+    serve(move |mut stream| {
+        let mut device = Device::<256>::new(TestBox::new(1), mode, None, false);
+        let start = Instant::now();
+        let mut buffer = [0u8; 256];
+        while let Ok(n @ 1..) = stream.read(&mut buffer) {
+            device.receive(&buffer[..n], start.elapsed());
+            thread::sleep(std::mem::take(&mut delay));
+            while let Some(response) = device.transmit() {
+                stream.write_all(&response).unwrap();
+            }
+        }
+    })
+}
+
+/// Address of a board answering every request with `response`
+pub fn answering(response: &'static str) -> String {
+    serve(move |mut stream| {
+        let mut buffer = [0u8; 256];
+        while let Ok(n @ 1..) = stream.read(&mut buffer) {
+            for _ in buffer[..n].iter().filter(|&&c| c == b'\n') {
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        }
+    })
+}
@@ -0,0 +1,112 @@
+//! Discovery of and connections to boards on PTYs, as they behave on a USB
+//! serial adapter.
+
+#![cfg(target_os = "linux")]
+
+use std::{
+    fs::File, io::{Read, Write}, os::fd::{AsFd, OwnedFd}, path::PathBuf, thread, time::{Duration, Instant}
+};
+
+use nix::{poll::{poll, PollFd, PollFlags, PollTimeout}, pty::openpty, sys::termios, unistd::ttyname};
+
+use simulator::{device::Device, parser::ParseMode, testbox::TestBox};
+use simulator_cli::{connection::{Connection, Target}, discovery::{discover, identify, Found, Probe}};
+
+/// How long the PTYs are served, longer than any test
+const LIFETIME: Duration = Duration::from_secs(10);
+
+const PROBE: Probe = Probe {
+    settle: Duration::from_millis(100),
+    attempts: 3,
+    timeout: Duration::from_millis(300),
+};
+
+#[derive(Clone, Copy)]
+enum Peer {
+    /// A board that was just reset: it prints boot messages, and ignores its
+    /// input until it has booted
+    Board,
+    /// Something that sends back whatever it receives
+    Echo,
+    /// Nothing plugged in
+    Silent,
+}
+
+/// Path of a PTY with `peer` on the other end, and its slave side, which must
+/// be kept open
+fn pty(peer: Peer) -> (PathBuf, OwnedFd) {
+    let pty = openpty(None, None).unwrap();
+    let mut settings = termios::tcgetattr(&pty.slave).unwrap();
+    termios::cfmakeraw(&mut settings);
+    termios::tcsetattr(&pty.slave, termios::SetArg::TCSANOW, &settings).unwrap();
+    let path = ttyname(&pty.slave).unwrap();
+
+    let master = File::from(pty.master);
+    thread::spawn(move || serve(master, peer));
+    (path, pty.slave)
+}
+
+fn serve(mut master: File, peer: Peer) {
+    let start = Instant::now();
+    let booted = start + Duration::from_millis(250);
+    let mut device = Device::<256>::new(TestBox::new(1), ParseMode::Strict, None, false);
+    let mut buffer = [0u8; 256];
+
+    if let Peer::Board = peer {
+        master.write_all(b"\xaa\x55ets Jan  8 2013,rst cause:2, boot mode:(3,6)\r\n\xfe\x01load 0x4010f000").unwrap();
+    }
+
+    while start.elapsed() < LIFETIME {
+        let mut fds = [PollFd::new(master.as_fd(), PollFlags::POLLIN)];
+        if poll(&mut fds, PollTimeout::from(20u8)).unwrap() == 0 {
+            continue;
+        }
+        let Ok(n) = master.read(&mut buffer) else {
+            thread::sleep(Duration::from_millis(20));
+            continue;
+        };
+
+        match peer {
+            Peer::Board if Instant::now() < booted => {}
+            Peer::Board => {
+                device.receive(&buffer[..n], start.elapsed());
+                while let Some(response) = device.transmit() {
+                    master.write_all(&response).unwrap();
+                }
+            }
+            Peer::Echo => master.write_all(&buffer[..n]).unwrap(),
+            Peer::Silent => {}
+        }
+    }
+}
+
+#[test]
+fn boards_are_identified_through_boot_noise() {
+    let (board, _board) = pty(Peer::Board);
+    assert_eq!(identify(&board, PROBE).unwrap(), Some("ESP8266_WEMOS_D1MINI".into()));
+
+    let (echo, _echo) = pty(Peer::Echo);
+    assert_eq!(identify(&echo, PROBE).unwrap(), None);
+}
+
+#[test]
+fn only_ports_with_a_board_are_found() {
+    let ptys = [pty(Peer::Silent), pty(Peer::Board), pty(Peer::Echo), pty(Peer::Board)];
+    let mut ports: Vec<PathBuf> = ptys.iter().map(|(path, _)| path.clone()).collect();
+    ports.push("/dev/does-not-exist".into());
+
+    let started = Instant::now();
+    let found = discover(&ports, PROBE);
+
+    let id = String::from("ESP8266_WEMOS_D1MINI");
+    assert_eq!(found, [Found { path: ports[1].clone(), id: id.clone() }, Found { path: ports[3].clone(), id }]);
+    // Ports are probed at once
+    assert!(started.elapsed() < Duration::from_secs(2), "took {:?}", started.elapsed());
+}
+
+#[test]
+fn connections_wait_for_the_board_to_boot() {
+    let (board, _board) = pty(Peer::Board);
+    let mut connection = Connection::open(&Target::Serial(board), Duration::from_millis(500)).unwrap();
+    assert_eq!(connection.request("ID").unwrap(), b"OK ESP8266_WEMOS_D1MINI\r\n");
+}
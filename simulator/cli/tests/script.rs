@@ -0,0 +1,80 @@
+//! The `testbox` command, its output and exit codes.
+
+use std::process::{Command, Output};
+
+use simulator::parser::ParseMode;
+
+mod common;
+
+fn testbox(addr: &str, args: &[&str]) -> Output {
+    let (host, port) = addr.split_once(':').unwrap();
+    Command::new(env!("CARGO_BIN_EXE_testbox"))
+        .args(["--host", host, "--port", port, "--timeout", "0.5"])
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+fn stdout(output: &Output) -> &str {
+    std::str::from_utf8(&output.stdout).unwrap()
+}
+
+#[test]
+fn values_print_bare_or_as_json() {
+    let output = testbox(&common::board(), &["set", "red-led", "512"]);
+    assert_eq!((output.status.code(), stdout(&output)), (Some(0), "512\n"));
+
+    let output = testbox(&common::board(), &["get", "servo", "self-test"]);
+    assert_eq!((output.status.code(), stdout(&output)), (Some(0), "90\nINACTIVE 0\n"));
+
+    let output = testbox(&common::board(), &["--json", "get", "SERVO", "temp_and_hum"]);
+    let value: serde_json::Value = serde_json::from_str(stdout(&output)).unwrap();
+    assert_eq!(value["servo"], 90);
+    assert_eq!(value["temp_and_hum"]["status"], "OK");
+
+    let output = testbox(&common::board(), &["dump", "--json"]);
+    let value: serde_json::Value = serde_json::from_str(stdout(&output)).unwrap();
+    assert_eq!(value.as_object().unwrap().len(), 7);
+    assert_eq!(value["self_test"]["active"], false);
+}
+
+#[test]
+fn failures_map_to_exit_codes() {
+    let output = testbox(&common::board(), &["set", "servo", "181"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "testbox: SERVO takes values from 0 to 180, not 181\n");
+
+    let output = testbox(&common::answering("ERR BAD_NOUN\r\n"), &["--json", "get", "servo"]);
+    assert_eq!(output.status.code(), Some(12));
+    assert_eq!(stdout(&output), "{\"error\":\"BAD_NOUN\",\"message\":\"the board answered ERR BAD_NOUN\"}\n");
+
+    let output = testbox(&common::answering("ERR BAD_VALUE\r\n"), &["self-test"]);
+    assert_eq!(output.status.code(), Some(13));
+
+    let output = testbox(&common::answering("OK ACTIVE 10\r\n"), &["get", "servo"]);
+    assert_eq!(output.status.code(), Some(1));
+
+    let output = testbox(&common::answering(""), &["id"]);
+    assert_eq!(output.status.code(), Some(3));
+
+    let output = testbox(&common::board(), &["discover", "--settle", "0", "/dev/does-not-exist"]);
+    assert_eq!(output.status.code(), Some(4));
+}
+
+#[test]
+fn leases_are_joined_with_their_token() {
+    let board = common::board();
+    let (host, port) = board.split_once(':').unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_testbox"))
+        .args(["--host", host, "--port", port, "--timeout", "0.5", "--json", "lease", "exclusive", "60"])
+        .env("TESTBOX_HOLDER", "ci-1")
+        .output()
+        .unwrap();
+    let value: serde_json::Value = serde_json::from_str(stdout(&output)).unwrap();
+    assert_eq!(value["lease"]["holders"]["ci-1"], 60);
+    assert_eq!(value["lease"]["token"].as_str().unwrap().len(), 16);
+
+    // Firmware doesn't know leases, the requests go through without them
+    let output = testbox(&common::board_parsing(ParseMode::Firmware), &["--holder", "ci-1", "--token", "C0FFEE", "get", "servo"]);
+    assert_eq!((output.status.code(), stdout(&output)), (Some(0), "90\n"));
+}
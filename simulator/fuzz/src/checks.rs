@@ -0,0 +1,105 @@
+//! Properties checked by the fuzz targets. Also used by
+//! `tests/fuzz_regressions.rs` to replay the inputs that once broke them.
+
+use std::convert::TryFrom;
+
+use simulator::{
+    binary::{Decode, Encode, FrameDecoder},
+    parser::{Framing, ParseMode, Request, Response, Session, Tagged},
+};
+
+use crate::common::reference;
+
+const LEN: usize = 256;
+
+/// `Request::try_from` never panics and agrees with the regex based parser
+pub fn request(data: &[u8]) {
+    assert_eq!(Request::try_from(data), reference::parse(data));
+
+    let _ = Tagged::<Request>::try_from(data);
+}
+
+/// Feeds bytes to a session and answers every request right away, like the
+/// test box would. Keeps track of the framing the client has to use.
+fn feed(session: &mut Session<LEN>, framing: &mut Framing, bytes: &[u8]) -> Vec<Tagged<Request>> {
+    let mut requests = Vec::new();
+
+    for &c in bytes {
+        let Some(request) = session.push(c) else { continue };
+
+        let response = match &request.inner {
+            Ok(Request::Framing(f)) => {
+                *framing = *f;
+                Response::Framing(*f)
+            }
+            Ok(_) => Response::Value(0),
+            Err(e) => Response::Error(*e),
+        };
+        session.encode(Tagged { tag: request.tag, inner: response });
+
+        if let Ok(r) = request.inner {
+            requests.push(Tagged { tag: request.tag, inner: r });
+        }
+    }
+
+    requests
+}
+
+/// Whatever a client sends, the session never panics and the next well-formed
+/// request after a line or frame boundary is decoded as such.
+///
+/// The first byte picks the parse mode, the rest is the byte stream.
+pub fn session(data: &[u8]) {
+    let (mode, stream) = match data.split_first() {
+        Some((&m, stream)) if m & 1 == 0 => (ParseMode::Strict, stream),
+        Some((_, stream)) => (ParseMode::Firmware, stream),
+        None => return,
+    };
+
+    let mut session = Session::<LEN>::new(mode);
+    let mut framing = Framing::Plain;
+
+    feed(&mut session, &mut framing, stream);
+
+    // Close whatever line or frame is pending, then send a request
+    let (sync, expected) = match mode {
+        // A binary session is picked by a leading zero byte
+        ParseMode::Strict if stream.first() == Some(&0) => {
+            let id = Tagged { tag: Some(7), inner: Request::Id };
+            ([vec![0], id.to_frame()].concat(), Some(7))
+        }
+        ParseMode::Strict => ([b"\n".to_vec(), framing.encode(b"#7 ID\r\n".to_vec())].concat(), Some(7)),
+        // The firmware knows nothing about tags
+        ParseMode::Firmware => (b"\nID\n".to_vec(), None),
+    };
+
+    let requests = feed(&mut session, &mut framing, &sync);
+    assert_eq!(
+        requests.last().map(|r| (r.tag, r.inner)), Some((expected, Request::Id)),
+        "session out of sync after {:?}", String::from_utf8_lossy(stream)
+    );
+}
+
+/// Any response decoded from a binary payload encodes back to a payload
+/// decoding to the same response, and survives framing.
+pub fn response(data: &[u8]) {
+    let Ok(response) = Tagged::<Response>::decode(data) else { return };
+
+    let mut payload = Vec::new();
+    response.encode(&mut payload);
+
+    let decoded = Tagged::<Response>::decode(&payload).expect("encoded response must decode");
+    let mut again = Vec::new();
+    decoded.encode(&mut again);
+    assert_eq!(payload, again);
+
+    let mut frames = FrameDecoder::<LEN>::new();
+    let frame = response.to_frame();
+    let received: Vec<_> = frame.iter().filter_map(|&c| frames.push(c)).collect();
+    if frame.len() <= LEN + 1 {
+        assert_eq!(received, [Ok(payload)]);
+    }
+
+    // Encoding as text never panics either
+    let _: Vec<u8> = response.into();
+}
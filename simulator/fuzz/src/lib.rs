@@ -0,0 +1,4 @@
+#[path = "../../tests/common/mod.rs"]
+mod common;
+
+pub mod checks;
@@ -0,0 +1,372 @@
+//! Python bindings, to embed the simulator in test suites.
+//!
+//! * `Simulator` runs the TCP simulator in the background of the current
+//!   process, on real time.
+//! * `Device` is a direct handle on a simulated board, without sockets and
+//!   on a virtual clock that only moves when told to.
+//!
+//! Both give access to the test box state and to admin controls, such as
+//! fixed sensor values, sensor faults and scenarios.
+
+use std::{collections::VecDeque, net::SocketAddr, sync::mpsc as std_mpsc, time::Duration};
+
+use pyo3::{exceptions::{PyRuntimeError, PyValueError}, prelude::*};
+use tokio::{net::TcpListener, runtime::Runtime, sync::{mpsc, watch}, task::JoinHandle, time};
+
+use simulator::{
+    device::{self, Control},
+    listener::Listener,
+    parser::ParseMode,
+    scenario::Scenario,
+    server,
+    testbox::{SensorFault, TestBox, TestBoxState},
+    uart::{self, Uart},
+};
+
+/// Size of the request line buffer, as on the board
+const LEN: usize = 256;
+
+/// How long `Device.request` waits for an answer, in virtual time
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long `Simulator.stop` waits for the last responses to go out
+const STOP_TIMEOUT: Duration = Duration::from_secs(1);
+
+fn parse_mode(mode: &str) -> PyResult<ParseMode> {
+    mode.parse().map_err(PyValueError::new_err)
+}
+
+fn sensor_reading(temperature: Option<f64>, humidity: Option<f64>) -> PyResult<Option<(f64, f64)>> {
+    match (temperature, humidity) {
+        (Some(t), Some(h)) => Ok(Some((t, h))),
+        (None, None) => Ok(None),
+        _ => Err(PyValueError::new_err("temperature and humidity go together")),
+    }
+}
+
+fn sensor_fault(fault: Option<&str>) -> PyResult<Option<SensorFault>> {
+    fault.map(str::parse).transpose().map_err(PyValueError::new_err)
+}
+
+fn scenario(text: &str) -> PyResult<Scenario> {
+    text.parse().map_err(PyValueError::new_err)
+}
+
+fn seconds(s: f64) -> PyResult<Duration> {
+    Duration::try_from_secs_f64(s).map_err(|e| PyValueError::new_err(e.to_string()))
+}
+
+/// Snapshot of the test box
+#[pyclass(name = "TestBoxState", frozen, get_all)]
+struct PyTestBoxState {
+    red_led: i64,
+    yellow_led: i64,
+    green_led: i64,
+    servo: i64,
+    sensor_status: String,
+    temperature: f64,
+    humidity: f64,
+    self_test_active: bool,
+    self_test_progress: i64,
+}
+
+#[pymethods]
+impl PyTestBoxState {
+    fn __repr__(&self) -> String {
+        format!(
+            "TestBoxState(red_led={}, yellow_led={}, green_led={}, servo={}, sensor_status={:?}, \
+             temperature={:.2}, humidity={:.2}, self_test_active={}, self_test_progress={})",
+            self.red_led, self.yellow_led, self.green_led, self.servo, self.sensor_status,
+            self.temperature, self.humidity, if self.self_test_active { "True" } else { "False" },
+            self.self_test_progress
+        )
+    }
+}
+
+impl From<TestBoxState> for PyTestBoxState {
+    fn from(s: TestBoxState) -> Self {
+        Self {
+            red_led: s.red_led.value,
+            yellow_led: s.yellow_led.value,
+            green_led: s.green_led.value,
+            servo: s.servo.value,
+            sensor_status: s.sensor.status,
+            temperature: s.sensor.temperature,
+            humidity: s.sensor.humidity,
+            self_test_active: s.self_test.active,
+            self_test_progress: s.self_test.progress,
+        }
+    }
+}
+
+/// A simulated board driven directly, on a virtual clock starting at 0
+#[pyclass(name = "Device")]
+struct PyDevice {
+    device: device::Device<LEN>,
+    now: Duration,
+    /// Received bytes that don't make a complete line yet
+    partial: Vec<u8>,
+    lines: VecDeque<String>,
+}
+
+impl PyDevice {
+    fn flush(&mut self) {
+        while let Some(bytes) = self.device.transmit() {
+            self.partial.extend(bytes);
+
+            while let Some(end) = self.partial.iter().position(|&c| c == b'\n') {
+                let line: Vec<u8> = self.partial.drain(..=end).collect();
+                let line = line.strip_suffix(b"\r\n").or_else(|| line.strip_suffix(b"\n")).unwrap_or(&line);
+                self.lines.push_back(String::from_utf8_lossy(line).into_owned());
+            }
+        }
+    }
+}
+
+#[pymethods]
+impl PyDevice {
+    #[new]
+    #[pyo3(signature = (seed=0, parse_mode="strict", rx_fifo=None, loop_cadence=false))]
+    fn new(seed: u64, parse_mode: &str, rx_fifo: Option<usize>, loop_cadence: bool) -> PyResult<Self> {
+        let uart = rx_fifo.map(|size| Uart::new(size, uart::BAUD_RATE));
+        let mut device = device::Device::new(TestBox::new(seed), self::parse_mode(parse_mode)?, uart, loop_cadence);
+        device.advance(Duration::ZERO);
+
+        Ok(Self { device, now: Duration::ZERO, partial: Vec::new(), lines: VecDeque::new() })
+    }
+
+    /// Sends raw bytes to the board
+    fn write(&mut self, data: &[u8]) {
+        self.device.receive(data, self.now);
+        self.flush();
+    }
+
+    /// Sends a request line, without its line ending
+    fn send(&mut self, line: &str) {
+        self.write(format!("{}\n", line).as_bytes());
+    }
+
+    /// Next line sent by the board, without its line ending
+    fn receive(&mut self) -> Option<String> {
+        self.lines.pop_front()
+    }
+
+    /// Sends a request line and returns the first line received after it,
+    /// letting virtual time pass if needed
+    fn request(&mut self, line: &str) -> PyResult<String> {
+        self.send(line);
+
+        let deadline = self.now + REQUEST_TIMEOUT;
+        while self.lines.is_empty() && self.device.next_deadline() <= deadline {
+            self.now = self.now.max(self.device.next_deadline());
+            self.device.advance(self.now);
+            self.flush();
+        }
+
+        self.receive().ok_or_else(|| PyRuntimeError::new_err(format!("No answer to {:?}", line)))
+    }
+
+    /// Lets `seconds` of virtual time pass
+    fn advance(&mut self, seconds: f64) -> PyResult<()> {
+        self.now += self::seconds(seconds)?;
+        self.device.advance(self.now);
+        self.flush();
+        Ok(())
+    }
+
+    /// Virtual time since boot, in seconds
+    #[getter]
+    fn now(&self) -> f64 {
+        self.now.as_secs_f64()
+    }
+
+    #[getter]
+    fn state(&self) -> PyTestBoxState {
+        self.device.testbox().get().into()
+    }
+
+    /// Makes the sensor read fixed values, or random ones again without arguments.
+    /// Takes effect at the next tick.
+    #[pyo3(signature = (temperature=None, humidity=None))]
+    fn set_sensor(&mut self, temperature: Option<f64>, humidity: Option<f64>) -> PyResult<()> {
+        self.device.testbox_mut().set_sensor(sensor_reading(temperature, humidity)?);
+        Ok(())
+    }
+
+    /// Makes the sensor reads fail with `TIMEOUT` or `CHECKSUM`, or succeed
+    /// again with `None`. Takes effect at the next tick.
+    #[pyo3(signature = (fault=None))]
+    fn set_sensor_fault(&mut self, fault: Option<&str>) -> PyResult<()> {
+        self.device.testbox_mut().set_sensor_fault(sensor_fault(fault)?);
+        Ok(())
+    }
+
+    /// Plays a scenario, given as the text of a scenario file. Its times
+    /// count from now.
+    fn play(&mut self, scenario: &str) -> PyResult<()> {
+        self.device.play(self::scenario(scenario)?, self.now);
+        Ok(())
+    }
+}
+
+/// The TCP simulator, running in the background until stopped
+#[pyclass(name = "Simulator")]
+struct PySimulator {
+    runtime: Option<Runtime>,
+    address: SocketAddr,
+    control: mpsc::Sender<Control<LEN>>,
+    shutdown: watch::Sender<bool>,
+    tasks: Vec<JoinHandle<Result<(), String>>>,
+}
+
+impl PySimulator {
+    fn control(&self, py: Python<'_>, c: Control<LEN>) -> PyResult<()> {
+        if self.runtime.is_none() {
+            return Err(PyRuntimeError::new_err("Simulator is stopped"));
+        }
+        py.allow_threads(|| self.control.blocking_send(c))
+            .map_err(|_| PyRuntimeError::new_err("Simulator is not running"))
+    }
+
+    fn apply(&self, py: Python<'_>, f: impl FnOnce(&mut device::Device<LEN>) + Send + 'static) -> PyResult<()> {
+        self.control(py, Control::Apply(Box::new(f)))
+    }
+}
+
+#[pymethods]
+impl PySimulator {
+    /// Starts listening right away. With `port=0`, a free port is picked, see `address`.
+    #[new]
+    #[pyo3(signature = (
+        host="127.0.0.1", port=0, seed=None, parse_mode="strict", rx_fifo=None, loop_cadence=false,
+        idle_timeout=None, half_open_timeout=5.0, rfc2217=false
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        host: &str, port: u16, seed: Option<u64>, parse_mode: &str, rx_fifo: Option<usize>, loop_cadence: bool,
+        idle_timeout: Option<f64>, half_open_timeout: f64, rfc2217: bool
+    ) -> PyResult<Self> {
+        let mode = self::parse_mode(parse_mode)?;
+        let protocol = if rfc2217 { server::Protocol::Rfc2217 } else { server::Protocol::Raw };
+        let timeouts = server::Timeouts {
+            idle: idle_timeout.map(self::seconds).transpose()?,
+            half_open: self::seconds(half_open_timeout)?,
+        };
+        let runtime = tokio::runtime::Builder::new_multi_thread().enable_all().build()?;
+        let listener = runtime.block_on(TcpListener::bind((host, port)))?;
+        let address = listener.local_addr()?;
+        let listener = Listener::tcp(listener)?;
+
+        let (incoming_tx, incoming_rx) = mpsc::channel(10);
+        let (outgoing_tx, outgoing_rx) = mpsc::channel(10);
+        let (state_tx, mut state_rx) = mpsc::channel(10);
+        let (control_tx, control_rx) = mpsc::channel(10);
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+
+        let tbox = TestBox::new(seed.unwrap_or_else(rand::random));
+        let uart = rx_fifo.map(|size| Uart::new(size, uart::BAUD_RATE));
+        let device = device::Device::new(tbox, mode, uart, loop_cadence);
+
+        // Errors only end the task, the controls then report the simulator is not running
+        let shutdown = shutdown_rx.clone();
+        let server = runtime.spawn(async move {
+            server::server::<LEN>(listener, protocol, timeouts, incoming_tx, outgoing_rx, shutdown).await.map_err(|e| e.to_string())
+        });
+        let device = runtime.spawn(async move {
+            device::device(device, incoming_rx, outgoing_tx, state_tx, Some(control_rx), shutdown_rx).await
+                .map_err(|e| e.to_string())
+        });
+        // Nobody watches the status line, `state` asks the device directly
+        runtime.spawn(async move { while state_rx.recv().await.is_some() {} });
+
+        Ok(Self {
+            runtime: Some(runtime),
+            address,
+            control: control_tx,
+            shutdown: shutdown_tx,
+            tasks: vec![server, device],
+        })
+    }
+
+    /// `(host, port)` the simulator listens on
+    #[getter]
+    fn address(&self) -> (String, u16) {
+        (self.address.ip().to_string(), self.address.port())
+    }
+
+    #[getter]
+    fn port(&self) -> u16 {
+        self.address.port()
+    }
+
+    #[getter]
+    fn state(&self, py: Python<'_>) -> PyResult<PyTestBoxState> {
+        let (tx, rx) = std_mpsc::channel();
+        self.apply(py, move |d| {
+            let _ = tx.send(d.testbox().get());
+        })?;
+
+        py.allow_threads(move || rx.recv())
+            .map(PyTestBoxState::from)
+            .map_err(|_| PyRuntimeError::new_err("Simulator is not running"))
+    }
+
+    /// Makes the sensor read fixed values, or random ones again without arguments
+    #[pyo3(signature = (temperature=None, humidity=None))]
+    fn set_sensor(&self, py: Python<'_>, temperature: Option<f64>, humidity: Option<f64>) -> PyResult<()> {
+        let reading = sensor_reading(temperature, humidity)?;
+        self.apply(py, move |d| d.testbox_mut().set_sensor(reading))
+    }
+
+    /// Makes the sensor reads fail with `TIMEOUT` or `CHECKSUM`, or succeed again with `None`
+    #[pyo3(signature = (fault=None))]
+    fn set_sensor_fault(&self, py: Python<'_>, fault: Option<&str>) -> PyResult<()> {
+        let fault = sensor_fault(fault)?;
+        self.apply(py, move |d| d.testbox_mut().set_sensor_fault(fault))
+    }
+
+    /// Plays a scenario, given as the text of a scenario file. Its times
+    /// count from now.
+    fn play(&self, py: Python<'_>, scenario: &str) -> PyResult<()> {
+        self.control(py, Control::Play(self::scenario(scenario)?))
+    }
+
+    /// Moves the simulator clock `seconds` forward, e.g. to get through a self test
+    fn skip(&self, py: Python<'_>, seconds: f64) -> PyResult<()> {
+        self.control(py, Control::Skip(self::seconds(seconds)?))
+    }
+
+    /// Stops the simulator once the requests it received are answered, closing its socket
+    fn stop(&mut self, py: Python<'_>) {
+        if let Some(runtime) = self.runtime.take() {
+            self.shutdown.send_replace(true);
+            let tasks = std::mem::take(&mut self.tasks);
+            py.allow_threads(move || {
+                // Whatever is still running after the timeout is dropped with the runtime
+                runtime.block_on(async {
+                    let _ = time::timeout(STOP_TIMEOUT, async {
+                        for task in tasks {
+                            let _ = task.await;
+                        }
+                    }).await;
+                })
+            });
+        }
+    }
+
+    fn __enter__(slf: Py<Self>) -> Py<Self> {
+        slf
+    }
+
+    fn __exit__(&mut self, py: Python<'_>, _exc_type: PyObject, _exc_value: PyObject, _traceback: PyObject) {
+        self.stop(py);
+    }
+}
+
+#[pymodule]
+fn testbox_simulator(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyTestBoxState>()?;
+    m.add_class::<PyDevice>()?;
+    m.add_class::<PySimulator>()?;
+    Ok(())
+}
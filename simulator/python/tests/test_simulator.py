@@ -0,0 +1,106 @@
+import socket
+
+import pytest
+
+from testbox_simulator import Device, Simulator
+
+
+def talk(address, *lines):
+    with socket.create_connection(address, timeout=2) as s:
+        reader = s.makefile("rb")
+        answers = []
+        for line in lines:
+            s.sendall(line.encode() + b"\n")
+            answers.append(reader.readline().decode().rstrip("\r\n"))
+        return answers
+
+
+def test_device_answers_requests():
+    device = Device(seed=1)
+    assert device.request("ID") == "OK ESP8266_WEMOS_D1MINI"
+    assert device.request("SET RED_LED 500") == "OK 500"
+    assert device.request("FOO") == "ERR BAD_VERB"
+    assert device.state.red_led == 500
+
+
+def test_device_virtual_clock():
+    device = Device(seed=1)
+    device.request("SET SELF_TEST 1")
+    assert device.state.self_test_active
+
+    device.advance(3)
+    assert device.now == pytest.approx(3)
+    assert not device.state.self_test_active
+
+
+def test_device_sensor_controls():
+    device = Device()
+    device.set_sensor(21.5, 40.25)
+    device.advance(0.1)
+    assert device.request("GET TEMP_AND_HUM") == "OK OK 21.50 40.25"
+
+    device.set_sensor_fault("TIMEOUT")
+    device.advance(0.1)
+    assert device.request("GET TEMP_AND_HUM") == "OK TIMEOUT 0.00 0.00"
+    assert device.state.sensor_status == "TIMEOUT"
+
+    with pytest.raises(ValueError):
+        device.set_sensor_fault("BROKEN")
+
+
+def test_device_firmware_mode():
+    device = Device(parse_mode="firmware", loop_cadence=True)
+    assert device.request("GET FOO") == "Failed to find noun [FOO]"
+    assert device.receive() == "ERR BAD_NOUN"
+
+
+def test_simulator_over_tcp():
+    with Simulator(seed=1) as sim:
+        host, port = sim.address
+        assert port == sim.port != 0
+
+        assert talk(sim.address, "SET SERVO 10", "GET SERVO") == ["OK 10", "OK 10"]
+        assert sim.state.servo == 10
+
+        sim.set_sensor(25.0, 60.0)
+        sim.skip(0.2)
+        assert talk(sim.address, "GET TEMP_AND_HUM") == ["OK OK 25.00 60.00"]
+
+        talk(sim.address, "SET SELF_TEST 1")
+        sim.skip(3)
+        assert not sim.state.self_test_active
+
+
+def test_simulator_drops_clients_that_are_gone():
+    with Simulator(seed=1, loop_cadence=True, idle_timeout=0.5) as sim:
+        # A client that is done sending still gets every response
+        with socket.create_connection(sim.address, timeout=2) as s:
+            s.sendall(b"GET SERVO\n" * 5)
+            s.shutdown(socket.SHUT_WR)
+            assert s.makefile("rb").read() == b"OK 90\r\n" * 5
+
+        # Responses to a client that left never reach the next one
+        with socket.create_connection(sim.address, timeout=2) as s:
+            s.sendall(b"SET SERVO 10\n" * 5)
+        assert talk(sim.address, "ID") == ["OK ESP8266_WEMOS_D1MINI"]
+
+        # Silent clients are disconnected
+        with socket.create_connection(sim.address, timeout=2) as s:
+            assert s.recv(1) == b""
+
+
+def test_device_plays_scenarios():
+    device = Device(seed=1)
+    device.play("""
+        # Heat wave
+        0s   temperature 22
+        0s   temperature 35 over 60s
+        30s  fault TIMEOUT for 1 read
+    """)
+    device.advance(30)
+    assert device.request("GET TEMP_AND_HUM") == "OK TIMEOUT 0.00 0.00"
+    device.advance(2)
+    assert device.request("GET TEMP_AND_HUM") == "OK OK 28.93 50.00"
+
+    with pytest.raises(ValueError, match="line 1"):
+        device.play("10s rain")
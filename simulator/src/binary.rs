@@ -0,0 +1,717 @@
+//! Compact binary encoding of the protocol
+//!
+//! Every message is a payload followed by its CRC-16/CCITT-FALSE (big endian),
+//! COBS encoded and terminated by a `0x00` delimiter.
+//!
+//! Request payload: `header [tag] [noun] [value]`
+//!
+//! * `header`: verb ID in the low 7 bits, bit 7 set if a tag follows
+//! * `tag`: LEB128 varint
+//! * `noun`: noun ID, for verbs that take one
+//! * `value`: zigzag LEB128 varint, for verbs that take one
+//!
+//! Response payload: `header [tag] body`, with the response kind in the low 7
+//! bits of `header`. Sensor readings are sent as hundredths, matching the two
+//! decimals of the text protocol.
+
+use std::convert::{TryFrom, TryInto};
+
+use crate::lease::{Holder, LeaseMode, LeaseRequest, LeaseState};
+use crate::parser::{
+    Batch, Description, Framing, Request, RequestNoun, Response, ResponseError, Tag, Tagged, ValueKind,
+    GETTABLE, NOUN_COUNT, SETTABLE
+};
+
+const TAGGED: u8 = 0x80;
+
+const VERB_ID: u8 = 0x01;
+const VERB_GET: u8 = 0x02;
+const VERB_SET: u8 = 0x03;
+const VERB_FRAMING: u8 = 0x04;
+const VERB_GET_MANY: u8 = 0x05;
+const VERB_SET_MANY: u8 = 0x06;
+const VERB_HELP: u8 = 0x07;
+const VERB_LIST: u8 = 0x08;
+const VERB_DESCRIBE: u8 = 0x09;
+const VERB_VERSION: u8 = 0x0A;
+const VERB_LEASE: u8 = 0x0B;
+
+const KIND_ERROR: u8 = 0x00;
+const KIND_ID: u8 = 0x01;
+const KIND_VALUE: u8 = 0x02;
+const KIND_TEMP_AND_HUM: u8 = 0x03;
+const KIND_SELF_TEST: u8 = 0x04;
+const KIND_FRAMING: u8 = 0x05;
+const KIND_MANY: u8 = 0x06;
+const KIND_NAMES: u8 = 0x07;
+const KIND_DESCRIPTION: u8 = 0x08;
+const KIND_VERSION: u8 = 0x09;
+const KIND_LEASE: u8 = 0x0A;
+
+/// What a `LEASE` request does, after its header
+const LEASE_STATUS: u8 = 0x00;
+const LEASE_AS: u8 = 0x03;
+const LEASE_RELEASE: u8 = 0x04;
+
+impl From<RequestNoun> for u8 {
+    fn from(n: RequestNoun) -> Self {
+        match n {
+            RequestNoun::RedLed => 0x01,
+            RequestNoun::YellowLed => 0x02,
+            RequestNoun::GreenLed => 0x03,
+            RequestNoun::Servo => 0x04,
+            RequestNoun::TempAndHum => 0x05,
+            RequestNoun::SelfTest => 0x06,
+        }
+    }
+}
+
+impl TryFrom<u8> for RequestNoun {
+    type Error = ResponseError;
+
+    fn try_from(id: u8) -> Result<Self, Self::Error> {
+        match id {
+            0x01 => Ok(Self::RedLed),
+            0x02 => Ok(Self::YellowLed),
+            0x03 => Ok(Self::GreenLed),
+            0x04 => Ok(Self::Servo),
+            0x05 => Ok(Self::TempAndHum),
+            0x06 => Ok(Self::SelfTest),
+            _ => Err(ResponseError::BadNoun)
+        }
+    }
+}
+
+impl From<Framing> for u8 {
+    fn from(f: Framing) -> Self {
+        match f {
+            Framing::Plain => 0x00,
+            Framing::Checksum => 0x01,
+        }
+    }
+}
+
+impl TryFrom<u8> for Framing {
+    type Error = ResponseError;
+
+    fn try_from(id: u8) -> Result<Self, Self::Error> {
+        match id {
+            0x00 => Ok(Self::Plain),
+            0x01 => Ok(Self::Checksum),
+            _ => Err(ResponseError::BadNoun)
+        }
+    }
+}
+
+impl From<ValueKind> for u8 {
+    fn from(k: ValueKind) -> Self {
+        match k {
+            ValueKind::Int => 0x00,
+            ValueKind::Bool => 0x01,
+            ValueKind::Sensor => 0x02,
+        }
+    }
+}
+
+impl TryFrom<u8> for ValueKind {
+    type Error = ResponseError;
+
+    fn try_from(id: u8) -> Result<Self, Self::Error> {
+        match id {
+            0x00 => Ok(Self::Int),
+            0x01 => Ok(Self::Bool),
+            0x02 => Ok(Self::Sensor),
+            _ => Err(ResponseError::BadSyntax)
+        }
+    }
+}
+
+/// Also the action of a `LEASE` request taking a lease, and the mode of a
+/// lease response, where `0x00` means nobody holds a lease
+impl From<LeaseMode> for u8 {
+    fn from(m: LeaseMode) -> Self {
+        match m {
+            LeaseMode::Exclusive => 0x01,
+            LeaseMode::Shared => 0x02,
+        }
+    }
+}
+
+impl TryFrom<u8> for LeaseMode {
+    type Error = ResponseError;
+
+    fn try_from(id: u8) -> Result<Self, Self::Error> {
+        match id {
+            0x01 => Ok(Self::Exclusive),
+            0x02 => Ok(Self::Shared),
+            _ => Err(ResponseError::BadNoun)
+        }
+    }
+}
+
+impl From<&ResponseError> for u8 {
+    fn from(e: &ResponseError) -> Self {
+        match e {
+            ResponseError::BadSyntax => 0x01,
+            ResponseError::BadVerb => 0x02,
+            ResponseError::BadNoun => 0x03,
+            ResponseError::BadValue => 0x04,
+            ResponseError::BadChecksum => 0x05,
+            ResponseError::Leased => 0x06,
+            ResponseError::NoResponse => 0x07,
+        }
+    }
+}
+
+impl TryFrom<u8> for ResponseError {
+    type Error = ResponseError;
+
+    fn try_from(id: u8) -> Result<Self, Self::Error> {
+        match id {
+            0x01 => Ok(Self::BadSyntax),
+            0x02 => Ok(Self::BadVerb),
+            0x03 => Ok(Self::BadNoun),
+            0x04 => Ok(Self::BadValue),
+            0x05 => Ok(Self::BadChecksum),
+            0x06 => Ok(Self::Leased),
+            0x07 => Ok(Self::NoResponse),
+            _ => Err(ResponseError::BadSyntax)
+        }
+    }
+}
+
+/// CRC-16/CCITT-FALSE
+pub fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0xFFFFu16, |crc, &b| {
+        (0..8).fold(crc ^ ((b as u16) << 8), |crc, _| {
+            if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 }
+        })
+    })
+}
+
+fn cobs_encode(data: &[u8], out: &mut Vec<u8>) {
+    let mut code_index = out.len();
+    out.push(0);
+    let mut code = 1u8;
+
+    for &b in data {
+        if b == 0 {
+            out[code_index] = code;
+            code_index = out.len();
+            out.push(0);
+            code = 1;
+        } else {
+            out.push(b);
+            code += 1;
+            if code == 0xFF {
+                out[code_index] = code;
+                code_index = out.len();
+                out.push(0);
+                code = 1;
+            }
+        }
+    }
+
+    out[code_index] = code;
+}
+
+fn cobs_decode(data: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut i = 0;
+
+    while i < data.len() {
+        let code = data[i] as usize;
+        if code == 0 || i + code > data.len() {
+            return None;
+        }
+
+        out.extend_from_slice(&data[i + 1..i + code]);
+        i += code;
+
+        if code < 0xFF && i < data.len() {
+            out.push(0);
+        }
+    }
+
+    Some(out)
+}
+
+/// Wraps a payload into a complete frame, delimiter included
+pub fn encode_frame(payload: &[u8]) -> Vec<u8> {
+    let crc = crc16(payload);
+    let mut out = Vec::with_capacity(payload.len() + payload.len() / 254 + 5);
+    cobs_encode(&[payload, &crc.to_be_bytes()].concat(), &mut out);
+    out.push(0);
+    out
+}
+
+/// Extracts frame payloads from a byte stream
+pub struct FrameDecoder<const LEN: usize> {
+    buffer: [u8; LEN],
+    buffer_len: usize,
+    overflow: bool,
+}
+
+impl<const LEN: usize> Default for FrameDecoder<LEN> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const LEN: usize> FrameDecoder<LEN> {
+    pub fn new() -> Self {
+        Self { buffer: [0u8; LEN], buffer_len: 0, overflow: false }
+    }
+
+    /// Feeds one byte. Returns the payload of a frame once its delimiter is
+    /// received, or an error if the frame is too long or corrupted. Empty
+    /// frames are skipped.
+    pub fn push(&mut self, c: u8) -> Option<Result<Vec<u8>, ResponseError>> {
+        if c != 0 {
+            if self.buffer_len < LEN {
+                self.buffer[self.buffer_len] = c;
+                self.buffer_len += 1;
+            } else {
+                self.overflow = true;
+            }
+            return None;
+        }
+
+        let frame = &self.buffer[..self.buffer_len];
+        let overflow = self.overflow;
+        self.buffer_len = 0;
+        self.overflow = false;
+
+        if overflow {
+            return Some(Err(ResponseError::BadSyntax));
+        }
+
+        if frame.is_empty() {
+            return None;
+        }
+
+        let decoded = cobs_decode(frame)
+            .filter(|d| d.len() >= 2)
+            .and_then(|d| {
+                let (payload, crc) = d.split_at(d.len() - 2);
+                (crc16(payload).to_be_bytes() == crc).then(|| payload.to_vec())
+            });
+
+        Some(decoded.ok_or(ResponseError::BadChecksum))
+    }
+
+    pub fn clear(&mut self) {
+        self.buffer_len = 0;
+        self.overflow = false;
+    }
+}
+
+fn put_varint(mut v: u64, out: &mut Vec<u8>) {
+    while v >= 0x80 {
+        out.push((v as u8) | 0x80);
+        v >>= 7;
+    }
+    out.push(v as u8);
+}
+
+fn put_signed(v: i64, out: &mut Vec<u8>) {
+    put_varint(((v << 1) ^ (v >> 63)) as u64, out)
+}
+
+fn put_str(s: &str, out: &mut Vec<u8>) {
+    put_varint(s.len() as u64, out);
+    out.extend_from_slice(s.as_bytes());
+}
+
+/// Cursor over a payload being decoded
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn byte(&mut self) -> Result<u8, ResponseError> {
+        let (&b, rest) = self.0.split_first().ok_or(ResponseError::BadSyntax)?;
+        self.0 = rest;
+        Ok(b)
+    }
+
+    fn varint(&mut self) -> Result<u64, ResponseError> {
+        let mut v = 0u64;
+        for shift in (0..64).step_by(7) {
+            let b = self.byte()?;
+            v |= ((b & 0x7F) as u64) << shift;
+            if b & 0x80 == 0 {
+                return Ok(v);
+            }
+        }
+        Err(ResponseError::BadSyntax)
+    }
+
+    fn signed(&mut self) -> Result<i64, ResponseError> {
+        let v = self.varint()?;
+        Ok(((v >> 1) as i64) ^ -((v & 1) as i64))
+    }
+
+    fn str(&mut self) -> Result<String, ResponseError> {
+        let len = self.varint()? as usize;
+        if len > self.0.len() {
+            return Err(ResponseError::BadSyntax);
+        }
+        let (s, rest) = self.0.split_at(len);
+        self.0 = rest;
+        String::from_utf8(s.to_vec()).map_err(|_| ResponseError::BadSyntax)
+    }
+
+    fn holder(&mut self) -> Result<Holder, ResponseError> {
+        self.str().map_err(|_| ResponseError::BadValue)?.as_bytes().try_into()
+    }
+
+    fn header(&mut self) -> Result<(u8, Option<Tag>), ResponseError> {
+        let header = self.byte()?;
+        let tag = if header & TAGGED != 0 {
+            Some(self.varint()?.try_into().map_err(|_| ResponseError::BadSyntax)?)
+        } else {
+            None
+        };
+        Ok((header & !TAGGED, tag))
+    }
+
+    fn end(&self) -> Result<(), ResponseError> {
+        self.0.is_empty().then_some(()).ok_or(ResponseError::BadSyntax)
+    }
+}
+
+fn put_header(id: u8, tag: Option<Tag>, out: &mut Vec<u8>) {
+    match tag {
+        Some(tag) => {
+            out.push(id | TAGGED);
+            put_varint(tag as u64, out);
+        }
+        None => out.push(id)
+    }
+}
+
+/// Binary encoding of a message payload
+pub trait Encode {
+    fn encode(&self, out: &mut Vec<u8>);
+
+    /// Encodes into a complete frame, ready to be sent
+    fn to_frame(&self) -> Vec<u8> {
+        let mut payload = Vec::new();
+        self.encode(&mut payload);
+        encode_frame(&payload)
+    }
+}
+
+/// Binary decoding of a message payload
+pub trait Decode: Sized {
+    type Error;
+
+    fn decode(payload: &[u8]) -> Result<Self, Self::Error>;
+}
+
+impl Encode for Tagged<Request> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match &self.inner {
+            Request::Id => put_header(VERB_ID, self.tag, out),
+            Request::Get(noun) => {
+                put_header(VERB_GET, self.tag, out);
+                out.push((*noun).into());
+            }
+            Request::Set(noun, value) => {
+                put_header(VERB_SET, self.tag, out);
+                out.push((*noun).into());
+                put_signed(*value, out);
+            }
+            Request::GetMany(nouns) => {
+                put_header(VERB_GET_MANY, self.tag, out);
+                out.push(nouns.len() as u8);
+                out.extend(nouns.iter().map(u8::from));
+            }
+            Request::SetMany(items) => {
+                put_header(VERB_SET_MANY, self.tag, out);
+                out.push(items.len() as u8);
+                for (noun, value) in items.iter() {
+                    out.push(noun.into());
+                    put_signed(value, out);
+                }
+            }
+            Request::Framing(f) => {
+                put_header(VERB_FRAMING, self.tag, out);
+                out.push((*f).into());
+            }
+            Request::Help => put_header(VERB_HELP, self.tag, out),
+            Request::List => put_header(VERB_LIST, self.tag, out),
+            Request::Describe(noun) => {
+                put_header(VERB_DESCRIBE, self.tag, out);
+                out.push((*noun).into());
+            }
+            Request::Version => put_header(VERB_VERSION, self.tag, out),
+            Request::Lease(lease) => {
+                put_header(VERB_LEASE, self.tag, out);
+                match lease {
+                    LeaseRequest::Status => out.push(LEASE_STATUS),
+                    LeaseRequest::Acquire(mode, holder, seconds) => {
+                        out.push((*mode).into());
+                        put_str(holder.as_str(), out);
+                        put_varint(*seconds as u64, out);
+                    }
+                    LeaseRequest::As(holder, token) => {
+                        out.push(LEASE_AS);
+                        put_str(holder.as_str(), out);
+                        put_varint(*token, out);
+                    }
+                    LeaseRequest::Release(holder) => {
+                        out.push(LEASE_RELEASE);
+                        put_str(holder.as_str(), out);
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Decode for Tagged<Request> {
+    type Error = Tagged<ResponseError>;
+
+    fn decode(payload: &[u8]) -> Result<Self, Self::Error> {
+        let mut r = Reader(payload);
+        let (verb, tag) = r.header().map_err(|inner| Tagged { tag: None, inner })?;
+
+        let request = (|| {
+            let request = match verb {
+                VERB_ID => Request::Id,
+                VERB_GET => {
+                    let noun: RequestNoun = r.byte().map_err(|_| ResponseError::BadNoun)?.try_into()?;
+                    GETTABLE.get(&noun).ok_or(ResponseError::BadNoun)?;
+                    Request::Get(noun)
+                }
+                VERB_SET => {
+                    let noun: RequestNoun = r.byte().map_err(|_| ResponseError::BadNoun)?.try_into()?;
+                    SETTABLE.get(&noun).ok_or(ResponseError::BadNoun)?;
+                    Request::Set(noun, r.signed().map_err(|_| ResponseError::BadValue)?)
+                }
+                VERB_GET_MANY => {
+                    let mut nouns = Batch::new();
+                    for _ in 0..r.byte()? {
+                        let noun: RequestNoun = r.byte().map_err(|_| ResponseError::BadNoun)?.try_into()?;
+                        GETTABLE.get(&noun).ok_or(ResponseError::BadNoun)?;
+                        if nouns.iter().any(|n| n == noun) {
+                            return Err(ResponseError::BadNoun);
+                        }
+                        nouns.push(noun)?;
+                    }
+                    Request::GetMany(nouns)
+                }
+                VERB_SET_MANY => {
+                    let mut items = Batch::new();
+                    for _ in 0..r.byte()? {
+                        let noun: RequestNoun = r.byte().map_err(|_| ResponseError::BadNoun)?.try_into()?;
+                        SETTABLE.get(&noun).ok_or(ResponseError::BadNoun)?;
+                        let value = r.signed().map_err(|_| ResponseError::BadValue)?;
+                        if items.iter().any(|(n, _)| n == noun) {
+                            return Err(ResponseError::BadNoun);
+                        }
+                        items.push((noun, value))?;
+                    }
+                    Request::SetMany(items)
+                }
+                VERB_HELP => Request::Help,
+                VERB_LIST => Request::List,
+                VERB_DESCRIBE => Request::Describe(r.byte().map_err(|_| ResponseError::BadNoun)?.try_into()?),
+                VERB_VERSION => Request::Version,
+                VERB_FRAMING => Request::Framing(r.byte().map_err(|_| ResponseError::BadNoun)?.try_into()?),
+                VERB_LEASE => Request::Lease(match r.byte().map_err(|_| ResponseError::BadNoun)? {
+                    LEASE_STATUS => LeaseRequest::Status,
+                    LEASE_AS => LeaseRequest::As(r.holder()?, r.varint().map_err(|_| ResponseError::BadValue)?),
+                    LEASE_RELEASE => LeaseRequest::Release(r.holder()?),
+                    mode => {
+                        let mode = mode.try_into()?;
+                        let holder = r.holder()?;
+                        let seconds = r.varint().ok()
+                            .and_then(|s| u32::try_from(s).ok())
+                            .filter(|&s| s > 0)
+                            .ok_or(ResponseError::BadValue)?;
+                        LeaseRequest::Acquire(mode, holder, seconds)
+                    }
+                }),
+                _ => return Err(ResponseError::BadVerb)
+            };
+            r.end()?;
+            Ok(request)
+        })();
+
+        request
+            .map(|inner| Tagged { tag, inner })
+            .map_err(|inner| Tagged { tag, inner })
+    }
+}
+
+fn put_response(response: &Response, tag: Option<Tag>, out: &mut Vec<u8>) {
+    match response {
+        Response::Id(id) => {
+            put_header(KIND_ID, tag, out);
+            put_str(id, out);
+        }
+        Response::Value(v) => {
+            put_header(KIND_VALUE, tag, out);
+            put_signed(*v, out);
+        }
+        Response::TempAndHum(s, t, h) => {
+            put_header(KIND_TEMP_AND_HUM, tag, out);
+            put_str(s, out);
+            put_signed((t * 100.0).round() as i64, out);
+            put_signed((h * 100.0).round() as i64, out);
+        }
+        Response::SelfTest(a, p) => {
+            put_header(KIND_SELF_TEST, tag, out);
+            out.push(*a as u8);
+            put_signed(*p, out);
+        }
+        Response::Framing(f) => {
+            put_header(KIND_FRAMING, tag, out);
+            out.push((*f).into());
+        }
+        Response::Many(rs) => {
+            put_header(KIND_MANY, tag, out);
+            put_varint(rs.len() as u64, out);
+            for r in rs {
+                put_response(r, None, out);
+            }
+        }
+        Response::Names(names) => {
+            put_header(KIND_NAMES, tag, out);
+            put_varint(names.len() as u64, out);
+            for name in names {
+                put_str(name, out);
+            }
+        }
+        Response::Description(d) => {
+            put_header(KIND_DESCRIPTION, tag, out);
+            out.push(d.kind.into());
+            out.push(d.gettable as u8 | (d.settable as u8) << 1);
+            match d.range {
+                Some((min, max, def)) => {
+                    out.push(1);
+                    put_signed(min, out);
+                    put_signed(max, out);
+                    put_signed(def, out);
+                }
+                None => out.push(0)
+            }
+        }
+        Response::Version(p, f) => {
+            put_header(KIND_VERSION, tag, out);
+            put_signed(*p, out);
+            put_str(f, out);
+        }
+        Response::Lease(state) => {
+            put_header(KIND_LEASE, tag, out);
+            match state {
+                Some(state) => {
+                    out.push(state.mode.into());
+                    put_varint(state.holders.len() as u64, out);
+                    for (holder, left) in &state.holders {
+                        put_str(holder.as_str(), out);
+                        put_varint(*left, out);
+                    }
+                    // Only in the answer to taking a lease
+                    if let Some(token) = state.token {
+                        put_varint(token, out);
+                    }
+                }
+                None => out.push(0x00)
+            }
+        }
+        Response::Error(e) => {
+            put_header(KIND_ERROR, tag, out);
+            out.push(e.into());
+        }
+    }
+}
+
+fn read_response(r: &mut Reader, nested: bool) -> Result<Tagged<Response>, ResponseError> {
+    let (kind, tag) = r.header()?;
+
+    let response = match kind {
+        KIND_ID => Response::Id(r.str()?),
+        KIND_VALUE => Response::Value(r.signed()?),
+        KIND_TEMP_AND_HUM => {
+            let s = r.str()?;
+            let t = r.signed()? as f64 / 100.0;
+            let h = r.signed()? as f64 / 100.0;
+            Response::TempAndHum(s, t, h)
+        }
+        KIND_SELF_TEST => {
+            let a = match r.byte()? {
+                0 => false,
+                1 => true,
+                _ => return Err(ResponseError::BadSyntax)
+            };
+            Response::SelfTest(a, r.signed()?)
+        }
+        KIND_FRAMING => Response::Framing(r.byte()?.try_into().map_err(|_| ResponseError::BadSyntax)?),
+        KIND_MANY if !nested => {
+            let count = r.varint()?;
+            if count > NOUN_COUNT as u64 {
+                return Err(ResponseError::BadSyntax);
+            }
+            let rs = (0..count)
+                .map(|_| read_response(r, true).map(|r| r.inner))
+                .collect::<Result<_, _>>()?;
+            Response::Many(rs)
+        }
+        KIND_NAMES => {
+            let count = r.varint()?;
+            if count > r.0.len() as u64 {
+                return Err(ResponseError::BadSyntax);
+            }
+            Response::Names((0..count).map(|_| r.str()).collect::<Result<_, _>>()?)
+        }
+        KIND_DESCRIPTION => {
+            let kind = r.byte()?.try_into()?;
+            let access = r.byte()?;
+            let range = match r.byte()? {
+                0 => None,
+                1 => Some((r.signed()?, r.signed()?, r.signed()?)),
+                _ => return Err(ResponseError::BadSyntax)
+            };
+            Response::Description(Description { kind, gettable: access & 1 != 0, settable: access & 2 != 0, range })
+        }
+        KIND_VERSION => Response::Version(r.signed()?, r.str()?),
+        KIND_LEASE => match r.byte()? {
+            0x00 => Response::Lease(None),
+            mode => {
+                let mode = mode.try_into().map_err(|_| ResponseError::BadSyntax)?;
+                let count = r.varint()?;
+                if count > r.0.len() as u64 {
+                    return Err(ResponseError::BadSyntax);
+                }
+                let holders = (0..count)
+                    .map(|_| Ok((r.holder().map_err(|_| ResponseError::BadSyntax)?, r.varint()?)))
+                    .collect::<Result<_, ResponseError>>()?;
+                let token = if r.0.is_empty() { None } else { Some(r.varint()?) };
+                Response::Lease(Some(LeaseState { mode, holders, token }))
+            }
+        },
+        KIND_ERROR => Response::Error(r.byte()?.try_into()?),
+        _ => return Err(ResponseError::BadSyntax)
+    };
+
+    Ok(Tagged { tag, inner: response })
+}
+
+impl Encode for Tagged<Response> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        put_response(&self.inner, self.tag, out)
+    }
+}
+
+impl Decode for Tagged<Response> {
+    type Error = ResponseError;
+
+    fn decode(payload: &[u8]) -> Result<Self, Self::Error> {
+        let mut r = Reader(payload);
+        let response = read_response(&mut r, false)?;
+        r.end()?;
+        Ok(response)
+    }
+}
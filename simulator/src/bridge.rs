@@ -0,0 +1,206 @@
+//! Bridge to a real TestBox on a serial port, so it can be reached through the
+//! same TCP interface as the simulator, or to another simulator or bridge over
+//! TCP. Requests from `server::shared` are written one line at a time, and the
+//! response is read up to its final `OK` or `ERR` line. A board that doesn't
+//! answer in time gets `ERR NO_RESPONSE` sent in its place, and its late answer
+//! is dropped rather than taken for the answer to the next request.
+//!
+//! When the port goes away, e.g. because the USB device re-enumerated, it is
+//! opened again as soon as it is back. A request that was waiting for its
+//! answer gets `ERR NO_RESPONSE`, and the next ones wait in the meantime.
+
+use std::{fmt, io, path::PathBuf, time::Duration};
+
+use log::{debug, info, warn};
+use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpStream, select, sync::{mpsc, watch}, time::{self, Instant}};
+use tokio_serial::{DataBits, FlowControl, Parity, SerialPortBuilderExt, StopBits};
+
+use crate::listener::Stream;
+use crate::parser::{self, Request, Response, ResponseError, Tagged};
+use crate::server::Exchange;
+use crate::supervisor::TaskResult;
+use crate::uart::BAUD_RATE;
+
+/// Where the requests are passed on to
+#[derive(Clone, Debug)]
+pub enum Upstream {
+    /// A board on a serial port
+    Serial(PathBuf),
+    /// A simulator or bridge, as `host:port`
+    Tcp(String),
+}
+
+impl fmt::Display for Upstream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Upstream::Serial(path) => write!(f, "{}", path.display()),
+            Upstream::Tcp(addr) => write!(f, "{}", addr),
+        }
+    }
+}
+
+impl Upstream {
+    async fn open(&self) -> io::Result<Box<dyn Stream>> {
+        match self {
+            Upstream::Serial(path) => {
+                let port = tokio_serial::new(path.to_string_lossy(), BAUD_RATE as u32)
+                    .data_bits(DataBits::Eight)
+                    .parity(Parity::None)
+                    .stop_bits(StopBits::One)
+                    .flow_control(FlowControl::None)
+                    .open_native_async()?;
+                Ok(Box::new(port))
+            }
+            Upstream::Tcp(addr) => Ok(Box::new(TcpStream::connect(addr).await?)),
+        }
+    }
+}
+
+/// Pause between attempts to open the port
+const RECONNECT_DELAY: Duration = Duration::from_millis(500);
+
+/// How long the board gets to answer a request
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Passes requests on to `upstream` until there are no clients left to send
+/// any, or until shutdown while it is gone
+pub async fn bridge(
+    upstream: Upstream,
+    mut exchanges: mpsc::Receiver<Exchange>,
+    mut shutdown: watch::Receiver<bool>
+) -> TaskResult {
+    let mut reported = false;
+
+    loop {
+        let port = match upstream.open().await {
+            Ok(port) => port,
+            Err(e) => {
+                if !std::mem::replace(&mut reported, true) {
+                    warn!("Failed to open {}: {}, retrying until it is back", upstream, e);
+                }
+                select! {
+                    _ = time::sleep(RECONNECT_DELAY) => continue,
+                    _ = shutdown.changed() => return Ok(()),
+                }
+            }
+        };
+        info!("Opened {}", upstream);
+        reported = false;
+
+        match serve(port, &mut exchanges).await {
+            Ok(()) => return Ok(()),
+            Err(e) => warn!("Lost {}: {}", upstream, e),
+        }
+    }
+}
+
+async fn serve(mut port: Box<dyn Stream>, exchanges: &mut mpsc::Receiver<Exchange>) -> io::Result<()> {
+    let mut buffer = [0u8; 256];
+    // What arrived so far of the answer to a request that timed out
+    let mut late: Option<Vec<u8>> = None;
+
+    loop {
+        select! {
+            exchange = exchanges.recv() => {
+                let Some(exchange) = exchange else {
+                    return Ok(());
+                };
+
+                // The port is lost, but the client still gets its answer
+                let response = match pass_on(&mut port, &exchange.request, &mut late).await {
+                    Ok(response) => response,
+                    Err(e) => {
+                        let _ = exchange.response.send(no_response(&exchange.request));
+                        return Err(e);
+                    }
+                };
+                if exchange.response.send(response).is_err() {
+                    debug!("Client is gone, dropping the response");
+                }
+            }
+
+            // Whatever the board sends on its own, e.g. the boot message after a
+            // reset, or the answer to a request that timed out
+            received = port.read(&mut buffer) => {
+                let received = match received? {
+                    0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+                    n => &buffer[..n],
+                };
+                match late.as_mut() {
+                    Some(partial) => {
+                        partial.extend_from_slice(received);
+                        if parser::is_complete(partial) {
+                            debug!("Dropping late {:?}", String::from_utf8_lossy(partial));
+                            late = None;
+                        }
+                    }
+                    None => debug!("Dropping unsolicited {:?}", String::from_utf8_lossy(received)),
+                }
+            }
+        }
+    }
+}
+
+/// Writes a request and reads its response, or `ERR NO_RESPONSE` if the board
+/// doesn't answer in time
+async fn pass_on(port: &mut Box<dyn Stream>, request: &[u8], late: &mut Option<Vec<u8>>) -> io::Result<Vec<u8>> {
+    // Waits for the late answer a while longer, rather than have it arrive
+    // after the next request is written
+    if let Some(partial) = late.take() {
+        match receive(port, partial).await? {
+            Ok(response) => debug!("Dropping late {:?}", String::from_utf8_lossy(&response)),
+            Err(_) => warn!("Still no response, going on"),
+        }
+    }
+    drain(port).await?;
+
+    port.write_all(request).await?;
+    debug!("Sent {:?}", String::from_utf8_lossy(request));
+    match receive(port, Vec::new()).await? {
+        Ok(response) => Ok(response),
+        Err(partial) => {
+            warn!("No response to {:?} within {:?}", String::from_utf8_lossy(request), RESPONSE_TIMEOUT);
+            *late = Some(partial);
+            Ok(no_response(request))
+        }
+    }
+}
+
+/// `ERR NO_RESPONSE`, tagged like the request so the client knows what wasn't answered
+fn no_response(request: &[u8]) -> Vec<u8> {
+    let tag = Tagged::<Request>::try_from(request).ok().and_then(|r| r.tag);
+    Tagged { tag, inner: Response::Error(ResponseError::NoResponse) }.into()
+}
+
+/// Reads the response lines that follow `response`, the partial response on timeout
+async fn receive(port: &mut Box<dyn Stream>, mut response: Vec<u8>) -> io::Result<Result<Vec<u8>, Vec<u8>>> {
+    let deadline = Instant::now() + RESPONSE_TIMEOUT;
+    let mut buffer = [0u8; 256];
+
+    while !parser::is_complete(&response) {
+        match time::timeout_at(deadline, port.read(&mut buffer)).await {
+            Ok(received) => match received? {
+                0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+                n => response.extend_from_slice(&buffer[..n]),
+            },
+            Err(_) => return Ok(Err(response)),
+        }
+    }
+
+    debug!("Received {:?}", String::from_utf8_lossy(&response));
+    Ok(Ok(response))
+}
+
+/// Drops whatever already arrived, so it isn't taken for the next response
+async fn drain(port: &mut Box<dyn Stream>) -> io::Result<()> {
+    let mut buffer = [0u8; 256];
+    loop {
+        match time::timeout(Duration::ZERO, port.read(&mut buffer)).await {
+            Ok(received) => match received? {
+                0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+                n => debug!("Dropping stale {:?}", String::from_utf8_lossy(&buffer[..n])),
+            },
+            Err(_) => return Ok(()),
+        }
+    }
+}
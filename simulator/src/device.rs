@@ -0,0 +1,415 @@
+//! The simulated board as a protocol state machine: bytes from the host go in,
+//! response bytes and test box state updates come out. Like the test box it is
+//! free of I/O, the caller passes in the time elapsed since boot and calls
+//! `advance` once `next_deadline` is reached.
+
+use std::{collections::VecDeque, time::Duration};
+
+use log::{debug, info, warn};
+#[cfg(feature = "runtime")]
+use tokio::{select, sync::{mpsc, watch}, time};
+
+#[cfg(feature = "runtime")]
+use crate::supervisor::TaskResult;
+
+use crate::firmware::LOOP_PERIOD;
+use crate::lease::{ClientId, Leases};
+use crate::parser::{ParseMode, Request, Response, ResponseError, Session, Tagged};
+use crate::scenario::{Playback, Scenario};
+use crate::testbox::{Event, TestBox, TestBoxState};
+use crate::uart::{self, LineSettings, Uart};
+
+/// Period of the test box ticks, when not running like the firmware's loop()
+const TICK_PERIOD: Duration = Duration::from_millis(100);
+
+/// Time the firmware spends reading the DHT22 sensor
+const SENSOR_READ_TIME: Duration = Duration::from_millis(5);
+
+/// What the ESP8266 boot ROM prints after a reset
+const BOOT_MESSAGE: &[u8] = b"\r\n ets Jan  8 2013,rst cause:2, boot mode:(3,6)\r\n\r\n";
+
+/// Serial settings of the boot ROM, which prints at 74880 baud
+const BOOT_LINE: LineSettings = LineSettings { baud_rate: 74880, ..LineSettings::BOARD };
+
+/// What the transport passes on to the device
+#[derive(Debug, Clone, PartialEq)]
+pub enum Input {
+    /// A new host connected, what follows comes from it
+    Connected(ClientId),
+    /// Bytes sent by the host
+    Bytes(Vec<u8>),
+    /// The host is gone or done sending
+    Disconnected,
+    /// The host changed its serial line settings
+    Line(LineSettings),
+    /// The host changed its modem control lines
+    Modem { dtr: bool, rts: bool },
+}
+
+/// Out-of-band access to a running device, for test harnesses
+#[cfg(feature = "runtime")]
+pub enum Control<const LEN: usize> {
+    /// Runs a function on the device, e.g. to change or read the test box
+    Apply(Box<dyn FnOnce(&mut Device<LEN>) + Send>),
+    /// Moves the device clock forward
+    Skip(Duration),
+    /// Plays a scenario from now on
+    Play(Scenario),
+}
+
+pub struct Device<const LEN: usize> {
+    session: Session<LEN>,
+    tbox: TestBox,
+    uart: Option<Uart>,
+    loop_cadence: bool,
+    /// Bytes received but not parsed yet, without a UART. Like the firmware,
+    /// the next request is only parsed once the previous one is answered.
+    received: VecDeque<u8>,
+    output: VecDeque<Vec<u8>>,
+    /// Start of the next loop() iteration, or of the next tick
+    next_loop: Duration,
+    changed: bool,
+    /// Serial settings of the host, bytes are garbled unless they match the board's
+    line: LineSettings,
+    dtr: bool,
+    /// Host that sent the requests being answered
+    client: ClientId,
+    leases: Leases,
+    scenario: Option<Playback>,
+}
+
+impl<const LEN: usize> Device<LEN> {
+    /// Creates a device around a test box. With a UART, received bytes go
+    /// through the serial input model. With `loop_cadence`, requests are
+    /// answered like the firmware's main loop does, at most one per iteration.
+    pub fn new(tbox: TestBox, mode: ParseMode, uart: Option<Uart>, loop_cadence: bool) -> Self {
+        Self {
+            session: Session::new(mode),
+            tbox,
+            uart,
+            loop_cadence,
+            received: VecDeque::new(),
+            output: VecDeque::new(),
+            next_loop: Duration::ZERO,
+            changed: true,
+            line: LineSettings::BOARD,
+            dtr: true,
+            client: 0,
+            leases: Leases::new(0),
+            scenario: None,
+        }
+    }
+
+    /// Whether the device runs like the firmware's loop(), rather than
+    /// answering requests as soon as they are received
+    fn looping(&self) -> bool {
+        self.uart.is_some() || self.loop_cadence
+    }
+
+    /// Handles what the transport received
+    pub fn input(&mut self, input: Input, now: Duration) {
+        match input {
+            Input::Connected(client) => self.client = client,
+            Input::Bytes(bytes) => self.receive(&bytes, now),
+            Input::Disconnected => self.disconnect(now),
+            Input::Line(line) => self.set_line(line),
+            Input::Modem { dtr, rts } => self.set_modem(dtr, rts, now),
+        }
+    }
+
+    /// Handles bytes received from the host
+    pub fn receive(&mut self, bytes: &[u8], now: Duration) {
+        let garbled;
+        let bytes = if self.line == LineSettings::BOARD {
+            bytes
+        } else {
+            garbled = uart::transcode(bytes, self.line, LineSettings::BOARD);
+            &garbled
+        };
+        info!("Received {} bytes to be parsed {:?}", bytes.len(), String::from_utf8_lossy(bytes));
+
+        match self.uart.as_mut() {
+            Some(uart) => uart.send(bytes, now),
+            None => self.received.extend(bytes),
+        }
+
+        if !self.looping() {
+            self.answer_pending(now);
+        }
+    }
+
+    /// The host is gone or done sending. Answers what it sent, forgets
+    /// everything about it and ends its responses with an empty buffer: what
+    /// `transmit` returns after that is for the next host.
+    pub fn disconnect(&mut self, now: Duration) {
+        info!("Client disconnected, resetting session");
+        self.answer_pending(now);
+        self.session.reset();
+        if let Some(uart) = self.uart.as_mut() {
+            uart.clear();
+        }
+        self.leases.disconnected(self.client);
+        self.line = LineSettings::BOARD;
+        self.dtr = true;
+        self.output.push_back(Vec::new());
+    }
+
+    /// Changes the serial settings of the host
+    pub fn set_line(&mut self, line: LineSettings) {
+        if line != self.line {
+            info!("Host serial settings: {:?}", line);
+        }
+        self.line = line;
+    }
+
+    /// Changes the modem control lines of the host. DTR going inactive resets
+    /// the board, RTS is not wired.
+    pub fn set_modem(&mut self, dtr: bool, rts: bool, now: Duration) {
+        debug!("Host modem lines: DTR={} RTS={}", dtr, rts);
+        if self.dtr && !dtr {
+            self.reset(now);
+        }
+        self.dtr = dtr;
+    }
+
+    /// Resets the board: the test box and the firmware start over, what the
+    /// host sent so far is lost
+    pub fn reset(&mut self, now: Duration) {
+        info!("Resetting the board");
+        self.tbox.reset(now);
+        self.session.reset();
+        self.received.clear();
+        if let Some(uart) = self.uart.as_mut() {
+            uart.clear();
+        }
+        self.next_loop = now;
+        self.changed = true;
+
+        self.send(BOOT_MESSAGE, BOOT_LINE);
+    }
+
+    /// Answers every request received so far, e.g. before shutting down
+    pub fn answer_pending(&mut self, now: Duration) {
+        while let Some(request) = self.parse() {
+            self.respond(request, now);
+        }
+    }
+
+    /// Fills the line buffer until a request is pending, from the UART if
+    /// there is one. Errors are answered in turn too, so all responses stay in order.
+    fn parse(&mut self) -> Option<Tagged<Result<Request, ResponseError>>> {
+        loop {
+            let c = match self.uart.as_mut() {
+                Some(uart) => uart.read(),
+                None => self.received.pop_front(),
+            }?;
+            if let Some(request) = self.session.push(c) {
+                info!("{:?}", request);
+                return Some(request);
+            }
+        }
+    }
+
+    /// Time at which `advance` has work to do
+    pub fn next_deadline(&self) -> Duration {
+        self.next_loop
+    }
+
+    /// Runs everything due by `now`
+    pub fn advance(&mut self, now: Duration) {
+        while self.next_loop <= now {
+            let start = self.next_loop;
+
+            self.next_loop = if self.looping() {
+                start + self.iteration(start)
+            } else {
+                if !self.tick(start).is_empty() {
+                    self.changed = true;
+                }
+                start + TICK_PERIOD
+            };
+        }
+    }
+
+    /// Plays a scenario from `now` on, in place of the one playing if any.
+    /// Steps are carried out at the ticks.
+    pub fn play(&mut self, scenario: Scenario, now: Duration) {
+        info!("Playing a scenario of {} steps", scenario.steps.len());
+        self.scenario = Some(Playback::new(scenario, now));
+    }
+
+    /// Seeds the lease tokens, which are only secret if the seed is
+    pub fn seed_leases(&mut self, seed: u64) {
+        self.leases = Leases::new(seed);
+    }
+
+    /// The scenario playing, if any
+    pub fn scenario(&self) -> Option<&Playback> {
+        self.scenario.as_ref()
+    }
+
+    pub fn testbox(&self) -> &TestBox {
+        &self.tbox
+    }
+
+    /// The test box, to change what the device senses
+    pub fn testbox_mut(&mut self) -> &mut TestBox {
+        &mut self.tbox
+    }
+
+    /// Next bytes to send to the host
+    pub fn transmit(&mut self) -> Option<Vec<u8>> {
+        self.output.pop_front()
+    }
+
+    /// State of the test box, if it changed since the last call
+    pub fn state(&mut self) -> Option<TestBoxState> {
+        std::mem::take(&mut self.changed).then(|| self.tbox.get())
+    }
+
+    /// Ticks the test box, once the scenario caught up with `now`
+    fn tick(&mut self, now: Duration) -> Vec<Event> {
+        if self.scenario.as_mut().is_some_and(|s| s.advance(&mut self.tbox, now)) {
+            self.reset(now);
+        }
+
+        let events = self.tbox.tick(now);
+        if let Some(scenario) = self.scenario.as_mut() {
+            if events.contains(&Event::SensorRead) {
+                scenario.sensor_read();
+            }
+        }
+        events
+    }
+
+    /// Like the firmware's loop(): step the self test, read the sensor, fill
+    /// the line buffer and answer at most one request. Returns the duration of
+    /// the iteration, which is longer than the loop period if it overran.
+    fn iteration(&mut self, now: Duration) -> Duration {
+        let mut busy = Duration::ZERO;
+
+        let events = self.tick(now);
+        if events.contains(&Event::SensorRead) {
+            busy += SENSOR_READ_TIME;
+        }
+        if !events.is_empty() {
+            self.changed = true;
+        }
+
+        if let Some(uart) = self.uart.as_mut() {
+            let dropped = uart.advance(now);
+            if dropped > 0 {
+                warn!("RX FIFO full, dropped {} bytes ({} in total)", dropped, uart.dropped());
+            }
+        }
+
+        if let Some(request) = self.parse() {
+            // Serial.print() blocks once the TX FIFO is full
+            let len = self.respond(request, now);
+            busy += uart::write_time(len, uart::BAUD_RATE);
+        }
+
+        if busy > LOOP_PERIOD {
+            debug!("Loop iteration overran: {:?}", busy);
+        }
+
+        busy.max(LOOP_PERIOD)
+    }
+
+    /// Answers a request, returns the number of bytes sent
+    fn respond(&mut self, request: Tagged<Result<Request, ResponseError>>, now: Duration) -> usize {
+        let response = request.map(|r| match r {
+            Ok(Request::Lease(lease)) => self.leases.handle(self.client, lease, now),
+            Ok(r) if !self.leases.allows(self.client, &r, now) => Response::Error(ResponseError::Leased),
+            Ok(r) => self.tbox.handle(r, now),
+            Err(e) => Response::Error(e),
+        });
+
+        let bytes = self.session.encode(response);
+        info!("Sending response {:?}", String::from_utf8_lossy(&bytes));
+
+        let len = bytes.len();
+        self.send(&bytes, LineSettings::BOARD);
+        self.changed = true;
+        len
+    }
+
+    /// Sends bytes written with the given serial settings, as the host receives them
+    fn send(&mut self, bytes: &[u8], line: LineSettings) {
+        let bytes = uart::transcode(bytes, line, self.line);
+        // An empty buffer would end the responses to the host
+        if !bytes.is_empty() {
+            self.output.push_back(bytes);
+        }
+    }
+}
+
+#[cfg(feature = "runtime")]
+pub async fn device<const LEN: usize>(
+    mut device: Device<LEN>,
+    mut incoming: mpsc::Receiver<Input>,
+    outgoing_bytes: mpsc::Sender<Vec<u8>>,
+    state_update_tx: mpsc::Sender<TestBoxState>,
+    mut control: Option<mpsc::Receiver<Control<LEN>>>,
+    mut shutdown: watch::Receiver<bool>
+) -> TaskResult {
+
+    let boot = time::Instant::now();
+    // Added to the time since boot by Control::Skip
+    let mut skipped = Duration::ZERO;
+
+    loop {
+        while let Some(bytes) = device.transmit() {
+            outgoing_bytes.send(bytes).await?;
+        }
+        if let Some(state) = device.state() {
+            state_update_tx.send(state).await?;
+        }
+
+        select! {
+            input = incoming.recv() => {
+                match input {
+                    Some(input) => device.input(input, boot.elapsed() + skipped),
+                    None => {
+                        info!("Receiving channel for bytes is closed, exiting");
+                        return Ok(());
+                    }
+                }
+            }
+
+            c = async { control.as_mut()?.recv().await }, if control.is_some() => {
+                match c {
+                    Some(Control::Apply(f)) => f(&mut device),
+                    Some(Control::Skip(d)) => {
+                        skipped += d;
+                        device.advance(boot.elapsed() + skipped);
+                    }
+                    Some(Control::Play(scenario)) => device.play(scenario, boot.elapsed() + skipped),
+                    None => control = None,
+                }
+            }
+
+            _ = time::sleep_until(boot + device.next_deadline().saturating_sub(skipped)) => {
+                device.advance(boot.elapsed() + skipped);
+            }
+
+            _ = shutdown.changed() => {
+                // Answer everything already received, the server sends it before closing the connection
+                incoming.close();
+                while let Some(input) = incoming.recv().await {
+                    device.input(input, boot.elapsed() + skipped);
+                }
+                device.answer_pending(boot.elapsed() + skipped);
+
+                while let Some(bytes) = device.transmit() {
+                    if outgoing_bytes.send(bytes).await.is_err() {
+                        info!("No client left to answer");
+                        break;
+                    }
+                }
+
+                return Ok(());
+            }
+        }
+    }
+}
@@ -0,0 +1,214 @@
+//! Request parsing that reproduces the firmware's `RequestParser::parse_into`
+//! and the error checks of `handle_request`, quirks included:
+//!
+//! * Tokens are split with `strtok(" \r\n")`, so repeated and leading spaces
+//!   are accepted and blank lines are answered with `BAD_VERB`.
+//! * Values are parsed with `strtol`, so `12abc` is `12` and out of range
+//!   values saturate to the limits of a 32-bit `long`.
+//! * Anything after the value, up to the line ending, turns the value back
+//!   into "empty": `SET` then fails with `BAD_VALUE`, while `GET` succeeds.
+//! * The request is a static that is only partially overwritten by each line,
+//!   so a line that stops early sees the fields left over by previous lines.
+//! * Unknown nouns are reported with an extra `Failed to find noun [...]` line.
+
+use std::time::Duration;
+
+use crate::parser::{Request, RequestNoun, ResponseError, GETTABLE, SETTABLE};
+
+/// Period of the firmware's `loop()` (`refresh_delay`)
+pub const LOOP_PERIOD: Duration = Duration::from_millis(20);
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Verb {
+    Empty,
+    Invalid,
+    Id,
+    Get,
+    Set,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Noun {
+    Empty,
+    Invalid,
+    Valid(RequestNoun),
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Value {
+    Empty,
+    Invalid,
+    Ok,
+}
+
+/// `strtok` over a line, keeping its position between calls
+struct Strtok<'a> {
+    line: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Strtok<'a> {
+    fn next(&mut self, delimiters: &[u8]) -> Option<&'a [u8]> {
+        let rest = &self.line[self.pos..];
+        let start = self.pos + rest.iter().position(|c| !delimiters.contains(c))?;
+
+        let token = &self.line[start..];
+        match token.iter().position(|c| delimiters.contains(c)) {
+            Some(end) => {
+                // strtok overwrites the delimiter and resumes right after it
+                self.pos = start + end + 1;
+                Some(&token[..end])
+            }
+            None => {
+                self.pos = self.line.len();
+                Some(token)
+            }
+        }
+    }
+}
+
+/// `strtol(s, &end, 10)` with a 32-bit `long`. Returns `None` if no digits were consumed.
+fn strtol(s: &[u8]) -> Option<i32> {
+    let s = &s[s.iter().position(|c| !b" \t\n\x0b\x0c\r".contains(c)).unwrap_or(s.len())..];
+
+    let (negative, s) = match s.first() {
+        Some(b'-') => (true, &s[1..]),
+        Some(b'+') => (false, &s[1..]),
+        _ => (false, s),
+    };
+
+    let digits = s.iter().take_while(|c| c.is_ascii_digit()).count();
+    if digits == 0 {
+        return None;
+    }
+
+    let magnitude = s[..digits].iter()
+        .fold(0i64, |acc, d| acc.saturating_mul(10).saturating_add((d - b'0') as i64));
+    let value = if negative { -magnitude } else { magnitude };
+
+    // On overflow strtol returns LONG_MIN or LONG_MAX
+    Some(value.clamp(i32::MIN as i64, i32::MAX as i64) as i32)
+}
+
+/// Firmware-compatible request parser. Keeps the state of the firmware's
+/// static `Request` between lines.
+pub struct FirmwareParser {
+    verb: Verb,
+    noun: Noun,
+    value: Value,
+    value_int: i32,
+}
+
+impl Default for FirmwareParser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FirmwareParser {
+    pub fn new() -> Self {
+        Self { verb: Verb::Empty, noun: Noun::Empty, value: Value::Empty, value_int: 0 }
+    }
+
+    /// Parses a line. Returns the request, or the error the firmware answers
+    /// with, and the diagnostic line the firmware prints before answering, if any.
+    pub fn parse(&mut self, line: &[u8]) -> (Result<Request, ResponseError>, Option<String>) {
+        let diagnostic = self.parse_into(line);
+        (self.request(), diagnostic)
+    }
+
+    /// Mirror of `RequestParser::parse_into`
+    fn parse_into(&mut self, line: &[u8]) -> Option<String> {
+        // The line buffer is a C string
+        let line = &line[..line.iter().position(|&c| c == 0).unwrap_or(line.len())];
+        let mut tokens = Strtok { line, pos: 0 };
+
+        // Parse VERB
+        let verb = match tokens.next(b" \r\n") {
+            Some(verb) => verb,
+            None => {
+                self.verb = Verb::Empty;
+                return None;
+            }
+        };
+
+        self.verb = match verb {
+            b"ID" => Verb::Id,
+            b"GET" => Verb::Get,
+            b"SET" => Verb::Set,
+            _ => {
+                self.verb = Verb::Invalid;
+                return None;
+            }
+        };
+
+        // Parse NOUN
+        let noun = match tokens.next(b" \r\n") {
+            Some(noun) => noun,
+            None => {
+                self.noun = Noun::Empty;
+                return None;
+            }
+        };
+
+        self.noun = match RequestNoun::try_from(noun) {
+            Ok(noun) => Noun::Valid(noun),
+            Err(_) => {
+                self.noun = Noun::Invalid;
+                return Some(format!("Failed to find noun [{}]", String::from_utf8_lossy(noun)));
+            }
+        };
+
+        // Parse VALUE
+        let value = match tokens.next(b" \r\n") {
+            Some(value) => value,
+            None => {
+                self.value = Value::Empty;
+                return None;
+            }
+        };
+
+        match strtol(value) {
+            Some(v) => self.value_int = v,
+            None => {
+                // strtol still stores its result, which is 0 when nothing was parsed
+                self.value_int = 0;
+                self.value = Value::Invalid;
+                return None;
+            }
+        }
+
+        self.value = Value::Ok;
+
+        // Remaining tokens
+        if tokens.next(b"\r\n").is_some() {
+            self.value = Value::Empty;
+        }
+
+        None
+    }
+
+    /// Mirror of the checks done by `handle_request`
+    fn request(&self) -> Result<Request, ResponseError> {
+        match self.verb {
+            Verb::Empty | Verb::Invalid => Err(ResponseError::BadVerb),
+
+            Verb::Id => match self.noun {
+                Noun::Empty => Ok(Request::Id),
+                _ => Err(ResponseError::BadNoun),
+            },
+
+            Verb::Get => match (self.value, self.noun) {
+                (Value::Empty, Noun::Valid(noun)) if GETTABLE.contains(&noun) => Ok(Request::Get(noun)),
+                (Value::Empty, _) => Err(ResponseError::BadNoun),
+                _ => Err(ResponseError::BadValue),
+            },
+
+            Verb::Set => match (self.value, self.noun) {
+                (Value::Ok, Noun::Valid(noun)) if SETTABLE.contains(&noun) => Ok(Request::Set(noun, self.value_int as i64)),
+                (Value::Ok, _) => Err(ResponseError::BadNoun),
+                _ => Err(ResponseError::BadValue),
+            },
+        }
+    }
+}
@@ -0,0 +1,337 @@
+//! Leases, so jobs sharing a board don't change its settings under each
+//! other. A lease is taken by a named holder, e.g. a CI job, for a number of
+//! seconds, and renewed by taking it again. While it is held, clients that
+//! didn't take it or join it with `LEASE AS` are refused with `ERR LEASED`:
+//! everything under an exclusive lease, `SET` under shared ones.
+//!
+//! Leases outlive the connections that took them, so one-shot clients can
+//! take a lease and use it from the next connection. Taking a lease answers
+//! with a token, which `LEASE AS` needs: holder names are no secret, `LEASE`
+//! tells them. Clients are told apart by the id the server gives each
+//! connection.
+
+use std::{collections::HashMap, convert::TryFrom, fmt, str::FromStr, time::Duration};
+
+use log::info;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::parser::{Request, Response, ResponseError};
+
+/// Identity of a connection, given by the server
+pub type ClientId = u64;
+
+/// Longest holder name
+pub const HOLDER_LEN: usize = 32;
+
+/// Secret given to the client taking a lease, to act for its holder later
+pub type Token = u64;
+
+/// Parses a token, written as hexadecimal digits
+pub fn token(data: &[u8]) -> Result<Token, ResponseError> {
+    std::str::from_utf8(data).ok()
+        .filter(|s| (1..=16).contains(&s.len()) && s.bytes().all(|c| c.is_ascii_hexdigit()))
+        .and_then(|s| Token::from_str_radix(s, 16).ok())
+        .ok_or(ResponseError::BadValue)
+}
+
+/// How a lease is held
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum LeaseMode {
+    /// A single holder, other clients can't send anything but `LEASE`
+    Exclusive,
+    /// Any number of holders, other clients can still read
+    Shared,
+}
+
+impl TryFrom<&[u8]> for LeaseMode {
+    type Error = ResponseError;
+
+    fn try_from(data: &[u8]) -> Result<Self, Self::Error> {
+        match data {
+            b"EXCLUSIVE" => Ok(Self::Exclusive),
+            b"SHARED" => Ok(Self::Shared),
+            _ => Err(ResponseError::BadNoun)
+        }
+    }
+}
+
+impl From<LeaseMode> for &'static str {
+    fn from(m: LeaseMode) -> Self {
+        match m {
+            LeaseMode::Exclusive => "EXCLUSIVE",
+            LeaseMode::Shared => "SHARED",
+        }
+    }
+}
+
+/// Name of a lease holder: letters, digits, `-`, `_`, `.` and `:`
+#[derive(Clone, Copy, Eq, PartialEq, Hash)]
+pub struct Holder {
+    name: [u8; HOLDER_LEN],
+    len: usize,
+}
+
+impl Holder {
+    pub fn as_str(&self) -> &str {
+        // Only ASCII is accepted
+        std::str::from_utf8(&self.name[..self.len]).unwrap_or_default()
+    }
+}
+
+impl TryFrom<&[u8]> for Holder {
+    type Error = ResponseError;
+
+    fn try_from(data: &[u8]) -> Result<Self, Self::Error> {
+        let valid = |c: &u8| c.is_ascii_alphanumeric() || matches!(c, b'-' | b'_' | b'.' | b':');
+        if data.is_empty() || data.len() > HOLDER_LEN || !data.iter().all(valid) {
+            return Err(ResponseError::BadValue);
+        }
+        let mut name = [0u8; HOLDER_LEN];
+        name[..data.len()].copy_from_slice(data);
+        Ok(Self { name, len: data.len() })
+    }
+}
+
+impl fmt::Debug for Holder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.as_str())
+    }
+}
+
+impl fmt::Display for Holder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum LeaseRequest {
+    /// `LEASE`: who holds the lease
+    Status,
+    /// `LEASE EXCLUSIVE|SHARED <HOLDER> <SECONDS>`: takes or renews a lease
+    Acquire(LeaseMode, Holder, u32),
+    /// `LEASE AS <HOLDER> <TOKEN>`: the connection acts for a holder, without renewing its lease
+    As(Holder, Token),
+    /// `LEASE RELEASE <HOLDER>`, from a connection acting for the holder
+    Release(Holder),
+}
+
+impl LeaseRequest {
+    /// Parses the tokens after `LEASE`
+    pub(crate) fn parse(noun: Option<&[u8]>, value: Option<&[u8]>) -> Result<Self, ResponseError> {
+        let Some(noun) = noun else {
+            return value.map_or(Ok(Self::Status), |_| Err(ResponseError::BadValue));
+        };
+        let value = value.ok_or(ResponseError::BadValue)?;
+
+        match noun {
+            b"AS" => {
+                let space = value.iter().position(|&c| c == b' ').ok_or(ResponseError::BadValue)?;
+                Ok(Self::As(value[..space].try_into()?, token(&value[space + 1..])?))
+            }
+            b"RELEASE" => Ok(Self::Release(value.try_into()?)),
+            mode => {
+                let mode = LeaseMode::try_from(mode)?;
+                let space = value.iter().position(|&c| c == b' ').ok_or(ResponseError::BadValue)?;
+                let seconds = std::str::from_utf8(&value[space + 1..]).ok()
+                    .filter(|s| s.bytes().all(|c| c.is_ascii_digit()))
+                    .and_then(|s| s.parse::<u32>().ok())
+                    .filter(|&s| s > 0)
+                    .ok_or(ResponseError::BadValue)?;
+                Ok(Self::Acquire(mode, value[..space].try_into()?, seconds))
+            }
+        }
+    }
+}
+
+impl fmt::Display for LeaseRequest {
+    /// The request line, without its line ending
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LeaseRequest::Status => write!(f, "LEASE"),
+            LeaseRequest::Acquire(mode, holder, seconds) => {
+                write!(f, "LEASE {} {} {}", <&str>::from(*mode), holder, seconds)
+            }
+            LeaseRequest::As(holder, token) => write!(f, "LEASE AS {} {:016X}", holder, token),
+            LeaseRequest::Release(holder) => write!(f, "LEASE RELEASE {}", holder),
+        }
+    }
+}
+
+/// Who holds the lease, as answered to `LEASE` requests
+#[derive(Debug, Clone, PartialEq)]
+pub struct LeaseState {
+    pub mode: LeaseMode,
+    /// Holders, and the seconds left until their lease expires, rounded up
+    pub holders: Vec<(Holder, u64)>,
+    /// Token of the lease just taken, only in the answer to taking it
+    pub token: Option<Token>,
+}
+
+impl fmt::Display for LeaseState {
+    /// `<MODE> <HOLDER> <SECONDS>,<HOLDER> <SECONDS>,... [TOKEN <TOKEN>]`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let holders: Vec<String> = self.holders.iter().map(|(holder, left)| format!("{} {}", holder, left)).collect();
+        write!(f, "{} {}", <&str>::from(self.mode), holders.join(","))?;
+        match self.token {
+            Some(token) => write!(f, " TOKEN {:016X}", token),
+            None => Ok(()),
+        }
+    }
+}
+
+impl FromStr for LeaseState {
+    type Err = ResponseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // A holder may be called TOKEN, but then it directly follows the mode
+        let (s, token) = match s.rsplitn(3, ' ').collect::<Vec<_>>()[..] {
+            [token, "TOKEN", state] if state.contains(' ') => (state, Some(self::token(token.as_bytes())?)),
+            _ => (s, None),
+        };
+        let (mode, holders) = s.split_once(' ').ok_or(ResponseError::BadSyntax)?;
+        let holders = holders.split(',')
+            .map(|holder| {
+                let (holder, left) = holder.split_once(' ').ok_or(ResponseError::BadSyntax)?;
+                Ok((holder.as_bytes().try_into()?, left.parse().map_err(|_| ResponseError::BadValue)?))
+            })
+            .collect::<Result<_, ResponseError>>()?;
+        Ok(Self { mode: mode.as_bytes().try_into()?, holders, token })
+    }
+}
+
+struct Lease {
+    holder: Holder,
+    /// As time since boot
+    expires: Duration,
+    token: Token,
+}
+
+/// Leases of one board, and which client acts for which holder
+pub struct Leases {
+    mode: Option<LeaseMode>,
+    held: Vec<Lease>,
+    clients: HashMap<ClientId, Holder>,
+    /// Draws the tokens
+    rng: StdRng,
+}
+
+impl Leases {
+    /// The seed drives the tokens, it must not be guessable for them to be secret
+    pub fn new(seed: u64) -> Self {
+        Self { mode: None, held: Vec::new(), clients: HashMap::new(), rng: StdRng::seed_from_u64(seed) }
+    }
+
+    /// Answers a `LEASE` request of `client`
+    pub fn handle(&mut self, client: ClientId, request: LeaseRequest, now: Duration) -> Response {
+        self.expire(now);
+
+        match request {
+            LeaseRequest::Status => {}
+            LeaseRequest::Acquire(mode, holder, seconds) => {
+                // Only clients acting for the holder renew its lease
+                let held = self.held.iter().any(|l| l.holder == holder);
+                let others = self.held.iter().any(|l| l.holder != holder);
+                if held && !self.acts_for(client, holder)
+                    || others && (mode, self.mode) != (LeaseMode::Shared, Some(LeaseMode::Shared)) {
+                    return Response::Error(ResponseError::Leased);
+                }
+
+                let expires = now + Duration::from_secs(seconds.into());
+                let token = match self.held.iter_mut().find(|l| l.holder == holder) {
+                    Some(lease) => {
+                        lease.expires = expires;
+                        lease.token
+                    }
+                    None => {
+                        let token = self.rng.gen();
+                        self.held.push(Lease { holder, expires, token });
+                        token
+                    }
+                };
+                info!("Client {} leased the board as {} ({}) for {} s", client, holder, <&str>::from(mode), seconds);
+                self.mode = Some(mode);
+                self.clients.insert(client, holder);
+                return Response::Lease(self.state(now).map(|state| LeaseState { token: Some(token), ..state }));
+            }
+            LeaseRequest::As(holder, token) => {
+                if !self.held.iter().any(|l| l.holder == holder && l.token == token) {
+                    return Response::Error(ResponseError::BadValue);
+                }
+                self.clients.insert(client, holder);
+            }
+            LeaseRequest::Release(holder) => {
+                if !self.held.iter().any(|l| l.holder == holder) {
+                    // Nothing to release
+                } else if self.acts_for(client, holder) {
+                    info!("Client {} released the lease of {}", client, holder);
+                    self.held.retain(|l| l.holder != holder);
+                    self.forget_holders();
+                } else {
+                    return Response::Error(ResponseError::Leased);
+                }
+            }
+        }
+
+        Response::Lease(self.state(now))
+    }
+
+    fn acts_for(&self, client: ClientId, holder: Holder) -> bool {
+        self.clients.get(&client) == Some(&holder)
+    }
+
+    /// Whether `client` may send anything, even lines that don't parse but
+    /// that the board may still act on
+    pub fn allows_any(&mut self, client: ClientId, now: Duration) -> bool {
+        self.expire(now);
+        self.mode.is_none() || self.clients.contains_key(&client)
+    }
+
+    /// Whether `client` may send `request` under the current leases
+    pub fn allows(&mut self, client: ClientId, request: &Request, now: Duration) -> bool {
+        if self.allows_any(client, now) {
+            return true;
+        }
+        match self.mode {
+            None => true,
+            Some(LeaseMode::Shared) => !matches!(request, Request::Set(..) | Request::SetMany(_)),
+            Some(LeaseMode::Exclusive) => matches!(request, Request::Lease(_)),
+        }
+    }
+
+    /// The connection of `client` is closed, its leases stay until they expire
+    pub fn disconnected(&mut self, client: ClientId) {
+        self.clients.remove(&client);
+    }
+
+    pub fn state(&self, now: Duration) -> Option<LeaseState> {
+        let mode = self.mode?;
+        let holders = self.held.iter()
+            .map(|l| (l.holder, l.expires.saturating_sub(now).as_millis().div_ceil(1000) as u64))
+            .collect();
+        Some(LeaseState { mode, holders, token: None })
+    }
+
+    fn expire(&mut self, now: Duration) {
+        let before = self.held.len();
+        self.held.retain(|l| {
+            let live = l.expires > now;
+            if !live {
+                info!("Lease of {} expired", l.holder);
+            }
+            live
+        });
+        if self.held.len() != before {
+            self.forget_holders();
+        }
+    }
+
+    /// Drops what belonged to holders whose lease is gone
+    fn forget_holders(&mut self) {
+        let held = &self.held;
+        self.clients.retain(|_, holder| held.iter().any(|l| l.holder == *holder));
+        if held.is_empty() {
+            self.mode = None;
+        }
+    }
+}
@@ -0,0 +1,22 @@
+pub mod binary;
+#[cfg(feature = "runtime")]
+pub mod bridge;
+pub mod device;
+pub mod firmware;
+pub mod lease;
+#[cfg(feature = "runtime")]
+pub mod listener;
+pub mod parser;
+#[cfg(feature = "runtime")]
+pub mod proxy;
+pub mod rfc2217;
+pub mod scenario;
+#[cfg(feature = "runtime")]
+pub mod server;
+pub mod shadow;
+#[cfg(feature = "runtime")]
+pub mod supervisor;
+pub mod testbox;
+pub mod uart;
+#[cfg(feature = "runtime")]
+pub mod ui;
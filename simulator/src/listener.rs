@@ -0,0 +1,143 @@
+//! Where the server accepts clients: a TCP port, or a Unix domain socket for
+//! local test processes that would otherwise compete for ports.
+
+use std::{fmt, io, net::SocketAddr};
+#[cfg(unix)]
+use std::path::PathBuf;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+
+/// Connection to a client, whatever the transport
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+#[derive(Clone, Debug)]
+pub enum Address {
+    Tcp(SocketAddr),
+    /// Socket file at `path`, with its permissions set to `mode` if given
+    #[cfg(unix)]
+    Unix { path: PathBuf, mode: Option<u32> },
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Address::Tcp(addr) => write!(f, "{}", addr),
+            #[cfg(unix)]
+            Address::Unix { path, .. } => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+impl Address {
+    pub async fn bind(&self) -> io::Result<Listener> {
+        match self {
+            Address::Tcp(addr) => Listener::tcp(TcpListener::bind(addr).await?),
+            #[cfg(unix)]
+            Address::Unix { path, mode } => unix::bind(path, *mode).await,
+        }
+    }
+}
+
+enum Inner {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix { listener: UnixListener, inode: u64 },
+}
+
+/// A bound address. A Unix socket file is removed when the listener is dropped.
+pub struct Listener {
+    inner: Inner,
+    address: Address,
+}
+
+impl Listener {
+    /// Wraps a bound TCP listener, e.g. one bound to port 0
+    pub fn tcp(listener: TcpListener) -> io::Result<Self> {
+        let address = Address::Tcp(listener.local_addr()?);
+        Ok(Self { inner: Inner::Tcp(listener), address })
+    }
+
+    /// Actual address, with the port picked by the system if 0 was asked for
+    pub fn address(&self) -> &Address {
+        &self.address
+    }
+
+    /// Waits for a client, returns its connection and a description of it
+    pub async fn accept(&self) -> io::Result<(Box<dyn Stream>, String)> {
+        match &self.inner {
+            Inner::Tcp(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                Ok((Box::new(stream), addr.to_string()))
+            }
+            #[cfg(unix)]
+            Inner::Unix { listener, .. } => {
+                let (stream, _) = listener.accept().await?;
+                Ok((Box::new(stream), format!("client on {}", self.address)))
+            }
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if let (Inner::Unix { inode, .. }, Address::Unix { path, .. }) = (&self.inner, &self.address) {
+            unix::remove(path, *inode);
+        }
+    }
+}
+
+#[cfg(unix)]
+mod unix {
+    use std::{fs, io, os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt}, path::Path};
+
+    use log::{info, warn};
+
+    use super::*;
+
+    pub async fn bind(path: &Path, mode: Option<u32>) -> io::Result<Listener> {
+        match fs::symlink_metadata(path) {
+            Ok(metadata) if !metadata.file_type().is_socket() => {
+                return Err(io::Error::new(io::ErrorKind::AlreadyExists, "path exists and is not a socket"));
+            }
+            Ok(_) => match UnixStream::connect(path).await {
+                Ok(_) => return Err(io::Error::new(io::ErrorKind::AddrInUse, "socket is in use")),
+                Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                    info!("Removing stale socket {}", path.display());
+                    fs::remove_file(path)?;
+                }
+                Err(e) => return Err(e),
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+
+        let listener = UnixListener::bind(path)?;
+        if let Some(mode) = mode {
+            fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+        }
+        let inode = fs::symlink_metadata(path)?.ino();
+
+        Ok(Listener {
+            inner: Inner::Unix { listener, inode },
+            address: Address::Unix { path: path.to_owned(), mode },
+        })
+    }
+
+    /// Removes the socket file, unless something else took its place since
+    pub fn remove(path: &Path, inode: u64) {
+        match fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.ino() == inode => {
+                if let Err(e) = fs::remove_file(path) {
+                    warn!("Failed to remove {}: {}", path.display(), e);
+                }
+            }
+            _ => {}
+        }
+    }
+}
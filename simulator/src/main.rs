@@ -1,45 +1,201 @@
-use std::{error::Error};
+use std::{fs::OpenOptions, net::SocketAddr, path::PathBuf, process::ExitCode, time::Duration};
 
-use log::info;
-use tokio::{sync::mpsc, signal};
+use clap::{ArgGroup, Parser};
+use log::error;
+use tokio::sync::mpsc;
 
-mod server;
-mod parser;
-mod testbox;
-mod ui;
+use simulator::{
+    bridge, device, listener::Address, server, parser, proxy, scenario::Scenario, shadow, supervisor::Supervisor, testbox, ui, uart
+};
+
+/// TestBox simulator
+#[derive(Parser)]
+#[command(group(ArgGroup::new("bridge").args(["serial", "upstream"])))]
+struct Args {
+    /// TCP port to listen on
+    #[arg(long, default_value_t = 12345)]
+    port: u16,
+
+    /// Listen on a Unix domain socket at this path instead of a TCP port. The socket file is
+    /// removed on exit, and replaced on startup if no simulator is listening on it anymore.
+    #[cfg(unix)]
+    #[arg(long, value_name = "PATH", conflicts_with = "port")]
+    unix_socket: Option<PathBuf>,
+
+    /// Permissions of the Unix socket file, in octal, e.g. 660. By default they follow the umask.
+    #[cfg(unix)]
+    #[arg(long, value_name = "MODE", value_parser = mode, requires = "unix_socket")]
+    socket_mode: Option<u32>,
+
+    /// Request parsing: `strict`, or `firmware` to reproduce the firmware's tokenizer exactly.
+    /// When bridging, only used by the shadow simulator.
+    #[arg(long, default_value = "strict")]
+    parse_mode: parser::ParseMode,
+
+    /// Model the board's serial input: bytes arrive at 115200 baud into an RX FIFO of the given
+    /// size, which is drained once per 20 ms main loop iteration. Bytes overflowing the FIFO are dropped.
+    #[arg(long, value_name = "BYTES", num_args = 0..=1, default_missing_value = "256")]
+    rx_fifo: Option<usize>,
+
+    /// Answer requests like the firmware's 20 ms main loop does: at most one per iteration
+    #[arg(long)]
+    loop_cadence: bool,
+
+    /// Speak Telnet with the RFC 2217 COM port control option, so serial tools can open the
+    /// simulator as a remote serial port, e.g. `rfc2217://localhost:12345`. The serial settings
+    /// they ask for must match the board's 115200 8N1, and deasserting DTR resets the board.
+    #[arg(long)]
+    rfc2217: bool,
+
+    /// Seed for the sensor readings, and for the chances of the faults when bridging, random by default
+    #[arg(long)]
+    seed: Option<u64>,
+
+    /// Play a scenario file: what the sensor senses over time, its faults and board reboots, e.g.
+    /// `30s temperature 35 over 60s`. Times count from startup.
+    #[arg(long, value_name = "PATH")]
+    scenario: Option<PathBuf>,
+
+    /// Bridge a real TestBox on this serial port instead of simulating one. Several clients can
+    /// be connected at once, their requests are passed on one line at a time.
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["rx_fifo", "loop_cadence", "rfc2217", "scenario"])]
+    serial: Option<PathBuf>,
+
+    /// Like `--serial`, but pass the requests on to a simulator or bridge at this address
+    #[arg(long, value_name = "HOST:PORT", conflicts_with_all = ["serial", "rx_fifo", "loop_cadence", "rfc2217", "scenario"])]
+    upstream: Option<String>,
+
+    /// Fault to inject into the responses when bridging: `[VERB] [NOUN] ACTION [ARGUMENTS] [CHANCE%]`,
+    /// with the action one of `delay <MILLISECONDS>`, `error <CODE>`, `corrupt TIMEOUT|CHECKSUM`,
+    /// `corrupt <TEMPERATURE> <HUMIDITY>` or `drop`. May be repeated, rules apply in order.
+    #[arg(long = "fault", value_name = "RULE", requires = "bridge")]
+    faults: Vec<proxy::Rule>,
+
+    /// When bridging, also send every request to an in-process simulator, and log where its
+    /// responses differ from those of the board. The board's are the ones sent to the client.
+    #[arg(long, requires = "bridge")]
+    shadow: bool,
+
+    /// How far the shadow simulator's values may be from the board's: `<NOUN> any`, or
+    /// `<NOUN> <DELTA>`. May be repeated. `TEMP_AND_HUM any` and `SELF_TEST 5` are always allowed.
+    #[arg(long = "tolerance", value_name = "RULE", requires = "shadow")]
+    tolerances: Vec<shadow::Tolerance>,
+
+    /// Append the divergences found by the shadow simulator to this file
+    #[arg(long, value_name = "PATH", requires = "shadow")]
+    shadow_report: Option<PathBuf>,
+
+    /// Close the connection when the client sent nothing for this many seconds
+    #[arg(long, value_name = "SECONDS", value_parser = seconds)]
+    idle_timeout: Option<Duration>,
+
+    /// Consider the client gone when a response can't be written for this many seconds. Also how
+    /// long a client that closed its side of the connection gets to receive the remaining responses.
+    #[arg(long, value_name = "SECONDS", value_parser = seconds, default_value = "5")]
+    half_open_timeout: Duration,
+}
+
+#[cfg(unix)]
+fn mode(s: &str) -> Result<u32, String> {
+    u32::from_str_radix(s, 8).map_err(|e| e.to_string())
+}
+
+fn seconds(s: &str) -> Result<Duration, String> {
+    let s: f64 = s.parse().map_err(|e| format!("{}", e))?;
+    Duration::try_from_secs_f64(s).map_err(|e| e.to_string())
+}
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
+async fn main() -> ExitCode {
     env_logger::init();
 
-    let (incoming_tx, incoming_rx) = mpsc::channel(10);
-    let (outgoing_tx, outgoing_rx) = mpsc::channel(10);
+    let args = Args::parse();
 
-    let (requests_tx, requests_rx) = mpsc::channel(10);
-    let (responses_tx, responses_rx) = mpsc::channel(10);
+    let scenario = match args.scenario {
+        Some(path) => match std::fs::read_to_string(&path).map_err(|e| e.to_string()).and_then(|s| s.parse::<Scenario>()) {
+            Ok(scenario) => Some(scenario),
+            Err(e) => {
+                error!("Failed to load scenario {}: {}", path.display(), e);
+                return ExitCode::FAILURE;
+            }
+        },
+        None => None,
+    };
 
-    let (ui_tx, ui_rx) = mpsc::channel(10);
+    let address = Address::Tcp(SocketAddr::from(([0, 0, 0, 0], args.port)));
+    #[cfg(unix)]
+    let address = match args.unix_socket {
+        Some(path) => Address::Unix { path, mode: args.socket_mode },
+        None => address,
+    };
+
+    let listener = match address.bind().await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Failed to listen on {}: {}", address, e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let protocol = if args.rfc2217 { server::Protocol::Rfc2217 } else { server::Protocol::Raw };
+    let timeouts = server::Timeouts {
+        idle: args.idle_timeout,
+        half_open: args.half_open_timeout,
+    };
 
-    tokio::spawn(async move {
-        server::server::<256usize>(12345, incoming_tx, outgoing_rx).await.unwrap()
-    });
+    let mut supervisor = Supervisor::new();
 
-    tokio::spawn(async move {
-        parser::parser::<256usize>(incoming_rx, outgoing_tx, requests_tx, responses_rx).await.unwrap()
-    });
+    let upstream = match (args.serial, args.upstream) {
+        (Some(path), _) => Some(bridge::Upstream::Serial(path)),
+        (None, Some(addr)) => Some(bridge::Upstream::Tcp(addr)),
+        (None, None) => None,
+    };
+
+    if let Some(upstream) = upstream {
+        let (exchanges_tx, exchanges_rx) = mpsc::channel(10);
+        let (upstream_tx, upstream_rx) = mpsc::channel(1);
+        let seed = args.seed.unwrap_or_else(rand::random);
+        let faults = proxy::Faults::new(args.faults, seed);
+
+        let shadow = args.shadow.then(|| {
+            let device = device::Device::<256usize>::new(testbox::TestBox::new(seed), args.parse_mode, None, false);
+            shadow::Shadow::new(device, &args.tolerances)
+        });
+        let report = match args.shadow_report {
+            Some(path) => match OpenOptions::new().create(true).append(true).open(&path) {
+                Ok(file) => Some(file),
+                Err(e) => {
+                    error!("Failed to open {}: {}", path.display(), e);
+                    return ExitCode::FAILURE;
+                }
+            },
+            None => None,
+        };
+
+        supervisor.spawn("server", server::shared::<256usize>(listener, timeouts, exchanges_tx, supervisor.shutdown()));
+        supervisor.spawn("proxy", proxy::proxy(exchanges_rx, upstream_tx, faults, shadow, report));
+        supervisor.spawn("bridge", bridge::bridge(upstream, upstream_rx, supervisor.shutdown()));
+        return supervisor.run().await;
+    }
+
+    let (incoming_tx, incoming_rx) = mpsc::channel(10);
+    let (outgoing_tx, outgoing_rx) = mpsc::channel(10);
+
+    let (ui_tx, ui_rx) = mpsc::channel(10);
 
-    tokio::spawn(async move {
-        testbox::testbox(requests_rx, responses_tx, ui_tx).await.unwrap()
-    });
+    supervisor.spawn("server", server::server::<256usize>(listener, protocol, timeouts, incoming_tx, outgoing_rx, supervisor.shutdown()));
 
-    tokio::spawn(async move {
-        ui::ui(ui_rx).await.unwrap()
-    });
+    let tbox = testbox::TestBox::new(args.seed.unwrap_or_else(rand::random));
+    let uart = args.rx_fifo.map(|size| uart::Uart::new(size, uart::BAUD_RATE));
+    let mut device = device::Device::<256usize>::new(tbox, args.parse_mode, uart, args.loop_cadence);
+    device.seed_leases(rand::random());
+    if let Some(scenario) = scenario {
+        device.play(scenario, Duration::ZERO);
+    }
 
-    // Wait for CTRL+C
-    signal::ctrl_c().await.expect("Failed to listen to CTRL+C");
+    supervisor.spawn("device", device::device(device, incoming_rx, outgoing_tx, ui_tx, None, supervisor.shutdown()));
 
-    info!("Received CTRL+C, exiting...");
+    supervisor.spawn("ui", ui::ui(ui_rx));
 
-    Ok(())
-}
\ No newline at end of file
+    supervisor.run().await
+}
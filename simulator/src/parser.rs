@@ -1,11 +1,13 @@
-use std::{error::Error, convert::{TryFrom, TryInto}, collections::HashSet};
+use std::{convert::{TryFrom, TryInto}, collections::{HashSet, VecDeque}, str::FromStr};
 
-use log::{info, debug};
-use tokio::{sync::mpsc, select};
-use regex::bytes::Regex;
+use log::debug;
 use lazy_static::lazy_static;
 
-#[derive(Debug, Eq, PartialEq, Hash)]
+use crate::binary::{Decode, Encode, FrameDecoder};
+use crate::firmware::FirmwareParser;
+use crate::lease::{LeaseRequest, LeaseState};
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
 pub enum RequestNoun {
     RedLed,
     YellowLed,
@@ -31,23 +33,335 @@ impl TryFrom<&[u8]> for RequestNoun {
     }
 }
 
+impl From<RequestNoun> for &'static str {
+    fn from(n: RequestNoun) -> Self {
+        match n {
+            RequestNoun::RedLed => "RED_LED",
+            RequestNoun::YellowLed => "YELLOW_LED",
+            RequestNoun::GreenLed => "GREEN_LED",
+            RequestNoun::Servo => "SERVO",
+            RequestNoun::TempAndHum => "TEMP_AND_HUM",
+            RequestNoun::SelfTest => "SELF_TEST",
+        }
+    }
+}
+
+impl RequestNoun {
+    pub const ALL: [RequestNoun; NOUN_COUNT] = [
+        RequestNoun::RedLed, RequestNoun::YellowLed, RequestNoun::GreenLed,
+        RequestNoun::Servo, RequestNoun::TempAndHum, RequestNoun::SelfTest
+    ];
+}
+
+/// All verbs understood by the simulator, as reported by `HELP`
+pub const VERBS: [&str; 9] = ["ID", "GET", "SET", "FRAMING", "HELP", "LIST", "DESCRIBE", "VERSION", "LEASE"];
+
+/// Version of the protocol spoken by the simulator, as reported by `VERSION`
+pub const PROTOCOL_VERSION: i64 = 1;
+
 lazy_static! {
-    static ref SETTABLE: HashSet<RequestNoun> = HashSet::from([
+    pub(crate) static ref SETTABLE: HashSet<RequestNoun> = HashSet::from([
         RequestNoun::RedLed, RequestNoun::YellowLed, RequestNoun::GreenLed,
         RequestNoun::Servo, RequestNoun::SelfTest
     ]);
 
-    static ref GETTABLE: HashSet<RequestNoun> = HashSet::from([
+    pub(crate) static ref GETTABLE: HashSet<RequestNoun> = HashSet::from([
         RequestNoun::RedLed, RequestNoun::YellowLed, RequestNoun::GreenLed,
         RequestNoun::Servo, RequestNoun::TempAndHum, RequestNoun::SelfTest
     ]);
 }
 
-#[derive(Debug)]
+/// Line framing used by a session
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Framing {
+    /// Bare lines, as spoken by the firmware
+    Plain,
+    /// Lines carry an NMEA-style `*HH` suffix: the XOR of all preceding bytes, in hex
+    Checksum
+}
+
+impl TryFrom<&[u8]> for Framing {
+    type Error = ResponseError;
+
+    fn try_from(data: &[u8]) -> Result<Self, Self::Error> {
+        match data {
+            b"PLAIN" => Ok(Self::Plain),
+            b"CHECKSUM" => Ok(Self::Checksum),
+            _ => Err(ResponseError::BadNoun)
+        }
+    }
+}
+
+impl From<Framing> for &'static str {
+    fn from(f: Framing) -> Self {
+        match f {
+            Framing::Plain => "PLAIN",
+            Framing::Checksum => "CHECKSUM",
+        }
+    }
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |acc, c| acc ^ c)
+}
+
+impl Framing {
+    /// Verifies and strips the framing of an incoming line. The checksum
+    /// is replaced by the line terminator, in place.
+    pub fn decode<'a>(&self, line: &'a mut [u8]) -> Result<&'a [u8], ResponseError> {
+        match self {
+            Framing::Plain => Ok(line),
+            Framing::Checksum => {
+                let len = line.strip_suffix(b"\n").ok_or(ResponseError::BadChecksum)?.len();
+                let len = if line[..len].ends_with(b"\r") { len - 1 } else { len };
+
+                let star = line[..len].iter().rposition(|&c| c == b'*').ok_or(ResponseError::BadChecksum)?;
+                let (payload, suffix) = (&line[..star], &line[star + 1..len]);
+
+                let expected = std::str::from_utf8(suffix).ok()
+                    .filter(|s| s.len() == 2)
+                    .and_then(|s| u8::from_str_radix(s, 16).ok())
+                    .ok_or(ResponseError::BadChecksum)?;
+
+                if checksum(payload) != expected {
+                    return Err(ResponseError::BadChecksum);
+                }
+
+                line[star] = b'\n';
+                Ok(&line[..star + 1])
+            }
+        }
+    }
+
+    /// Applies the framing to an outgoing `\r\n` terminated line
+    pub fn encode(&self, line: Vec<u8>) -> Vec<u8> {
+        match self {
+            Framing::Plain => line,
+            Framing::Checksum => {
+                let payload = line.strip_suffix(b"\r\n").unwrap_or(&line);
+                [payload, format!("*{:02X}\r\n", checksum(payload)).as_bytes()].concat()
+            }
+        }
+    }
+}
+
+/// Request tag, echoed back on the corresponding response
+pub type Tag = u32;
+
+/// A request or response, optionally carrying a tag (`#<tag> <payload>`)
+#[derive(Debug, Clone)]
+pub struct Tagged<T> {
+    pub tag: Option<Tag>,
+    pub inner: T
+}
+
+impl<T> Tagged<T> {
+    pub fn map<U>(self, f: impl FnOnce(T) -> U) -> Tagged<U> {
+        Tagged { tag: self.tag, inner: f(self.inner) }
+    }
+}
+
+/// Number of distinct nouns, and so the most items a batch request can hold
+pub const NOUN_COUNT: usize = 6;
+
+/// Items of a batch request, kept inline
+#[derive(Clone, Copy, Eq, PartialEq)]
+pub struct Batch<T: Copy> {
+    items: [Option<T>; NOUN_COUNT],
+    len: usize
+}
+
+impl<T: Copy + std::fmt::Debug> std::fmt::Debug for Batch<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+impl<T: Copy> Default for Batch<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Copy> Batch<T> {
+    pub fn new() -> Self {
+        Self { items: [None; NOUN_COUNT], len: 0 }
+    }
+
+    /// Appends an item, fails if the batch is full
+    pub fn push(&mut self, item: T) -> Result<(), ResponseError> {
+        let slot = self.items.get_mut(self.len).ok_or(ResponseError::BadNoun)?;
+        *slot = Some(item);
+        self.len += 1;
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = T> + '_ {
+        self.items[..self.len].iter().flatten().copied()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub enum Request {
     Id,
     Get(RequestNoun),
-    Set(RequestNoun, i64)
+    Set(RequestNoun, i64),
+    /// Several nouns read at once, answered with a single response
+    GetMany(Batch<RequestNoun>),
+    /// Several nouns set at once: either all of them are set, or none
+    SetMany(Batch<(RequestNoun, i64)>),
+    Framing(Framing),
+    Help,
+    List,
+    Describe(RequestNoun),
+    Version,
+    /// Handled by whoever knows the clients apart, not by the test box
+    Lease(LeaseRequest)
+}
+
+/// Tokens of a request line, without their leading spaces
+struct Captures<'a> {
+    verb: &'a [u8],
+    noun: Option<&'a [u8]>,
+    value: Option<&'a [u8]>
+}
+
+fn is_token(c: u8) -> bool {
+    !matches!(c, b' ' | b'\r' | b'\n')
+}
+
+fn is_value(c: u8) -> bool {
+    !matches!(c, b'\r' | b'\n')
+}
+
+/// Length of the UTF-8 encoded character at the start of `data`, if it is valid
+fn char_len(data: &[u8]) -> Option<usize> {
+    let head = &data[..data.len().min(4)];
+    let valid = match std::str::from_utf8(head) {
+        Ok(valid) => valid,
+        Err(e) => std::str::from_utf8(&head[..e.valid_up_to()]).ok()?
+    };
+    valid.chars().next().map(char::len_utf8)
+}
+
+impl<'a> Captures<'a> {
+    /// Finds the first request in `data`. Matches exactly what the regex
+    /// `([^ \r\n]+)( [^ \r\n]+)?( [^\r\n]+)?\r?\n` would, in one pass and
+    /// without allocating.
+    fn find(data: &'a [u8]) -> Option<Self> {
+        // Every match ends with a line feed and holds no other, so it is in a single line
+        let mut start = 0;
+        while let Some(len) = data[start..].iter().position(|&c| c == b'\n') {
+            let end = start + len;
+            if let Some(captures) = Self::in_line(data, start, end) {
+                return Some(captures);
+            }
+            start = end + 1;
+        }
+        None
+    }
+
+    /// Finds a request in the line from `start` to the line feed at `end`
+    fn in_line(data: &'a [u8], start: usize, end: usize) -> Option<Self> {
+        let content_end = if end > start && data[end - 1] == b'\r' { end - 1 } else { end };
+
+        // A match runs up to the line ending, so it starts after the last byte that can't
+        // be matched: invalid UTF-8, or a carriage return that doesn't end the line
+        let mut clean = start;
+        let mut i = start;
+        while i < content_end {
+            match char_len(&data[i..content_end]) {
+                Some(len) if data[i] != b'\r' => i += len,
+                _ => {
+                    i += 1;
+                    clean = i;
+                }
+            }
+        }
+
+        // The leftmost match starts at the first token. If it doesn't match, the line
+        // ends with the verb and a single space, and neither would a later start.
+        let verb_start = clean + data[clean..content_end].iter().position(|&c| c != b' ')?;
+        Self::at(data, verb_start)
+    }
+
+    /// End of the run of characters accepted by `f` starting at `i`. Like the
+    /// regex classes, only valid UTF-8 is matched.
+    fn run(data: &[u8], mut i: usize, f: fn(u8) -> bool) -> usize {
+        while let Some(&c) = data.get(i) {
+            let len = match c {
+                0x00..=0x7f if f(c) => 1,
+                0x00..=0x7f => break,
+                _ => match char_len(&data[i..]) {
+                    Some(len) => len,
+                    None => break
+                }
+            };
+            i += len;
+        }
+        i
+    }
+
+    /// End of a space followed by a non-empty run of bytes accepted by `f`, starting at `i`
+    fn group(data: &[u8], i: usize, f: fn(u8) -> bool) -> Option<usize> {
+        if data.get(i) != Some(&b' ') {
+            return None;
+        }
+        let end = Self::run(data, i + 1, f);
+        (end > i + 1).then_some(end)
+    }
+
+    fn line_end(data: &[u8], i: usize) -> bool {
+        match data.get(i) {
+            Some(b'\n') => true,
+            Some(b'\r') => data.get(i + 1) == Some(&b'\n'),
+            _ => false
+        }
+    }
+
+    /// Tries to match a request starting at `start`
+    fn at(data: &'a [u8], start: usize) -> Option<Self> {
+        let verb_end = Self::run(data, start, is_token);
+        if verb_end == start {
+            return None;
+        }
+
+        // Optional groups are greedy: try with a noun first, then without. Tokens always
+        // span whole runs, since a shorter one could only be followed by another token byte.
+        let nouns = Self::group(data, verb_end, is_token).map(Some).into_iter().chain([None]);
+
+        for noun_end in nouns {
+            let noun = noun_end.map(|end| &data[verb_end + 1..end]);
+            let noun_end = noun_end.unwrap_or(verb_end);
+
+            if let Some(value_end) = Self::group(data, noun_end, is_value) {
+                if Self::line_end(data, value_end) {
+                    let value = Some(&data[noun_end + 1..value_end]);
+                    return Some(Self { verb: &data[start..verb_end], noun, value });
+                }
+            }
+
+            if Self::line_end(data, noun_end) {
+                return Some(Self { verb: &data[start..verb_end], noun, value: None });
+            }
+        }
+
+        None
+    }
+}
+
+fn parse_value(value: &[u8]) -> Result<i64, ResponseError> {
+    std::str::from_utf8(value).ok()
+        .and_then(|v| v.parse::<i64>().ok())
+        .ok_or(ResponseError::BadValue)
 }
 
 impl TryFrom<&[u8]> for Request {
@@ -55,40 +369,97 @@ impl TryFrom<&[u8]> for Request {
 
     fn try_from(data: &[u8]) -> Result<Self, Self::Error> {
 
-        let re = Regex::new(r"([^ \r\n]+)( [^ \r\n]+)?( [^\r\n]+)?\r?\n")
-            .expect("Failed to create decoder regex");
-
-        let caps = re.captures(data).ok_or(ResponseError::BadSyntax)?;
+        let caps = Captures::find(data).ok_or(ResponseError::BadSyntax)?;
 
         debug!("verb={:?} noun={:?} value={:?}",
-            caps.get(1).map_or("".into(), |v| String::from_utf8_lossy(v.as_bytes())),
-            caps.get(2).map_or("".into(), |v| String::from_utf8_lossy(v.as_bytes())),
-            caps.get(3).map_or("".into(), |v| String::from_utf8_lossy(v.as_bytes())));
+            String::from_utf8_lossy(caps.verb),
+            String::from_utf8_lossy(caps.noun.unwrap_or_default()),
+            String::from_utf8_lossy(caps.value.unwrap_or_default()));
 
-        let verb = caps.get(1).ok_or(ResponseError::BadSyntax)?;
+        match caps.verb {
+            b"ID" => caps.noun.map_or(Ok(Self::Id), |_| Err(ResponseError::BadNoun)),
 
-        match verb.as_bytes() {
-            b"ID" => caps.get(2).map_or(Ok(Self::Id), |_| Err(ResponseError::BadNoun)),
+            b"GET" if caps.noun.is_some_and(|n| n.contains(&b',')) => {
+                // GET <NOUN>,<NOUN>,...
+                let mut nouns = Batch::new();
+
+                for noun in caps.noun.unwrap_or_default().split(|&c| c == b',') {
+                    let noun: RequestNoun = noun.try_into()?;
+                    GETTABLE.get(&noun).ok_or(ResponseError::BadNoun)?;
+
+                    if nouns.iter().any(|n| n == noun) {
+                        return Err(ResponseError::BadNoun);
+                    }
+                    nouns.push(noun)?;
+                }
+
+                caps.value.map_or(Ok(Self::GetMany(nouns)), |_| Err(ResponseError::BadValue))
+            }
+
+            b"SET" if caps.noun.is_some_and(|n| n.contains(&b'=')) => {
+                // SET <NOUN>=<VALUE>,<NOUN>=<VALUE>,...
+                let mut items = Batch::new();
+
+                for item in caps.noun.unwrap_or_default().split(|&c| c == b',') {
+                    let eq = item.iter().position(|&c| c == b'=').unwrap_or(item.len());
+                    let noun: RequestNoun = item[..eq].try_into()?;
+                    SETTABLE.get(&noun).ok_or(ResponseError::BadNoun)?;
+
+                    let value = parse_value(item.get(eq + 1..).ok_or(ResponseError::BadValue)?)?;
+
+                    if items.iter().any(|(n, _)| n == noun) {
+                        return Err(ResponseError::BadNoun);
+                    }
+                    items.push((noun, value))?;
+                }
+
+                caps.value.map_or(Ok(Self::SetMany(items)), |_| Err(ResponseError::BadValue))
+            }
 
             verb @ (b"GET" | b"SET") => {
-                let noun: RequestNoun = caps.get(2)
+                let noun: RequestNoun = caps.noun
                     .ok_or(ResponseError::BadNoun)?
-                    .as_bytes()[1..] // skip leading space
                     .try_into()?;
 
                 if verb == b"GET" {
                     GETTABLE.get(&noun).ok_or(ResponseError::BadNoun)?;
-                    caps.get(3).map_or(Ok(Self::Get(noun)), |_| Err(ResponseError::BadValue))
+                    caps.value.map_or(Ok(Self::Get(noun)), |_| Err(ResponseError::BadValue))
                 } else {
                     SETTABLE.get(&noun).ok_or(ResponseError::BadNoun)?;
-                    let value = caps.get(3).ok_or(ResponseError::BadValue)?;
-                    let value = String::from_utf8_lossy(&value.as_bytes()[1..]); // skip leading space
-                    let value = value.parse::<i64>().map_err(|_| ResponseError::BadValue)?;
+                    let value = parse_value(caps.value.ok_or(ResponseError::BadValue)?)?;
 
                     Ok(Self::Set(noun, value))
                 }
             }
 
+            b"FRAMING" => {
+                let framing: Framing = caps.noun
+                    .ok_or(ResponseError::BadNoun)?
+                    .try_into()?;
+
+                caps.value.map_or(Ok(Self::Framing(framing)), |_| Err(ResponseError::BadValue))
+            }
+
+            verb @ (b"HELP" | b"LIST" | b"VERSION") => {
+                let request = match verb {
+                    b"HELP" => Self::Help,
+                    b"LIST" => Self::List,
+                    _ => Self::Version
+                };
+
+                caps.noun.map_or(Ok(request), |_| Err(ResponseError::BadNoun))
+            }
+
+            b"DESCRIBE" => {
+                let noun: RequestNoun = caps.noun
+                    .ok_or(ResponseError::BadNoun)?
+                    .try_into()?;
+
+                caps.value.map_or(Ok(Self::Describe(noun)), |_| Err(ResponseError::BadValue))
+            }
+
+            b"LEASE" => LeaseRequest::parse(caps.noun, caps.value).map(Self::Lease),
+
             _ => {
                 Err(ResponseError::BadVerb)
             }
@@ -96,12 +467,85 @@ impl TryFrom<&[u8]> for Request {
     }
 }
 
-#[derive(Debug)]
+impl TryFrom<&[u8]> for Tagged<Request> {
+    type Error = Tagged<ResponseError>;
+
+    fn try_from(data: &[u8]) -> Result<Self, Self::Error> {
+        let (tag, payload) = match data.strip_prefix(b"#") {
+            Some(rest) => {
+                let space = rest.iter().position(|&c| c == b' ')
+                    .ok_or(Tagged { tag: None, inner: ResponseError::BadSyntax })?;
+
+                let tag = std::str::from_utf8(&rest[..space]).ok()
+                    .filter(|t| t.bytes().all(|c| c.is_ascii_digit()))
+                    .and_then(|t| t.parse::<Tag>().ok())
+                    .ok_or(Tagged { tag: None, inner: ResponseError::BadSyntax })?;
+
+                (Some(tag), &rest[space + 1..])
+            }
+            None => (None, data)
+        };
+
+        Request::try_from(payload)
+            .map(|inner| Tagged { tag, inner })
+            .map_err(|inner| Tagged { tag, inner })
+    }
+}
+
+impl std::fmt::Display for Request {
+    /// The request line, without its line ending
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = |noun: RequestNoun| -> &'static str { noun.into() };
+        match self {
+            Request::Id => write!(f, "ID"),
+            Request::Get(noun) => write!(f, "GET {}", name(*noun)),
+            Request::Set(noun, value) => write!(f, "SET {} {}", name(*noun), value),
+            Request::GetMany(nouns) => {
+                write!(f, "GET {}", nouns.iter().map(name).collect::<Vec<_>>().join(","))
+            }
+            Request::SetMany(items) => {
+                let items: Vec<String> = items.iter().map(|(noun, value)| format!("{}={}", name(noun), value)).collect();
+                write!(f, "SET {}", items.join(","))
+            }
+            Request::Framing(framing) => write!(f, "FRAMING {}", <&str>::from(*framing)),
+            Request::Help => write!(f, "HELP"),
+            Request::List => write!(f, "LIST"),
+            Request::Describe(noun) => write!(f, "DESCRIBE {}", name(*noun)),
+            Request::Version => write!(f, "VERSION"),
+            Request::Lease(lease) => write!(f, "{}", lease),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[allow(clippy::enum_variant_names)] // Names mirror the protocol error codes
 pub enum ResponseError {
     BadSyntax,
     BadVerb,
     BadNoun,
-    BadValue
+    BadValue,
+    BadChecksum,
+    /// Someone else holds a lease on the board
+    Leased,
+    /// The board behind a bridge didn't answer in time
+    NoResponse,
+}
+
+impl TryFrom<&[u8]> for ResponseError {
+    type Error = ();
+
+    fn try_from(data: &[u8]) -> Result<Self, Self::Error> {
+        match data {
+            b"BAD_SYNTAX" => Ok(Self::BadSyntax),
+            b"BAD_VERB" => Ok(Self::BadVerb),
+            b"BAD_NOUN" => Ok(Self::BadNoun),
+            b"BAD_VALUE" => Ok(Self::BadValue),
+            b"BAD_CHECKSUM" => Ok(Self::BadChecksum),
+            b"LEASED" => Ok(Self::Leased),
+            b"NO_RESPONSE" => Ok(Self::NoResponse),
+            _ => Err(())
+        }
+    }
 }
 
 impl From<ResponseError> for &'static str {
@@ -111,98 +555,414 @@ impl From<ResponseError> for &'static str {
             ResponseError::BadVerb => "BAD_VERB",
             ResponseError::BadNoun => "BAD_NOUN",
             ResponseError::BadValue => "BAD_VALUE",
+            ResponseError::BadChecksum => "BAD_CHECKSUM",
+            ResponseError::Leased => "LEASED",
+            ResponseError::NoResponse => "NO_RESPONSE",
+        }
+    }
+}
+
+/// Type of the value behind a noun
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ValueKind {
+    Int,
+    Bool,
+    /// Sensor status, temperature and humidity
+    Sensor
+}
+
+impl TryFrom<&[u8]> for ValueKind {
+    type Error = ();
+
+    fn try_from(data: &[u8]) -> Result<Self, Self::Error> {
+        match data {
+            b"INT" => Ok(Self::Int),
+            b"BOOL" => Ok(Self::Bool),
+            b"SENSOR" => Ok(Self::Sensor),
+            _ => Err(())
         }
     }
 }
 
-#[derive(Debug)]
+impl From<ValueKind> for &'static str {
+    fn from(k: ValueKind) -> Self {
+        match k {
+            ValueKind::Int => "INT",
+            ValueKind::Bool => "BOOL",
+            ValueKind::Sensor => "SENSOR",
+        }
+    }
+}
+
+/// Answer to `DESCRIBE`
+#[derive(Debug, Clone, Copy)]
+pub struct Description {
+    pub kind: ValueKind,
+    pub gettable: bool,
+    pub settable: bool,
+    /// Minimum, maximum and default value, for settable nouns
+    pub range: Option<(i64, i64, i64)>
+}
+
+impl Description {
+    pub fn new(noun: RequestNoun, kind: ValueKind, range: Option<(i64, i64, i64)>) -> Self {
+        Self { kind, gettable: GETTABLE.contains(&noun), settable: SETTABLE.contains(&noun), range }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub enum Response {
     Id(String),
     Value(i64),
     TempAndHum(String, f64, f64),
     SelfTest(bool, i64),
+    Framing(Framing),
+    /// Answers to a batch request, in request order
+    Many(Vec<Response>),
+    /// Answer to `HELP` and `LIST`
+    Names(Vec<String>),
+    Description(Description),
+    /// Protocol version and firmware version
+    Version(i64, String),
+    /// `None` if nobody holds a lease
+    Lease(Option<LeaseState>),
     Error(ResponseError)
 }
 
+impl Response {
+    /// Text of a good response, after the leading `OK `
+    fn body(self) -> String {
+        match self {
+            Response::Id(id) => id,
+            Response::Value(v) => v.to_string(),
+            Response::TempAndHum(s, t, h) => format!("{} {:.2} {:.2}", s, t, h),
+            Response::SelfTest(a, p) => format!("{} {}", if a {"ACTIVE"} else {"INACTIVE"}, p),
+            Response::Framing(f) => {
+                let f: &'static str = f.into();
+                f.into()
+            }
+            Response::Many(rs) => rs.into_iter().map(Response::body).collect::<Vec<_>>().join(","),
+            Response::Names(names) => names.join(","),
+            Response::Description(d) => {
+                let kind: &'static str = d.kind.into();
+                let access = match (d.gettable, d.settable) {
+                    (true, true) => "RW",
+                    (false, true) => "W",
+                    _ => "R"
+                };
+                match d.range {
+                    Some((min, max, def)) => format!("{} {} {} {} {}", kind, access, min, max, def),
+                    None => format!("{} {}", kind, access)
+                }
+            }
+            Response::Version(p, f) => format!("{} {}", p, f),
+            Response::Lease(state) => state.map_or_else(|| "NONE".into(), |s| s.to_string()),
+            Response::Error(e) => {
+                let e: &'static str = e.into();
+                e.into()
+            }
+        }
+    }
+}
+
 impl From<Response> for Vec<u8> {
     fn from(r: Response) -> Self {
         match r {
-            Response::Id(id) => format!("OK {}\r\n", id),
-            Response::Value(v) => format!("OK {}\r\n", v),
-            Response::TempAndHum(s, t, h) => format!("OK {} {:.2} {:.2}\r\n", s, t, h),
-            Response::SelfTest(a, p) => format!("OK {} {}\r\n", if a {"ACTIVE"} else {"INACTIVE"}, p),
             Response::Error(e) => {
                 let e: &'static str = e.into();
                 format!("ERR {}\r\n", e)
             }
+            r => format!("OK {}\r\n", r.body())
         }.into()
     }
 }
 
-pub(crate) async fn parser<const LEN:usize> (
-    mut incoming_bytes: mpsc::Receiver<Option<Vec<u8>>>,
-    outgoing_bytes: mpsc::Sender<Vec<u8>>,
-    incoming_requests: mpsc::Sender<Request>,
-    mut outgoing_responses: mpsc::Receiver<Response>
-) -> Result<(), Box<dyn Error>> {
-
-    let mut buffer = [0u8; LEN];
-    let mut buffer_len = 0usize;
-
-    while select! {
-        ib = incoming_bytes.recv() => {
-            match ib {
-                Some(None) => {
-                    info!("Client disconnected, clearing buffer");
-                    buffer_len = 0;
-                    true
-                },
-                Some(Some(ib)) => {
-                    info!("Received {} bytes to be parsed {:?}", ib.len(), String::from_utf8_lossy(&ib));
-
-                    for c in ib {
-                        buffer[buffer_len] = c;
-                        buffer_len += 1;
-
-                        if c == b'\n' || buffer_len == LEN {
-                            match (&buffer[..buffer_len]).try_into() {
-                                Ok(r) => {
-                                    info!("{:?}", r);
-                                    incoming_requests.send(r).await?;
-                                }
-                                Err(e) => {
-                                    outgoing_bytes.send(Response::Error(e).into()).await?
-                                }
-                            }
-
-                            buffer_len = 0;
-                        }
+impl From<Tagged<Response>> for Vec<u8> {
+    fn from(r: Tagged<Response>) -> Self {
+        let payload: Vec<u8> = r.inner.into();
+        match r.tag {
+            Some(tag) => [format!("#{} ", tag).into_bytes(), payload].concat(),
+            None => payload
+        }
+    }
+}
+
+impl Response {
+    /// Reads a response line back, as the answer to `request`. Returns `None`
+    /// if the line isn't one the simulator could have sent in answer to it.
+    pub fn decode_line(request: &Request, line: &[u8]) -> Option<Self> {
+        let line = line.strip_suffix(b"\n").unwrap_or(line);
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+
+        if let Some(code) = line.strip_prefix(b"ERR ") {
+            return ResponseError::try_from(code).ok().map(Response::Error);
+        }
+        let body = std::str::from_utf8(line.strip_prefix(b"OK ")?).ok()?;
+
+        match *request {
+            Request::Id => Some(Response::Id(body.into())),
+            Request::Get(noun) | Request::Set(noun, _) => Self::decode_value(noun, body),
+            Request::GetMany(nouns) => Self::decode_many(nouns.iter().collect(), body),
+            Request::SetMany(items) => Self::decode_many(items.iter().map(|(noun, _)| noun).collect(), body),
+            Request::Framing(_) => Framing::try_from(body.as_bytes()).ok().map(Response::Framing),
+            Request::Help | Request::List => Some(Response::Names(body.split(',').map(String::from).collect())),
+            Request::Describe(noun) => {
+                let fields: Vec<&str> = body.split(' ').collect();
+                let (kind, access, range) = match fields[..] {
+                    [kind, access] => (kind, access, None),
+                    [kind, access, min, max, def] => {
+                        (kind, access, Some((min.parse().ok()?, max.parse().ok()?, def.parse().ok()?)))
                     }
-                    true
-                },
-                None => {
-                    info!("Receiving channel for bytes is closed, exiting");
-                    false
-                }
+                    _ => return None
+                };
+                let kind = ValueKind::try_from(kind.as_bytes()).ok()?;
+                let description = Description::new(noun, kind, range);
+                let expected = match (description.gettable, description.settable) {
+                    (true, true) => "RW",
+                    (false, true) => "W",
+                    _ => "R"
+                };
+                (access == expected).then_some(Response::Description(description))
+            }
+            Request::Version => {
+                let (protocol, firmware) = body.split_once(' ')?;
+                Some(Response::Version(protocol.parse().ok()?, firmware.into()))
+            }
+            Request::Lease(_) => match body {
+                "NONE" => Some(Response::Lease(None)),
+                state => state.parse().ok().map(|s| Response::Lease(Some(s))),
+            }
+        }
+    }
+
+    fn decode_value(noun: RequestNoun, body: &str) -> Option<Self> {
+        let fields: Vec<&str> = body.split(' ').collect();
+        match (noun, &fields[..]) {
+            (RequestNoun::TempAndHum, [status, temperature, humidity]) => {
+                Some(Response::TempAndHum((*status).into(), temperature.parse().ok()?, humidity.parse().ok()?))
             }
+            (RequestNoun::SelfTest, [active, progress]) => {
+                let active = match *active {
+                    "ACTIVE" => true,
+                    "INACTIVE" => false,
+                    _ => return None
+                };
+                Some(Response::SelfTest(active, progress.parse().ok()?))
+            }
+            (RequestNoun::TempAndHum | RequestNoun::SelfTest, _) => None,
+            (_, [value]) => value.parse().ok().map(Response::Value),
+            _ => None
+        }
+    }
+
+    fn decode_many(nouns: Vec<RequestNoun>, body: &str) -> Option<Self> {
+        let bodies: Vec<&str> = body.split(',').collect();
+        if bodies.len() != nouns.len() {
+            return None;
+        }
+        nouns.into_iter().zip(bodies)
+            .map(|(noun, body)| Self::decode_value(noun, body))
+            .collect::<Option<_>>()
+            .map(Response::Many)
+    }
+}
+
+/// Splits what a request was answered with into the diagnostic lines, like
+/// `Failed to find noun`, and the final line with the `OK` or `ERR`
+pub fn split_response(response: &[u8]) -> (&[u8], &[u8]) {
+    let body = response.strip_suffix(b"\n").unwrap_or(response);
+    let start = body.iter().rposition(|&c| c == b'\n').map_or(0, |i| i + 1);
+    response.split_at(start)
+}
+
+/// Whether a response is complete: its final line is `OK ...` or `ERR ...`,
+/// possibly tagged
+pub fn is_complete(response: &[u8]) -> bool {
+    if !response.ends_with(b"\n") {
+        return false;
+    }
+    let (_, line) = split_response(response);
+    let line = match line.strip_prefix(b"#") {
+        Some(tagged) => tagged.splitn(2, |&c| c == b' ').nth(1).unwrap_or_default(),
+        None => line,
+    };
+    line.starts_with(b"OK") || line.starts_with(b"ERR")
+}
+
+impl Tagged<Response> {
+    /// Reads a response line back, with its tag if it has one
+    pub fn decode_line(request: &Request, line: &[u8]) -> Option<Self> {
+        let (tag, payload) = match line.strip_prefix(b"#") {
+            Some(rest) => {
+                let space = rest.iter().position(|&c| c == b' ')?;
+                let tag = std::str::from_utf8(&rest[..space]).ok()?.parse::<Tag>().ok()?;
+                (Some(tag), &rest[space + 1..])
+            }
+            None => (None, line)
+        };
+
+        Response::decode_line(request, payload).map(|inner| Tagged { tag, inner })
+    }
+}
+
+/// How request lines are parsed
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ParseMode {
+    /// Exactly one space between tokens and 64-bit values, plus the simulator's protocol extensions
+    Strict,
+    /// Reproduces the firmware's tokenizer, see [`crate::firmware`]
+    Firmware
+}
+
+impl FromStr for ParseMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "strict" => Ok(Self::Strict),
+            "firmware" => Ok(Self::Firmware),
+            _ => Err(format!("invalid parse mode '{}', expected 'strict' or 'firmware'", s))
+        }
+    }
+}
+
+/// Wire encoding of a session, detected from its first byte
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Codec {
+    Text,
+    /// Binary sessions start with a frame delimiter (`0x00`), which never starts a text line
+    Binary
+}
+
+/// Protocol state of a client session
+pub struct Session<const LEN: usize> {
+    mode: ParseMode,
+    codec: Option<Codec>,
+    buffer: [u8; LEN],
+    buffer_len: usize,
+    frames: FrameDecoder<LEN>,
+    /// Framing of the lines in both directions, switched once a `FRAMING` request is answered
+    framing: Framing,
+    firmware: FirmwareParser,
+    /// Lines to send before the response to the given request, numbered from the session start
+    diagnostics: VecDeque<(u64, String)>,
+    requests: u64,
+    responses: u64,
+}
+
+impl<const LEN: usize> Default for Session<LEN> {
+    fn default() -> Self {
+        Self::new(ParseMode::Strict)
+    }
+}
+
+impl<const LEN: usize> Session<LEN> {
+    pub fn new(mode: ParseMode) -> Self {
+        Self {
+            mode,
+            codec: None,
+            buffer: [0u8; LEN],
+            buffer_len: 0,
+            frames: FrameDecoder::new(),
+            framing: Framing::Plain,
+            firmware: FirmwareParser::new(),
+            diagnostics: VecDeque::new(),
+            requests: 0,
+            responses: 0,
         }
+    }
 
-        or = outgoing_responses.recv() => {
-            match or {
-                Some(r) => {
-                    let r: Vec<u8> = r.into();
-                    info!("Sending response {:?}", String::from_utf8_lossy(&r));
-                    outgoing_bytes.send(r).await?;
-                    true
+    /// Forgets everything about the current client
+    pub fn reset(&mut self) {
+        *self = Self::new(self.mode);
+    }
+
+    /// Feeds one received byte. Returns the decoded request, or the error to
+    /// answer with, once a complete line or frame has been received.
+    pub fn push(&mut self, c: u8) -> Option<Tagged<Result<Request, ResponseError>>> {
+        let codec = *self.codec.get_or_insert(if c == 0 && self.mode == ParseMode::Strict {
+            Codec::Binary
+        } else {
+            Codec::Text
+        });
+
+        let request = match codec {
+            Codec::Text => {
+                self.buffer[self.buffer_len] = c;
+                self.buffer_len += 1;
+
+                // The firmware keeps the last byte of its buffer for the string terminator
+                let len = match self.mode {
+                    ParseMode::Strict => LEN,
+                    ParseMode::Firmware => LEN - 1,
+                };
+                if c != b'\n' && self.buffer_len < len {
+                    return None;
                 }
 
-                None => {
-                    info!("Receiving channel for responses is closed, exiting");
-                    false
+                let line = &mut self.buffer[..self.buffer_len];
+                self.buffer_len = 0;
+
+                match self.mode {
+                    ParseMode::Strict => self.framing.decode(line)
+                        .map_err(|inner| Tagged { tag: None, inner })
+                        .and_then(Tagged::<Request>::try_from),
+
+                    ParseMode::Firmware => {
+                        let (request, diagnostic) = self.firmware.parse(line);
+
+                        if let Some(d) = diagnostic {
+                            self.diagnostics.push_back((self.requests, d));
+                        }
+
+                        request
+                            .map(|inner| Tagged { tag: None, inner })
+                            .map_err(|inner| Tagged { tag: None, inner })
+                    }
                 }
             }
+
+            Codec::Binary => match self.frames.push(c)? {
+                Ok(payload) => Tagged::<Request>::decode(&payload),
+                Err(inner) => Err(Tagged { tag: None, inner })
+            }
+        };
+
+        self.requests += 1;
+
+        Some(match request {
+            Ok(r) => r.map(Ok),
+            Err(e) => e.map(Err)
+        })
+    }
+
+    /// Encodes a response to be sent to the client. Responses must be
+    /// encoded in the order of the requests they answer. An accepted `FRAMING`
+    /// request applies to the lines pushed after its answer is encoded, a
+    /// refused one changes nothing.
+    pub fn encode(&mut self, r: Tagged<Response>) -> Vec<u8> {
+        let mut out = Vec::new();
+
+        if self.diagnostics.front().is_some_and(|(n, _)| *n == self.responses) {
+            if let Some((_, d)) = self.diagnostics.pop_front() {
+                out.extend_from_slice(format!("{}\r\n", d).as_bytes());
+            }
+        }
+        self.responses += 1;
+
+        match self.codec {
+            Some(Codec::Binary) => out.extend(r.to_frame()),
+            _ => {
+                let switch_to = match r.inner {
+                    Response::Framing(f) => Some(f),
+                    _ => None
+                };
+
+                out.extend(self.framing.encode(r.into()));
+                self.framing = switch_to.unwrap_or(self.framing);
+            }
         }
-    } {}
 
-    Ok(())
+        out
+    }
 }
@@ -0,0 +1,357 @@
+//! Proxy between clients and an upstream TestBox, real or simulated. Each
+//! transaction is decoded with the parser types and logged, and faults can be
+//! injected into the responses to reproduce what was seen in the field without
+//! touching the firmware.
+//!
+//! Faults are given as rules: `[VERB] [NOUN] ACTION [ARGUMENTS] [CHANCE%]`, e.g.
+//! `GET SERVO delay 200`, `SET error BAD_VALUE 10%` or `TEMP_AND_HUM corrupt
+//! CHECKSUM`. A rule without a verb or noun matches every request.
+
+use std::{fmt, fs::File, io::Write, str::FromStr, time::Duration};
+
+use log::{debug, info, warn};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use tokio::{sync::{mpsc, oneshot}, time::{self, Instant}};
+
+use crate::parser::{self, Request, RequestNoun, Response, ResponseError, Tagged, VERBS};
+use crate::server::Exchange;
+use crate::shadow::Shadow;
+use crate::supervisor::TaskResult;
+use crate::testbox::SensorFault;
+
+/// What a corrupted sensor reading reports instead
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Reading {
+    /// A failed read, with both values cleared like the firmware does
+    Fault(SensorFault),
+    /// A successful read of the given temperature and humidity
+    Values(f64, f64),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    /// Holds the response back
+    Delay(Duration),
+    /// Replaces the response with `ERR <code>`, which need not be a code the simulator knows
+    Error(String),
+    /// Replaces the sensor readings in the response
+    Corrupt(Reading),
+    /// Never answers
+    Drop,
+}
+
+/// Fault applied to the responses to matching requests
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rule {
+    verb: Option<&'static str>,
+    noun: Option<RequestNoun>,
+    action: Action,
+    /// Percentage of the matching requests affected, all of them by default
+    chance: Option<f64>,
+}
+
+impl FromStr for Rule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut tokens: Vec<&str> = s.split_whitespace().collect();
+
+        let chance = match tokens.last().and_then(|t| t.strip_suffix('%')) {
+            Some(chance) => {
+                let chance: f64 = chance.parse().map_err(|_| format!("invalid chance '{}%'", chance))?;
+                if !(0.0..=100.0).contains(&chance) {
+                    return Err(format!("chance {}% is not between 0% and 100%", chance));
+                }
+                tokens.pop();
+                Some(chance)
+            }
+            None => None,
+        };
+
+        let mut tokens = tokens.into_iter().peekable();
+        let verb = tokens.next_if(|t| VERBS.contains(t))
+            .and_then(|t| VERBS.iter().copied().find(|v| *v == t));
+        let noun = tokens.next_if(|t| RequestNoun::try_from(t.as_bytes()).is_ok())
+            .and_then(|t| RequestNoun::try_from(t.as_bytes()).ok());
+
+        let action = tokens.next();
+        let arguments: Vec<&str> = tokens.collect();
+        let action = match action {
+            Some("delay") => match arguments[..] {
+                [ms] => Action::Delay(Duration::from_millis(ms.parse().map_err(|_| format!("invalid delay '{}'", ms))?)),
+                _ => return Err("expected 'delay <MILLISECONDS>'".into()),
+            },
+            Some("error") => match arguments[..] {
+                [code] if !code.is_empty() && code.bytes().all(|c| c.is_ascii_uppercase() || c == b'_') => {
+                    Action::Error(code.into())
+                }
+                _ => return Err("expected 'error <CODE>', e.g. 'error BAD_VALUE'".into()),
+            },
+            Some("corrupt") => {
+                if noun.is_some_and(|n| n != RequestNoun::TempAndHum) {
+                    return Err("only TEMP_AND_HUM readings can be corrupted".into());
+                }
+                match arguments[..] {
+                    [fault] => Action::Corrupt(Reading::Fault(fault.parse()?)),
+                    [temperature, humidity] => match (temperature.parse(), humidity.parse()) {
+                        (Ok(t), Ok(h)) => Action::Corrupt(Reading::Values(t, h)),
+                        _ => return Err(format!("invalid reading '{} {}'", temperature, humidity)),
+                    },
+                    _ => return Err("expected 'corrupt TIMEOUT|CHECKSUM' or 'corrupt <TEMPERATURE> <HUMIDITY>'".into()),
+                }
+            }
+            Some("drop") => match arguments[..] {
+                [] => Action::Drop,
+                _ => return Err("'drop' takes no arguments".into()),
+            },
+            Some(other) => return Err(format!("unknown action '{}', expected 'delay', 'error', 'corrupt' or 'drop'", other)),
+            None => return Err("missing action".into()),
+        };
+
+        Ok(Self { verb, noun, action, chance })
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(verb) = self.verb {
+            write!(f, "{} ", verb)?;
+        }
+        if let Some(noun) = self.noun {
+            write!(f, "{} ", <&str>::from(noun))?;
+        }
+        match &self.action {
+            Action::Delay(delay) => write!(f, "delay {}", delay.as_millis())?,
+            Action::Error(code) => write!(f, "error {}", code)?,
+            Action::Corrupt(Reading::Fault(fault)) => write!(f, "corrupt {}", <&str>::from(*fault))?,
+            Action::Corrupt(Reading::Values(t, h)) => write!(f, "corrupt {} {}", t, h)?,
+            Action::Drop => write!(f, "drop")?,
+        }
+        match self.chance {
+            Some(chance) => write!(f, " {}%", chance),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Rule {
+    /// Whether the rule is about `request`, which is `None` if it couldn't be decoded
+    fn matches(&self, request: Option<&Request>) -> bool {
+        match request {
+            Some(request) => {
+                self.verb.is_none_or(|v| v == verb(request)) && self.noun.is_none_or(|n| nouns(request).contains(&n))
+            }
+            None => self.verb.is_none() && self.noun.is_none(),
+        }
+    }
+}
+
+fn verb(request: &Request) -> &'static str {
+    match request {
+        Request::Id => "ID",
+        Request::Get(_) | Request::GetMany(_) => "GET",
+        Request::Set(..) | Request::SetMany(_) => "SET",
+        Request::Framing(_) => "FRAMING",
+        Request::Help => "HELP",
+        Request::List => "LIST",
+        Request::Describe(_) => "DESCRIBE",
+        Request::Version => "VERSION",
+        Request::Lease(_) => "LEASE",
+    }
+}
+
+fn nouns(request: &Request) -> Vec<RequestNoun> {
+    match *request {
+        Request::Get(noun) | Request::Set(noun, _) | Request::Describe(noun) => vec![noun],
+        Request::GetMany(nouns) => nouns.iter().collect(),
+        Request::SetMany(items) => items.iter().map(|(noun, _)| noun).collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Response after the faults, `None` if it is dropped
+#[derive(Debug)]
+pub struct Faulted {
+    pub response: Option<Vec<u8>>,
+    pub delay: Duration,
+    /// Rules that changed the response
+    pub applied: Vec<String>,
+}
+
+/// Fault rules, with the randomness deciding when those with a chance apply
+pub struct Faults {
+    rules: Vec<Rule>,
+    rng: StdRng,
+}
+
+impl Faults {
+    pub fn new(rules: Vec<Rule>, seed: u64) -> Self {
+        Self { rules, rng: StdRng::seed_from_u64(seed) }
+    }
+
+    /// Applies the matching rules, in order, to the response to `request`
+    pub fn apply(&mut self, request: &Tagged<Result<Request, ResponseError>>, response: Vec<u8>) -> Faulted {
+        let mut faulted = Faulted { response: Some(response), delay: Duration::ZERO, applied: Vec::new() };
+
+        for rule in &self.rules {
+            if !rule.matches(request.inner.as_ref().ok()) {
+                continue;
+            }
+            if rule.chance.is_some_and(|chance| self.rng.gen::<f64>() * 100.0 >= chance) {
+                continue;
+            }
+            let Some(response) = faulted.response.as_mut() else {
+                break;
+            };
+
+            let applied = match &rule.action {
+                Action::Delay(delay) => {
+                    faulted.delay += *delay;
+                    true
+                }
+                Action::Error(code) => {
+                    let tag = request.tag.map(|tag| format!("#{} ", tag)).unwrap_or_default();
+                    *response = format!("{}ERR {}\r\n", tag, code).into_bytes();
+                    true
+                }
+                Action::Corrupt(reading) => match &request.inner {
+                    Ok(request) => corrupt(request, response, *reading),
+                    Err(_) => false,
+                },
+                Action::Drop => {
+                    faulted.response = None;
+                    true
+                }
+            };
+
+            if applied {
+                faulted.applied.push(rule.to_string());
+            }
+        }
+
+        faulted
+    }
+}
+
+/// Replaces the sensor readings in the final line of the response, returns
+/// whether there were any
+fn corrupt(request: &Request, response: &mut Vec<u8>, reading: Reading) -> bool {
+    let start = parser::split_response(response).0.len();
+    let Some(mut decoded) = Tagged::<Response>::decode_line(request, &response[start..]) else {
+        return false;
+    };
+
+    let mut replace = |r: &mut Response| match r {
+        Response::TempAndHum(status, temperature, humidity) => {
+            (*status, *temperature, *humidity) = match reading {
+                Reading::Fault(fault) => (<&str>::from(fault).into(), 0.0, 0.0),
+                Reading::Values(t, h) => ("OK".into(), t, h),
+            };
+            true
+        }
+        _ => false,
+    };
+    let replaced = match &mut decoded.inner {
+        Response::Many(responses) => responses.iter_mut().map(&mut replace).fold(false, |a, b| a | b),
+        r => replace(r),
+    };
+
+    if replaced {
+        response.truncate(start);
+        response.extend(Vec::<u8>::from(decoded));
+    }
+    replaced
+}
+
+/// Decodes a request line like the simulator would in strict mode
+pub fn decode_request(line: &[u8]) -> Tagged<Result<Request, ResponseError>> {
+    match Tagged::<Request>::try_from(line) {
+        Ok(request) => request.map(Ok),
+        Err(e) => e.map(Err),
+    }
+}
+
+fn text(bytes: &[u8]) -> String {
+    format!("{:?}", String::from_utf8_lossy(bytes).trim_end())
+}
+
+/// Passes requests on to `upstream`, logging each transaction and faulting
+/// the responses, until there are no clients left. With a shadow simulator,
+/// where it disagrees with `upstream` is logged, and written to `report`.
+pub async fn proxy<const LEN: usize>(
+    mut exchanges: mpsc::Receiver<Exchange>,
+    upstream: mpsc::Sender<Exchange>,
+    mut faults: Faults,
+    mut shadow: Option<Shadow<LEN>>,
+    mut report: Option<File>
+) -> TaskResult {
+    let mut count = 0u64;
+    let started = Instant::now();
+
+    while let Some(exchange) = exchanges.recv().await {
+        count += 1;
+        let request = decode_request(&exchange.request);
+        let annotation = match &request.inner {
+            Ok(decoded) => format!("{:?}", decoded),
+            Err(e) => format!("invalid, {}", <&str>::from(*e)),
+        };
+
+        let (response_tx, response_rx) = oneshot::channel();
+        let sent = Instant::now();
+        upstream.send(Exchange { request: exchange.request.clone(), response: response_tx }).await
+            .map_err(|_| "upstream is gone")?;
+
+        let Ok(response) = response_rx.await else {
+            info!("#{} {} [{}] was not answered", count, text(&exchange.request), annotation);
+            continue;
+        };
+        let elapsed = sent.elapsed();
+
+        let start = parser::split_response(&response).0.len();
+        let decoded = request.inner.as_ref().ok()
+            .and_then(|r| Tagged::<Response>::decode_line(r, &response[start..]))
+            .map_or_else(|| "not understood".into(), |r| format!("{:?}", r.inner));
+        let diagnostics = match start {
+            0 => String::new(),
+            _ => format!(" after {}", text(&response[..start])),
+        };
+        info!(
+            "#{} {} [{}] -> {} [{}]{} in {} ms",
+            count, text(&exchange.request), annotation, text(&response[start..]), decoded, diagnostics, elapsed.as_millis()
+        );
+
+        if let Some(divergence) = shadow.as_mut().and_then(|s| s.compare(&exchange.request, &response, started.elapsed())) {
+            warn!("#{} {}", count, divergence);
+            if let Some(report) = report.as_mut() {
+                writeln!(report, "#{} {}", count, divergence)?;
+            }
+        }
+
+        let faulted = faults.apply(&request, response);
+        if !faulted.applied.is_empty() {
+            let outcome = faulted.response.as_deref().map_or_else(|| "nothing".into(), text);
+            info!("#{} faults {} applied, answering {}", count, faulted.applied.join(", "), outcome);
+        }
+
+        let Some(response) = faulted.response else {
+            continue;
+        };
+        if faulted.delay.is_zero() {
+            if exchange.response.send(response).is_err() {
+                debug!("Client is gone, dropping the response");
+            }
+        } else {
+            // Delays only hold back this client, whose next request waits for the response
+            tokio::spawn(async move {
+                time::sleep(faulted.delay).await;
+                let _ = exchange.response.send(response);
+            });
+        }
+    }
+
+    if let Some(shadow) = shadow {
+        let (requests, divergences) = shadow.counts();
+        info!("Shadow simulator diverged on {} of {} requests", divergences, requests);
+    }
+    Ok(())
+}
@@ -0,0 +1,318 @@
+//! Server side of the Telnet protocol with the RFC 2217 COM port control
+//! option, so serial tools can open the simulator as a remote serial port
+//! (e.g. pyserial's `rfc2217://host:port`).
+//!
+//! Like the parser it is free of I/O: bytes from the client go in, device
+//! inputs and replies for the client come out. Only the BINARY, SUPPRESS-GO-AHEAD
+//! and COM-PORT-OPTION options are accepted, and the server waits for the
+//! client to ask for them.
+
+use log::{debug, info};
+
+use crate::device::Input;
+use crate::uart::{LineSettings, Parity, StopBits};
+
+const SE: u8 = 240;
+const SB: u8 = 250;
+const WILL: u8 = 251;
+const WONT: u8 = 252;
+const DO: u8 = 253;
+const DONT: u8 = 254;
+const IAC: u8 = 255;
+
+const BINARY: u8 = 0;
+const SUPPRESS_GO_AHEAD: u8 = 3;
+const COM_PORT_OPTION: u8 = 44;
+
+// COM-PORT-OPTION commands from the client, the server answers with the same plus 100
+const SIGNATURE: u8 = 0;
+const SET_BAUDRATE: u8 = 1;
+const SET_DATASIZE: u8 = 2;
+const SET_PARITY: u8 = 3;
+const SET_STOPSIZE: u8 = 4;
+const SET_CONTROL: u8 = 5;
+const NOTIFY_LINESTATE: u8 = 6;
+const NOTIFY_MODEMSTATE: u8 = 7;
+const SET_LINESTATE_MASK: u8 = 10;
+const SET_MODEMSTATE_MASK: u8 = 11;
+const PURGE_DATA: u8 = 12;
+const SERVER_OFFSET: u8 = 100;
+
+// Values of SET-CONTROL
+const FLOW_CONTROL_REQUEST: u8 = 0;
+const FLOW_CONTROL_NONE: u8 = 1;
+const FLOW_CONTROL_HARDWARE: u8 = 3;
+const BREAK_REQUEST: u8 = 4;
+const BREAK_OFF: u8 = 6;
+const DTR_REQUEST: u8 = 7;
+const DTR_ON: u8 = 8;
+const DTR_OFF: u8 = 9;
+const RTS_REQUEST: u8 = 10;
+const RTS_ON: u8 = 11;
+const RTS_OFF: u8 = 12;
+const INBOUND_FLOW_CONTROL_REQUEST: u8 = 13;
+const INBOUND_FLOW_CONTROL_NONE: u8 = 14;
+const INBOUND_FLOW_CONTROL_HARDWARE: u8 = 16;
+const DCD_FLOW_CONTROL: u8 = 17;
+const DSR_FLOW_CONTROL: u8 = 19;
+
+const SIGNATURE_TEXT: &str = concat!("TestBox simulator ", env!("CARGO_PKG_VERSION"));
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum State {
+    Data,
+    /// After a carriage return, outside of binary mode
+    Cr,
+    Iac,
+    /// After WILL, WONT, DO or DONT
+    Option(u8),
+    Sub,
+    SubIac,
+}
+
+pub struct Telnet {
+    state: State,
+    subnegotiation: Vec<u8>,
+    /// Options enabled on the server side, and on the client side
+    local: [bool; 256],
+    remote: [bool; 256],
+    line: LineSettings,
+    dtr: bool,
+    rts: bool,
+    replies: Vec<u8>,
+}
+
+impl Default for Telnet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Telnet {
+    pub fn new() -> Self {
+        Self {
+            state: State::Data,
+            subnegotiation: Vec::new(),
+            local: [false; 256],
+            remote: [false; 256],
+            line: LineSettings::BOARD,
+            dtr: true,
+            rts: true,
+            replies: Vec::new(),
+        }
+    }
+
+    /// Decodes bytes from the client into what the device receives. Replies
+    /// to the client pile up until `replies` is called.
+    pub fn receive(&mut self, bytes: &[u8]) -> Vec<Input> {
+        let mut inputs = Vec::new();
+        let mut data = Vec::new();
+
+        for &c in bytes {
+            self.state = match (self.state, c) {
+                (State::Data | State::Cr, IAC) => State::Iac,
+                // Outside of binary mode, the client sends CR as CR NUL
+                (State::Cr, 0) => State::Data,
+                (State::Data | State::Cr, c) => {
+                    data.push(c);
+                    if c == b'\r' && !self.remote[BINARY as usize] { State::Cr } else { State::Data }
+                }
+
+                (State::Iac, IAC) => {
+                    data.push(IAC);
+                    State::Data
+                }
+                (State::Iac, WILL | WONT | DO | DONT) => State::Option(c),
+                (State::Iac, SB) => {
+                    self.subnegotiation.clear();
+                    State::Sub
+                }
+                // Other commands mean nothing for a serial port
+                (State::Iac, _) => State::Data,
+
+                (State::Option(command), option) => {
+                    self.negotiate(command, option);
+                    State::Data
+                }
+
+                (State::Sub, IAC) => State::SubIac,
+                (State::Sub, c) => {
+                    self.subnegotiation.push(c);
+                    State::Sub
+                }
+                (State::SubIac, IAC) => {
+                    self.subnegotiation.push(IAC);
+                    State::Sub
+                }
+                (State::SubIac, SE) => {
+                    if let Some(input) = self.subnegotiate() {
+                        if !data.is_empty() {
+                            inputs.push(Input::Bytes(std::mem::take(&mut data)));
+                        }
+                        inputs.push(input);
+                    }
+                    State::Data
+                }
+                // Not a valid subnegotiation, give up on it
+                (State::SubIac, _) => State::Data,
+            };
+        }
+
+        if !data.is_empty() {
+            inputs.push(Input::Bytes(data));
+        }
+        inputs
+    }
+
+    /// Replies to the client since the last call
+    pub fn replies(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.replies)
+    }
+
+    /// Bytes from the device as sent to the client
+    pub fn escape(bytes: &[u8]) -> Vec<u8> {
+        let mut escaped = Vec::with_capacity(bytes.len());
+        for &c in bytes {
+            escaped.push(c);
+            if c == IAC {
+                escaped.push(IAC);
+            }
+        }
+        escaped
+    }
+
+    fn negotiate(&mut self, command: u8, option: u8) {
+        let accepted = match command {
+            DO | DONT => matches!(option, BINARY | SUPPRESS_GO_AHEAD),
+            _ => matches!(option, BINARY | SUPPRESS_GO_AHEAD | COM_PORT_OPTION),
+        };
+
+        // Only answer requests that change something, so the two sides never loop
+        let (enabled, yes, no) = match command {
+            DO | DONT => (&mut self.local[option as usize], WILL, WONT),
+            _ => (&mut self.remote[option as usize], DO, DONT),
+        };
+        let wanted = matches!(command, DO | WILL);
+
+        let reply = match (wanted, accepted, *enabled) {
+            (true, true, false) => {
+                *enabled = true;
+                yes
+            }
+            (true, false, _) => no,
+            (false, _, true) => {
+                *enabled = false;
+                no
+            }
+            _ => return,
+        };
+
+        debug!("Telnet option {}: got {}, answering {}", option, command, reply);
+        self.replies.extend([IAC, reply, option]);
+    }
+
+    fn subnegotiate(&mut self) -> Option<Input> {
+        let (&option, payload) = self.subnegotiation.split_first()?;
+        let (&command, value) = payload.split_first()?;
+        if option != COM_PORT_OPTION {
+            return None;
+        }
+        let byte = value.first().copied().unwrap_or(0);
+
+        let (reply, input): (Vec<u8>, _) = match command {
+            SIGNATURE if value.is_empty() => (SIGNATURE_TEXT.as_bytes().to_vec(), None),
+            SIGNATURE => {
+                info!("Client signature: {}", String::from_utf8_lossy(value));
+                return None;
+            }
+            SET_BAUDRATE => {
+                let baud_rate = u32::from_be_bytes(value.try_into().ok()?);
+                if baud_rate != 0 {
+                    self.line.baud_rate = baud_rate;
+                }
+                (self.line.baud_rate.to_be_bytes().to_vec(), Some(Input::Line(self.line)))
+            }
+            SET_DATASIZE => {
+                if (5..=8).contains(&byte) {
+                    self.line.data_bits = byte;
+                }
+                (vec![self.line.data_bits], Some(Input::Line(self.line)))
+            }
+            SET_PARITY => {
+                match byte {
+                    1 => self.line.parity = Parity::None,
+                    2 => self.line.parity = Parity::Odd,
+                    3 => self.line.parity = Parity::Even,
+                    4 => self.line.parity = Parity::Mark,
+                    5 => self.line.parity = Parity::Space,
+                    _ => {}
+                }
+                let parity = match self.line.parity {
+                    Parity::None => 1,
+                    Parity::Odd => 2,
+                    Parity::Even => 3,
+                    Parity::Mark => 4,
+                    Parity::Space => 5,
+                };
+                (vec![parity], Some(Input::Line(self.line)))
+            }
+            SET_STOPSIZE => {
+                match byte {
+                    1 => self.line.stop_bits = StopBits::One,
+                    2 => self.line.stop_bits = StopBits::Two,
+                    3 => self.line.stop_bits = StopBits::OnePointFive,
+                    _ => {}
+                }
+                let stop_bits = match self.line.stop_bits {
+                    StopBits::One => 1,
+                    StopBits::Two => 2,
+                    StopBits::OnePointFive => 3,
+                };
+                (vec![stop_bits], Some(Input::Line(self.line)))
+            }
+            SET_CONTROL => {
+                let state = match byte {
+                    DTR_ON | DTR_OFF => {
+                        self.dtr = byte == DTR_ON;
+                        byte
+                    }
+                    RTS_ON | RTS_OFF => {
+                        self.rts = byte == RTS_ON;
+                        byte
+                    }
+                    DTR_REQUEST => if self.dtr { DTR_ON } else { DTR_OFF },
+                    RTS_REQUEST => if self.rts { RTS_ON } else { RTS_OFF },
+                    BREAK_REQUEST => BREAK_OFF,
+                    // The board has no flow control, and with `--rx-fifo` bytes arriving
+                    // while its FIFO is full are dropped. Other settings are refused by
+                    // answering the one in effect.
+                    FLOW_CONTROL_REQUEST..=FLOW_CONTROL_HARDWARE | DCD_FLOW_CONTROL..=DSR_FLOW_CONTROL => {
+                        if byte != FLOW_CONTROL_REQUEST && byte != FLOW_CONTROL_NONE {
+                            info!("Refusing flow control {}, the board has none", byte);
+                        }
+                        FLOW_CONTROL_NONE
+                    }
+                    INBOUND_FLOW_CONTROL_REQUEST..=INBOUND_FLOW_CONTROL_HARDWARE => {
+                        if byte != INBOUND_FLOW_CONTROL_REQUEST && byte != INBOUND_FLOW_CONTROL_NONE {
+                            info!("Refusing inbound flow control {}, the board has none", byte);
+                        }
+                        INBOUND_FLOW_CONTROL_NONE
+                    }
+                    c => c,
+                };
+                let input = matches!(byte, DTR_ON | DTR_OFF | RTS_ON | RTS_OFF)
+                    .then_some(Input::Modem { dtr: self.dtr, rts: self.rts });
+                (vec![state], input)
+            }
+            // No line errors and no modem status lines to report
+            NOTIFY_LINESTATE | NOTIFY_MODEMSTATE => (vec![0], None),
+            SET_LINESTATE_MASK | SET_MODEMSTATE_MASK | PURGE_DATA => (vec![byte], None),
+            _ => return None,
+        };
+
+        self.replies.extend([IAC, SB, COM_PORT_OPTION, command + SERVER_OFFSET]);
+        self.replies.extend(Self::escape(&reply));
+        self.replies.extend([IAC, SE]);
+        input
+    }
+}
@@ -0,0 +1,272 @@
+//! Scenarios: what the simulated world does over time, written down in a file
+//! so environmental test cases don't need code. Each line is a step, carried
+//! out when the device clock reaches its time:
+//!
+//! ```text
+//! # Heat wave, the sensor drops out and the board reboots
+//! 0s    temperature 22
+//! 0s    temperature 35 over 60s
+//! 30s   fault TIMEOUT for 3 reads
+//! 45s   reboot
+//! ```
+//!
+//! Times are counted from when the scenario starts playing, in `ms`, `s` or
+//! `m`, seconds without a unit. Steps are in time order. The actions are:
+//!
+//! * `temperature <°C> [over <TIME>]`, `humidity <%> [over <TIME>]`: what the
+//!   sensor senses, changing linearly from the current value when `over` is
+//!   given. Scenarios start at 20 °C and 50 %.
+//! * `fault TIMEOUT|CHECKSUM [for <N> reads]`: sensor reads fail, until
+//!   `fault none` or for the given number of reads.
+//! * `reboot`: the board resets, like when DTR drops.
+//!
+//! Like the rest of the world, the sensor is only read at the firmware's pace,
+//! and steps are carried out at the next tick of the device.
+
+use std::{fmt, str::FromStr, time::Duration};
+
+use log::info;
+
+use crate::testbox::{SensorFault, TestBox};
+
+/// Temperature and humidity before the scenario says otherwise, as the sensor reads at boot
+const START: (f64, f64) = (20.0, 50.0);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    /// Target temperature and how long it takes to get there
+    Temperature(f64, Duration),
+    /// Target humidity and how long it takes to get there
+    Humidity(f64, Duration),
+    /// Sensor fault, or `None` for successful reads, and for how many reads
+    Fault(Option<SensorFault>, Option<u32>),
+    Reboot,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Step {
+    /// Time since the scenario started
+    pub at: Duration,
+    pub action: Action,
+}
+
+fn time(s: &str) -> Result<Duration, String> {
+    let (number, unit) = s.split_at(s.find(|c: char| c.is_ascii_alphabetic()).unwrap_or(s.len()));
+    let scale = match unit {
+        "ms" => 0.001,
+        "s" | "" => 1.0,
+        "m" => 60.0,
+        _ => return Err(format!("invalid time '{}', expected e.g. '500ms', '30s' or '2m'", s)),
+    };
+    number.parse::<f64>().ok()
+        .and_then(|n| Duration::try_from_secs_f64(n*scale).ok())
+        .ok_or_else(|| format!("invalid time '{}'", s))
+}
+
+fn show_time(f: &mut fmt::Formatter<'_>, d: Duration) -> fmt::Result {
+    write!(f, "{}s", d.as_secs_f64())
+}
+
+impl FromStr for Step {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let tokens: Vec<&str> = s.split_whitespace().collect();
+        let Some((at, action)) = tokens.split_first() else {
+            return Err("expected '<TIME> <ACTION>'".into());
+        };
+        let at = time(at)?;
+
+        let value = |v: &str, what: &str| v.parse::<f64>().ok()
+            .filter(|v| v.is_finite())
+            .ok_or_else(|| format!("invalid {} '{}'", what, v));
+
+        let action = match action {
+            ["temperature", t, rest @ ..] | ["humidity", t, rest @ ..] => {
+                let over = match rest {
+                    [] => Duration::ZERO,
+                    ["over", d] => time(d)?,
+                    _ => return Err(format!("expected '{} <VALUE> [over <TIME>]'", action[0])),
+                };
+                if action[0] == "temperature" {
+                    Action::Temperature(value(t, "temperature")?, over)
+                } else {
+                    let h = value(t, "humidity")?;
+                    if !(0.0..=100.0).contains(&h) {
+                        return Err(format!("humidity {}% is not between 0% and 100%", h));
+                    }
+                    Action::Humidity(h, over)
+                }
+            }
+            ["fault", "none"] => Action::Fault(None, None),
+            ["fault", fault, rest @ ..] => {
+                let reads = match rest {
+                    [] => None,
+                    ["for", n, "reads" | "read"] => match n.parse() {
+                        Ok(n) if n > 0 => Some(n),
+                        _ => return Err(format!("invalid number of reads '{}'", n)),
+                    },
+                    _ => return Err("expected 'fault TIMEOUT|CHECKSUM [for <N> reads]' or 'fault none'".into()),
+                };
+                Action::Fault(Some(fault.parse()?), reads)
+            }
+            ["reboot"] => Action::Reboot,
+            _ => return Err(format!(
+                "invalid action '{}', expected 'temperature', 'humidity', 'fault' or 'reboot'", action.join(" ")
+            )),
+        };
+        Ok(Self { at, action })
+    }
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        show_time(f, self.at)?;
+        let (noun, value, over) = match self.action {
+            Action::Temperature(t, over) => ("temperature", t, over),
+            Action::Humidity(h, over) => ("humidity", h, over),
+            Action::Fault(fault, reads) => {
+                write!(f, " fault {}", fault.map_or("none", <&str>::from))?;
+                match reads {
+                    Some(1) => write!(f, " for 1 read")?,
+                    Some(reads) => write!(f, " for {} reads", reads)?,
+                    None => {}
+                }
+                return Ok(());
+            }
+            Action::Reboot => return write!(f, " reboot"),
+        };
+        write!(f, " {} {}", noun, value)?;
+        if !over.is_zero() {
+            write!(f, " over ")?;
+            show_time(f, over)?;
+        }
+        Ok(())
+    }
+}
+
+/// The steps of a scenario file
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Scenario {
+    pub steps: Vec<Step>,
+}
+
+impl FromStr for Scenario {
+    type Err = String;
+
+    /// Parses a scenario file. Errors name the line they are on.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut steps: Vec<Step> = Vec::new();
+        for (n, line) in s.lines().enumerate() {
+            let line = line.split_once('#').map_or(line, |(line, _)| line).trim();
+            if line.is_empty() {
+                continue;
+            }
+            let step: Step = line.parse().map_err(|e| format!("line {}: {}", n + 1, e))?;
+            if steps.last().is_some_and(|last| last.at > step.at) {
+                return Err(format!("line {}: steps must be in time order", n + 1));
+            }
+            steps.push(step);
+        }
+        Ok(Self { steps })
+    }
+}
+
+/// A value changing linearly over time
+#[derive(Debug, Clone, Copy)]
+struct Ramp {
+    from: f64,
+    to: f64,
+    start: Duration,
+    duration: Duration,
+}
+
+impl Ramp {
+    fn fixed(value: f64) -> Self {
+        Self { from: value, to: value, start: Duration::ZERO, duration: Duration::ZERO }
+    }
+
+    fn value(&self, now: Duration) -> f64 {
+        let elapsed = now.saturating_sub(self.start);
+        if elapsed >= self.duration {
+            self.to
+        } else {
+            self.from + (self.to - self.from)*elapsed.as_secs_f64()/self.duration.as_secs_f64()
+        }
+    }
+
+    /// Heads for `to` from wherever the value is at `now`
+    fn towards(&self, to: f64, now: Duration, duration: Duration) -> Self {
+        Self { from: self.value(now), to, start: now, duration }
+    }
+}
+
+/// A scenario being played against a test box
+pub struct Playback {
+    scenario: Scenario,
+    /// Index of the next step
+    next: usize,
+    /// Device time the scenario started at
+    start: Duration,
+    temperature: Ramp,
+    humidity: Ramp,
+    fault: Option<SensorFault>,
+    /// Reads left until the fault clears, if it is limited
+    fault_reads: Option<u32>,
+}
+
+impl Playback {
+    /// Starts playing a scenario at `start`, as time since boot
+    pub fn new(scenario: Scenario, start: Duration) -> Self {
+        Self {
+            scenario,
+            next: 0,
+            start,
+            temperature: Ramp::fixed(START.0),
+            humidity: Ramp::fixed(START.1),
+            fault: None,
+            fault_reads: None,
+        }
+    }
+
+    /// Carries out the steps due by `now`, and passes what the world is like
+    /// at `now` on to the sensor. Returns whether the board is to reboot.
+    pub fn advance(&mut self, tbox: &mut TestBox, now: Duration) -> bool {
+        let start = self.start;
+        let mut reboot = false;
+
+        while let Some(step) = self.scenario.steps.get(self.next).filter(|step| start + step.at <= now) {
+            info!("Scenario step: {}", step);
+            let at = start + step.at;
+            match step.action {
+                Action::Temperature(t, over) => self.temperature = self.temperature.towards(t, at, over),
+                Action::Humidity(h, over) => self.humidity = self.humidity.towards(h, at, over),
+                Action::Fault(fault, reads) => {
+                    self.fault = fault;
+                    self.fault_reads = reads;
+                }
+                Action::Reboot => reboot = true,
+            }
+            self.next += 1;
+        }
+
+        tbox.sense(Some((self.temperature.value(now), self.humidity.value(now))), self.fault);
+        reboot
+    }
+
+    /// The sensor was read, counts the reads of a limited fault
+    pub fn sensor_read(&mut self) {
+        if let Some(reads) = self.fault_reads.as_mut() {
+            *reads -= 1;
+            if *reads == 0 {
+                self.fault = None;
+                self.fault_reads = None;
+            }
+        }
+    }
+
+    /// Whether every step was carried out. The world then stays as the last steps left it.
+    pub fn finished(&self) -> bool {
+        self.next == self.scenario.steps.len()
+    }
+}
@@ -1,49 +1,496 @@
-use std::{net::SocketAddr, error::Error};
+use std::{collections::VecDeque, convert::TryFrom, future, io, sync::{Arc, Mutex}, time::Duration};
 
-use log::info;
-use tokio::{net::TcpListener, io::AsyncReadExt, io::AsyncWriteExt, sync::mpsc, select};
+use log::{debug, info, warn};
+use tokio::{io::AsyncReadExt, io::AsyncWriteExt, sync::{mpsc, oneshot, watch}, select, task::JoinSet, time::{self, Instant}};
 
-pub(crate) async fn server<const LEN: usize>(
-    port: u16,
-    incoming: mpsc::Sender<Option<Vec<u8>>>,
-    mut outgoing: mpsc::Receiver<Vec<u8>>
-) -> Result<(), Box<dyn Error>> {
-    let listener = TcpListener::bind(SocketAddr::from(([0, 0, 0, 0], port))).await?;
-    info!("Listening on {}", listener.local_addr()?);
+use crate::device::Input;
+use crate::lease::{ClientId, Leases};
+use crate::listener::{Listener, Stream};
+use crate::parser::{Request, Response, ResponseError, Tagged};
+use crate::rfc2217::Telnet;
+use crate::supervisor::{RestartBudget, TaskResult};
+
+/// Pause before rebinding the listener after a failure
+const RESTART_DELAY: Duration = Duration::from_millis(500);
+
+/// Limits on how long a client may hold the connection without making progress
+#[derive(Clone, Copy, Debug)]
+pub struct Timeouts {
+    /// Closes the connection when the client sent nothing for this long
+    pub idle: Option<Duration>,
+    /// How long a write may block on a client that stopped reading, and how
+    /// long a client that closed its side gets to receive the last responses
+    pub half_open: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self { idle: None, half_open: Duration::from_secs(5) }
+    }
+}
+
+/// What clients speak on top of the connection
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Protocol {
+    /// Bytes are passed on as they are
+    Raw,
+    /// Telnet with the RFC 2217 COM port control option, for serial tools
+    Rfc2217,
+}
+
+/// Serves clients on `listener` until shutdown. A client failing only closes
+/// its connection. After a failure of the listener, its address is bound
+/// again, as long as failures are not too frequent. Whatever the transport,
+/// the bytes of a connection are handled the same way.
+///
+/// Responses to a client that is gone are dropped, they never reach the next
+/// client. The device ends the responses to each client with an empty buffer.
+/// It is told which client the bytes that follow come from, to hold leases.
+/// As clients are served one at a time, leases only guard the board between
+/// connections here, `shared` serves clients at once.
+pub async fn server<const LEN: usize>(
+    listener: Listener,
+    protocol: Protocol,
+    timeouts: Timeouts,
+    incoming: mpsc::Sender<Input>,
+    mut outgoing: mpsc::Receiver<Vec<u8>>,
+    mut shutdown: watch::Receiver<bool>
+) -> TaskResult {
+    let addr = listener.address().clone();
+    let mut listener = Some(listener);
+    let mut budget = RestartBudget::new(5, Duration::from_secs(60));
+
+    let mut server = Server::<LEN> { protocol, timeouts, incoming, to_device: VecDeque::new(), stale: false, clients: 0 };
 
     loop {
-        let (mut stream, remote_addr) = listener.accept().await?;
-        info!("New connection from {}", remote_addr);
+        let result = match listener.take() {
+            Some(listener) => server.serve(&listener, &mut outgoing, &mut shutdown).await,
+            None => match addr.bind().await {
+                Ok(listener) => server.serve(&listener, &mut outgoing, &mut shutdown).await,
+                Err(e) => Err(e.into()),
+            }
+        };
+
+        let e = match result {
+            Ok(()) => return Ok(()),
+            Err(e) => e,
+        };
+
+        if !budget.allow() {
+            return Err(format!("giving up after repeated failures, last one: {}", e).into());
+        }
+        warn!("Server failed: {}, restarting in {:?}", e, RESTART_DELAY);
+
+        select! {
+            _ = time::sleep(RESTART_DELAY) => {}
+            _ = shutdown.changed() => return Ok(()),
+        }
+    }
+}
+
+/// How a connection ended
+enum End {
+    /// The client was done and got all its responses
+    Done,
+    Failed(io::Error),
+    /// The device stopped sending responses
+    Stopped,
+}
+
+struct Server<const LEN: usize> {
+    protocol: Protocol,
+    timeouts: Timeouts,
+    incoming: mpsc::Sender<Input>,
+    /// Bytes not passed on to the device yet. The device is handed bytes
+    /// while responses keep being taken from it, so neither side blocks the other.
+    to_device: VecDeque<Input>,
+    /// Responses to a client that is gone are still on their way
+    stale: bool,
+    /// Connections accepted so far, the last one's id
+    clients: ClientId,
+}
+
+impl<const LEN: usize> Server<LEN> {
+    /// Accepts one client at a time until shutdown, or until the device stops
+    async fn serve(
+        &mut self,
+        listener: &Listener,
+        outgoing: &mut mpsc::Receiver<Vec<u8>>,
+        shutdown: &mut watch::Receiver<bool>
+    ) -> TaskResult {
+        info!("Listening on {}", listener.address());
+
+        loop {
+            if *shutdown.borrow() {
+                return Ok(());
+            }
+
+            // Reserving borrows the sender for as long as the select
+            let incoming = self.incoming.clone();
+            let (stream, remote_addr) = select! {
+                accepted = listener.accept() => accepted?,
+
+                // Keep the device from blocking while nobody is connected
+                response = outgoing.recv() => {
+                    match response {
+                        Some(r) => self.discard(r),
+                        None => return Ok(()),
+                    }
+                    continue;
+                }
+
+                permit = incoming.reserve(), if !self.to_device.is_empty() => {
+                    self.deliver(permit, shutdown)?;
+                    continue;
+                }
+
+                _ = shutdown.changed() => return Ok(()),
+            };
+            self.clients += 1;
+            info!("New connection from {}, client {}", remote_addr, self.clients);
+            self.to_device.push_back(Input::Connected(self.clients));
+
+            let mut connection = Connection {
+                stream,
+                telnet: (self.protocol == Protocol::Rfc2217).then(Telnet::new),
+                last_read: Instant::now(),
+                reading: !*shutdown.borrow(),
+                closing: None,
+            };
+
+            match self.connection(&mut connection, outgoing, shutdown).await? {
+                End::Done => info!("Closing the connection to {}", remote_addr),
+                End::Stopped => {
+                    info!("Outgoing channel is closed, closing the connection");
+                    let _ = connection.stream.shutdown().await;
+                    return Ok(());
+                }
+                End::Failed(e) => {
+                    warn!("Connection to {} failed: {}", remote_addr, e);
+                    if connection.closing.is_none() {
+                        // The client didn't get to say it was done
+                        self.to_device.push_back(Input::Disconnected);
+                    }
+                    self.stale = true;
+                }
+            }
+        }
+    }
 
+    /// Passes bytes between the client and the device until the client is done
+    async fn connection(
+        &mut self,
+        connection: &mut Connection,
+        outgoing: &mut mpsc::Receiver<Vec<u8>>,
+        shutdown: &mut watch::Receiver<bool>
+    ) -> Result<End, Box<dyn std::error::Error + Send + Sync>> {
         let mut buffer = [0u8; LEN];
 
-        while select! {
-            response = outgoing.recv() => {
-                match response {
-                    Some(r) => {
-                        stream.write_all(&r).await?;
-                        true
-                    },
-                    None => {
-                        info!("Outgoing channel is closed. Exiting");
-                        false
+        loop {
+            let idle = connection.idle_deadline(&self.timeouts);
+            let closing = connection.closing;
+            let incoming = self.incoming.clone();
+
+            select! {
+                response = outgoing.recv() => {
+                    match response {
+                        None => return Ok(End::Stopped),
+                        Some(r) if self.stale => self.discard(r),
+                        // The device answered everything the client sent
+                        Some(r) if r.is_empty() => if connection.closing.is_some() {
+                            return Ok(End::Done);
+                        },
+                        Some(r) => {
+                            let r = if connection.telnet.is_some() { Telnet::escape(&r) } else { r };
+                            if let Err(e) = connection.write(&r, &self.timeouts).await {
+                                return Ok(End::Failed(e));
+                            }
+                        }
+                    }
+                }
+
+                permit = incoming.reserve(), if !self.to_device.is_empty() => {
+                    self.deliver(permit, shutdown)?;
+                }
+
+                // Only read once the device took the previous bytes
+                request = connection.stream.read(&mut buffer), if connection.reading && self.to_device.is_empty() => {
+                    match request {
+                        Ok(0) => {
+                            info!("Client is done sending, waiting for the remaining responses");
+                            connection.reading = false;
+                            connection.closing = Some(Instant::now() + self.timeouts.half_open);
+                            self.to_device.push_back(Input::Disconnected);
+                        }
+                        Ok(n) => {
+                            connection.last_read = Instant::now();
+                            match connection.telnet.as_mut() {
+                                Some(telnet) => {
+                                    self.to_device.extend(telnet.receive(&buffer[..n]));
+                                    let replies = telnet.replies();
+                                    if let Err(e) = connection.write(&replies, &self.timeouts).await {
+                                        return Ok(End::Failed(e));
+                                    }
+                                }
+                                None => self.to_device.push_back(Input::Bytes(buffer[..n].to_vec())),
+                            }
+                        }
+                        Err(e) => return Ok(End::Failed(e)),
                     }
                 }
+
+                _ = sleep_until(idle) => {
+                    return Ok(End::Failed(io::Error::new(io::ErrorKind::TimedOut, "client sent nothing for too long")));
+                }
+
+                _ = sleep_until(closing) => {
+                    return Ok(End::Failed(io::Error::new(io::ErrorKind::TimedOut, "remaining responses not sent in time")));
+                }
+
+                _ = shutdown.changed(), if connection.reading => {
+                    info!("Shutting down, waiting for the remaining responses");
+                    connection.reading = false;
+                }
             }
+        }
+    }
+
+    /// Passes the oldest bytes on to the device, which stops receiving on shutdown
+    fn deliver(
+        &mut self,
+        permit: Result<mpsc::Permit<'_, Input>, mpsc::error::SendError<()>>,
+        shutdown: &watch::Receiver<bool>
+    ) -> TaskResult {
+        match permit {
+            Ok(permit) => if let Some(input) = self.to_device.pop_front() {
+                permit.send(input);
+            },
+            Err(_) if *shutdown.borrow() => self.to_device.clear(),
+            Err(e) => return Err(e.into()),
+        }
+        Ok(())
+    }
+
+    /// Drops a response to a client that is gone
+    fn discard(&mut self, response: Vec<u8>) {
+        if response.is_empty() {
+            self.stale = false;
+        } else {
+            debug!("Dropping response to a client that is gone: {:?}", String::from_utf8_lossy(&response));
+        }
+    }
+}
 
-            request = stream.read(&mut buffer) => {
-                match request? {
+struct Connection {
+    stream: Box<dyn Stream>,
+    telnet: Option<Telnet>,
+    last_read: Instant,
+    /// Whether requests are still read from the client
+    reading: bool,
+    /// Deadline for the last responses, once the client is done sending
+    closing: Option<Instant>,
+}
+
+impl Connection {
+    /// Writes to the client, which is considered gone if it stopped reading
+    async fn write(&mut self, bytes: &[u8], timeouts: &Timeouts) -> io::Result<()> {
+        match time::timeout(timeouts.half_open, self.stream.write_all(bytes)).await {
+            Ok(result) => result,
+            Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "client stopped reading")),
+        }
+    }
+
+    fn idle_deadline(&self, timeouts: &Timeouts) -> Option<Instant> {
+        timeouts.idle.filter(|_| self.reading).map(|idle| self.last_read + idle)
+    }
+}
+
+/// A request line of a client, and where its response goes
+pub struct Exchange {
+    pub request: Vec<u8>,
+    pub response: oneshot::Sender<Vec<u8>>,
+}
+
+/// Serves any number of clients at once until shutdown, for a board that is
+/// shared rather than simulated. Requests are passed on one line at a time,
+/// each client waiting for the response to its line before sending the next,
+/// so clients take turns and responses never mix. Leases are held here, as
+/// the board knows nothing of the clients: `LEASE` requests, and those of
+/// clients the leases leave out, are answered without reaching the board.
+pub async fn shared<const LEN: usize>(
+    listener: Listener,
+    timeouts: Timeouts,
+    exchanges: mpsc::Sender<Exchange>,
+    mut shutdown: watch::Receiver<bool>
+) -> TaskResult {
+    info!("Listening on {}", listener.address());
+
+    let mut clients = JoinSet::new();
+    let mut budget = RestartBudget::new(5, Duration::from_secs(60));
+    let lessor = Lessor { leases: Arc::new(Mutex::new(Leases::new(rand::random()))), start: Instant::now() };
+    let mut client_id: ClientId = 0;
+
+    loop {
+        select! {
+            accepted = listener.accept() => {
+                let (stream, remote_addr) = match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) if budget.allow() => {
+                        warn!("Failed to accept a client: {}", e);
+                        time::sleep(RESTART_DELAY).await;
+                        continue;
+                    }
+                    Err(e) => return Err(format!("giving up after repeated failures, last one: {}", e).into()),
+                };
+                client_id += 1;
+                info!("New connection from {}, client {}", remote_addr, client_id);
+
+                let exchanges = exchanges.clone();
+                let shutdown = shutdown.clone();
+                let lessor = lessor.clone();
+                let id = client_id;
+                clients.spawn(async move {
+                    match client::<LEN>(stream, timeouts, exchanges, lessor.clone(), id, shutdown).await {
+                        Ok(()) => info!("Closing the connection to {}", remote_addr),
+                        Err(e) => warn!("Connection to {} failed: {}", remote_addr, e),
+                    }
+                    lessor.disconnected(id);
+                });
+            }
+
+            Some(_) = clients.join_next() => {}
+
+            _ = shutdown.changed() => {
+                info!("Shutting down, waiting for the remaining responses");
+                while clients.join_next().await.is_some() {}
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Leases of the clients of a shared board
+#[derive(Clone)]
+struct Lessor {
+    leases: Arc<Mutex<Leases>>,
+    /// Lease expiry is counted from there
+    start: Instant,
+}
+
+impl Lessor {
+    /// Answer to a request line of `client`, if it isn't for the board
+    fn answer(&self, client: ClientId, line: &[u8]) -> Option<Vec<u8>> {
+        let now = self.start.elapsed();
+        let mut leases = self.leases.lock().unwrap_or_else(|e| e.into_inner());
+
+        let Ok(request) = Tagged::<Request>::try_from(line) else {
+            // The firmware makes sense of more than the parser, e.g. `SET  SERVO 90`
+            let refused = !leases.allows_any(client, now);
+            return refused.then(|| Tagged { tag: None, inner: Response::Error(ResponseError::Leased) }.into());
+        };
+
+        let response = match request.inner {
+            Request::Lease(lease) => leases.handle(client, lease, now),
+            r if !leases.allows(client, &r, now) => Response::Error(ResponseError::Leased),
+            _ => return None,
+        };
+        Some(Tagged { tag: request.tag, inner: response }.into())
+    }
+
+    fn disconnected(&self, client: ClientId) {
+        self.leases.lock().unwrap_or_else(|e| e.into_inner()).disconnected(client);
+    }
+}
+
+/// Passes the lines of one client on until it is done, or until shutdown
+async fn client<const LEN: usize>(
+    mut stream: Box<dyn Stream>,
+    timeouts: Timeouts,
+    exchanges: mpsc::Sender<Exchange>,
+    lessor: Lessor,
+    id: ClientId,
+    mut shutdown: watch::Receiver<bool>
+) -> io::Result<()> {
+    let mut buffer = [0u8; LEN];
+    // Like the board, lines are cut once the line buffer is full
+    let mut line = Vec::with_capacity(LEN);
+    let mut lines = VecDeque::new();
+    let mut response: Option<oneshot::Receiver<Vec<u8>>> = None;
+
+    let mut reading = !*shutdown.borrow();
+    let mut last_read = Instant::now();
+    let mut closing = None;
+
+    while reading || !lines.is_empty() || response.is_some() {
+        let idle = timeouts.idle.filter(|_| reading).map(|idle| last_read + idle);
+        let exchanges = exchanges.clone();
+
+        select! {
+            received = stream.read(&mut buffer), if reading => {
+                match received? {
                     0 => {
-                        info!("Got 0 bytes, closing the connection");
-                        incoming.send(None).await?;
-                        false
+                        reading = false;
+                        closing = Some(Instant::now() + timeouts.half_open);
                     }
                     n => {
-                        incoming.send(Some(buffer[..n].to_vec())).await?;
-                        true
-                    },
+                        last_read = Instant::now();
+                        for &c in &buffer[..n] {
+                            line.push(c);
+                            if c == b'\n' || line.len() == LEN - 1 {
+                                lines.push_back(std::mem::replace(&mut line, Vec::with_capacity(LEN)));
+                            }
+                        }
+                    }
                 }
             }
-        } {}
+
+            permit = exchanges.reserve(), if response.is_none() && !lines.is_empty() => {
+                let permit = permit.map_err(io::Error::other)?;
+                let request = lines.pop_front().unwrap_or_default();
+                match lessor.answer(id, &request) {
+                    Some(answer) => write(&mut stream, &answer, &timeouts).await?,
+                    None => {
+                        let (tx, rx) = oneshot::channel();
+                        permit.send(Exchange { request, response: tx });
+                        response = Some(rx);
+                    }
+                }
+            }
+
+            answer = async { response.as_mut()?.await.ok() }, if response.is_some() => {
+                response = None;
+                match answer {
+                    Some(answer) => write(&mut stream, &answer, &timeouts).await?,
+                    None => warn!("Request was not answered"),
+                }
+            }
+
+            _ = sleep_until(idle) => {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "client sent nothing for too long"));
+            }
+
+            _ = sleep_until(closing) => {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "remaining responses not sent in time"));
+            }
+
+            _ = shutdown.changed(), if reading => reading = false,
+        }
+    }
+
+    stream.shutdown().await
+}
+
+/// Writes to a client of a shared board, which is considered gone if it stopped reading
+async fn write(stream: &mut Box<dyn Stream>, bytes: &[u8], timeouts: &Timeouts) -> io::Result<()> {
+    match time::timeout(timeouts.half_open, stream.write_all(bytes)).await {
+        Ok(result) => result,
+        Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "client stopped reading")),
+    }
+}
+
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => time::sleep_until(deadline).await,
+        None => future::pending().await,
     }
 }
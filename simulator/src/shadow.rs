@@ -0,0 +1,207 @@
+//! Shadow simulator: every request a board answers is also handled by an
+//! in-process device, and the two responses are compared, to find where the
+//! simulator and the firmware disagree.
+//!
+//! Responses are compared decoded, so values can be allowed to differ: by
+//! default sensor readings may differ entirely, and the self test progress by
+//! a few percent since the two clocks don't run in step. Tolerances are given
+//! as `<NOUN> any` or `<NOUN> <DELTA>`.
+
+use std::{collections::HashMap, convert::TryFrom, fmt, str::FromStr, time::Duration};
+
+use crate::device::Device;
+use crate::parser::{split_response, Request, RequestNoun, Response, Tagged};
+
+/// How far the values of a noun may differ
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Tolerance {
+    noun: RequestNoun,
+    /// Largest difference allowed, `None` if values may differ entirely
+    delta: Option<f64>,
+}
+
+impl Tolerance {
+    /// What is expected to differ between a board and a simulator
+    pub const DEFAULTS: [Tolerance; 2] = [
+        Tolerance { noun: RequestNoun::TempAndHum, delta: None },
+        Tolerance { noun: RequestNoun::SelfTest, delta: Some(5.0) },
+    ];
+}
+
+impl FromStr for Tolerance {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((noun, delta)) = s.split_once(' ') else {
+            return Err("expected '<NOUN> any' or '<NOUN> <DELTA>'".into());
+        };
+        let noun = RequestNoun::try_from(noun.as_bytes()).map_err(|_| format!("invalid noun '{}'", noun))?;
+        let delta = match delta.trim() {
+            "any" => None,
+            delta => match delta.parse::<f64>() {
+                Ok(delta) if delta >= 0.0 => Some(delta),
+                _ => return Err(format!("invalid delta '{}'", delta)),
+            },
+        };
+        Ok(Self { noun, delta })
+    }
+}
+
+impl fmt::Display for Tolerance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ", <&str>::from(self.noun))?;
+        match self.delta {
+            Some(delta) => write!(f, "{}", delta),
+            None => write!(f, "any"),
+        }
+    }
+}
+
+/// What differs between the two responses
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Difference {
+    /// One side answered `OK` and the other `ERR`, or the response couldn't be read
+    Status,
+    /// Both answered `OK`, with different values
+    Value,
+    /// Both answered `ERR`, with different codes
+    ErrorCode,
+    /// Lines before the response, like `Failed to find noun`, differ
+    Diagnostics,
+}
+
+impl From<Difference> for &'static str {
+    fn from(d: Difference) -> Self {
+        match d {
+            Difference::Status => "status",
+            Difference::Value => "value",
+            Difference::ErrorCode => "error code",
+            Difference::Diagnostics => "diagnostics",
+        }
+    }
+}
+
+/// A request the board and the simulator answered differently
+#[derive(Debug, Clone)]
+pub struct Divergence {
+    pub difference: Difference,
+    pub request: String,
+    pub hardware: String,
+    pub simulator: String,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f, "Different {} for {:?}: hardware answered {:?}, simulator {:?}",
+            <&str>::from(self.difference), self.request, self.hardware, self.simulator
+        )
+    }
+}
+
+pub struct Shadow<const LEN: usize> {
+    device: Device<LEN>,
+    tolerances: HashMap<RequestNoun, Option<f64>>,
+    requests: u64,
+    divergences: u64,
+}
+
+impl<const LEN: usize> Shadow<LEN> {
+    /// Shadows with `device`, allowing the default tolerances, then `tolerances`
+    pub fn new(device: Device<LEN>, tolerances: &[Tolerance]) -> Self {
+        let tolerances = Tolerance::DEFAULTS.iter().chain(tolerances)
+            .map(|t| (t.noun, t.delta))
+            .collect();
+        Self { device, tolerances, requests: 0, divergences: 0 }
+    }
+
+    /// Handles the request line the board answered with `hardware`. `now` is
+    /// the time since the board was first sent a request.
+    pub fn compare(&mut self, line: &[u8], hardware: &[u8], now: Duration) -> Option<Divergence> {
+        self.device.advance(now);
+        self.device.receive(line, now);
+        let simulator: Vec<u8> = std::iter::from_fn(|| self.device.transmit()).flatten().collect();
+        self.requests += 1;
+
+        let (hardware_diagnostics, hardware_line) = split_response(hardware);
+        let (simulator_diagnostics, simulator_line) = split_response(&simulator);
+        let request = Tagged::<Request>::try_from(line).ok().map(|r| r.inner);
+        let decode = |line| request.as_ref().and_then(|r| Tagged::<Response>::decode_line(r, line));
+
+        let difference = match (decode(hardware_line), decode(simulator_line)) {
+            (Some(h), Some(s)) => match (h.inner, s.inner) {
+                (Response::Error(h), Response::Error(s)) => (h != s).then_some(Difference::ErrorCode),
+                (Response::Error(_), _) | (_, Response::Error(_)) => Some(Difference::Status),
+                (h, s) => (!self.same(request.as_ref(), &h, &s)).then_some(Difference::Value),
+            },
+            _ if hardware_line == simulator_line => None,
+            _ => match (error_code(hardware_line), error_code(simulator_line)) {
+                (Some(_), Some(_)) => Some(Difference::ErrorCode),
+                _ => Some(Difference::Status),
+            },
+        };
+        let difference = difference
+            .or((hardware_diagnostics != simulator_diagnostics).then_some(Difference::Diagnostics))?;
+
+        self.divergences += 1;
+        Some(Divergence {
+            difference,
+            request: text(line),
+            hardware: text(hardware),
+            simulator: text(&simulator),
+        })
+    }
+
+    /// Requests compared so far, and how many of them diverged
+    pub fn counts(&self) -> (u64, u64) {
+        (self.requests, self.divergences)
+    }
+
+    fn same(&self, request: Option<&Request>, hardware: &Response, simulator: &Response) -> bool {
+        let nouns = match request {
+            Some(Request::Get(noun) | Request::Set(noun, _)) => vec![*noun],
+            Some(Request::GetMany(nouns)) => nouns.iter().collect(),
+            Some(Request::SetMany(items)) => items.iter().map(|(noun, _)| noun).collect(),
+            _ => Vec::new(),
+        };
+
+        match (hardware, simulator) {
+            (Response::Many(h), Response::Many(s)) => {
+                h.len() == s.len() && nouns.len() == h.len()
+                    && nouns.iter().zip(h.iter().zip(s)).all(|(&noun, (h, s))| self.same_value(noun, h, s))
+            }
+            (h, s) if nouns.len() == 1 => self.same_value(nouns[0], h, s),
+            (h, s) => Vec::<u8>::from(h.clone()) == Vec::<u8>::from(s.clone()),
+        }
+    }
+
+    fn same_value(&self, noun: RequestNoun, hardware: &Response, simulator: &Response) -> bool {
+        let close = |h: f64, s: f64| match self.tolerances.get(&noun) {
+            Some(None) => true,
+            Some(Some(delta)) => (h - s).abs() <= *delta,
+            None => h == s,
+        };
+
+        match (hardware, simulator) {
+            (Response::Value(h), Response::Value(s)) => close(*h as f64, *s as f64),
+            (Response::TempAndHum(hs, ht, hh), Response::TempAndHum(ss, st, sh)) => {
+                hs == ss && close(*ht, *st) && close(*hh, *sh)
+            }
+            (Response::SelfTest(ha, hp), Response::SelfTest(sa, sp)) => ha == sa && close(*hp as f64, *sp as f64),
+            (h, s) => Vec::<u8>::from(h.clone()) == Vec::<u8>::from(s.clone()),
+        }
+    }
+}
+
+/// Code of an error line, tagged or not, even one the simulator doesn't know
+fn error_code(line: &[u8]) -> Option<&[u8]> {
+    let line = match line.strip_prefix(b"#") {
+        Some(tagged) => &tagged[tagged.iter().position(|&c| c == b' ')? + 1..],
+        None => line,
+    };
+    line.strip_prefix(b"ERR ")
+}
+
+fn text(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes).trim_end().into()
+}
@@ -0,0 +1,154 @@
+//! Lifecycle of the simulator tasks. A task failing, or stopping on its own,
+//! brings the whole simulator down with an error. SIGINT and SIGTERM shut it
+//! down in order: the device answers what it received, the connection is
+//! closed once the answers are out, and every task gets to finish.
+
+use std::{collections::VecDeque, error::Error, future::Future, process::ExitCode, time::Duration};
+
+use log::{error, info, warn};
+use tokio::{select, signal, sync::watch, task::{JoinError, JoinSet}, time::{self, Instant}};
+
+pub type TaskResult = Result<(), Box<dyn Error + Send + Sync>>;
+
+/// How long tasks get to finish once shutdown is requested
+const GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+/// Exit code after a second signal, like a shell would report for SIGINT
+const INTERRUPTED: u8 = 130;
+
+/// Limits how often a recoverable task is restarted: at most `max` times per `window`
+pub struct RestartBudget {
+    max: usize,
+    window: Duration,
+    restarts: VecDeque<Instant>,
+}
+
+impl RestartBudget {
+    pub fn new(max: usize, window: Duration) -> Self {
+        Self { max, window, restarts: VecDeque::new() }
+    }
+
+    /// Records a restart, returns false if there were too many already
+    pub fn allow(&mut self) -> bool {
+        let now = Instant::now();
+        while self.restarts.front().is_some_and(|&t| now.duration_since(t) > self.window) {
+            self.restarts.pop_front();
+        }
+
+        self.restarts.push_back(now);
+        self.restarts.len() <= self.max
+    }
+}
+
+/// Waits for SIGINT or SIGTERM, returns the name of the signal
+async fn signal() -> &'static str {
+    #[cfg(unix)]
+    {
+        use signal::unix::{signal, SignalKind};
+
+        let mut terminate = signal(SignalKind::terminate()).expect("Failed to listen to SIGTERM");
+        select! {
+            _ = signal::ctrl_c() => "SIGINT",
+            _ = terminate.recv() => "SIGTERM",
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = signal::ctrl_c().await;
+        "CTRL+C"
+    }
+}
+
+/// Logs how a task ended, returns whether it went well
+fn report(joined: Result<(&'static str, TaskResult), JoinError>) -> bool {
+    match joined {
+        Ok((name, Ok(()))) => {
+            info!("Task {} stopped", name);
+            true
+        }
+        Ok((name, Err(e))) => {
+            error!("Task {} failed: {}", name, e);
+            false
+        }
+        Err(e) => {
+            error!("Task panicked: {}", e);
+            false
+        }
+    }
+}
+
+pub struct Supervisor {
+    tasks: JoinSet<(&'static str, TaskResult)>,
+    shutdown: watch::Sender<bool>,
+}
+
+impl Default for Supervisor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Supervisor {
+    pub fn new() -> Self {
+        Self { tasks: JoinSet::new(), shutdown: watch::channel(false).0 }
+    }
+
+    /// Flag tasks watch to know when to shut down
+    pub fn shutdown(&self) -> watch::Receiver<bool> {
+        self.shutdown.subscribe()
+    }
+
+    pub fn spawn(&mut self, name: &'static str, task: impl Future<Output = TaskResult> + Send + 'static) {
+        self.tasks.spawn(async move { (name, task.await) });
+    }
+
+    /// Runs until a signal is received or a task stops, then shuts down.
+    /// Fails if any task failed or did not stop in time.
+    pub async fn run(mut self) -> ExitCode {
+        let mut ok = select! {
+            signal = signal() => {
+                info!("Received {}, shutting down", signal);
+                true
+            }
+
+            Some(joined) = self.tasks.join_next() => {
+                // Tasks only stop on their own when something went wrong
+                if report(joined) {
+                    error!("Task stopped unexpectedly, shutting down");
+                }
+                false
+            }
+        };
+
+        self.shutdown.send_replace(true);
+
+        let tasks = &mut self.tasks;
+        let stopped = async {
+            let mut ok = true;
+            while let Some(joined) = tasks.join_next().await {
+                ok &= report(joined);
+            }
+            ok
+        };
+
+        select! {
+            stopped = time::timeout(GRACE_PERIOD, stopped) => {
+                match stopped {
+                    Ok(stopped) => ok &= stopped,
+                    Err(_) => {
+                        error!("Tasks did not stop within {:?}", GRACE_PERIOD);
+                        ok = false;
+                    }
+                }
+            }
+
+            signal = signal() => {
+                warn!("Received {} again, exiting right away", signal);
+                return ExitCode::from(INTERRUPTED);
+            }
+        }
+
+        if ok { ExitCode::SUCCESS } else { ExitCode::FAILURE }
+    }
+}
@@ -1,11 +1,20 @@
-use std::{error::Error, time::Duration, iter::zip};
+//! The simulated test box. Synchronous and free of I/O: time is passed in by
+//! the caller, as the time elapsed since the board booted.
 
-use log::{info, debug};
-use tokio::{sync::mpsc, select, time};
+use std::{time::Duration, iter::zip, str::FromStr};
+
+use log::debug;
 use lazy_static::lazy_static;
-use rand::random;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::parser::{
+    Description, Request, RequestNoun, Response, ResponseError, ValueKind, PROTOCOL_VERSION, VERBS
+};
 
-use crate::parser::{Request, RequestNoun, Response, ResponseError};
+/// Minimum, maximum and default value of the LEDs
+const LED_RANGE: (i64, i64, i64) = (0, 1023, 0);
+/// Minimum, maximum and default angle of the servo
+const SERVO_RANGE: (i64, i64, i64) = (0, 180, 90);
 
 struct Positioner {
     min: i64,
@@ -14,13 +23,13 @@ struct Positioner {
     value: i64
 }
 
-#[derive(Debug)]
-pub(crate) struct PositionerState {
+#[derive(Debug, Clone)]
+pub struct PositionerState {
     pub value: i64,
 }
 
 impl Positioner {
-    fn new(min: i64, max: i64, def: i64) -> Self {
+    fn new((min, max, def): (i64, i64, i64)) -> Self {
         Positioner {
             min, max, def,
             value: def
@@ -51,27 +60,65 @@ impl Positioner {
 }
 
 
+/// Failure reported by the DHT22 library instead of a reading
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum SensorFault {
+    Timeout,
+    Checksum,
+}
+
+impl From<SensorFault> for &'static str {
+    fn from(f: SensorFault) -> Self {
+        match f {
+            SensorFault::Timeout => "TIMEOUT",
+            SensorFault::Checksum => "CHECKSUM",
+        }
+    }
+}
+
+impl FromStr for SensorFault {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "TIMEOUT" => Ok(Self::Timeout),
+            "CHECKSUM" => Ok(Self::Checksum),
+            _ => Err(format!("invalid sensor fault '{}', expected 'TIMEOUT' or 'CHECKSUM'", s))
+        }
+    }
+}
+
 struct Sensor {
     status: String,
     temperature: f64,
     humidity: f64,
-    last_update: time::Instant,
+    last_update: Duration,
+    rng: StdRng,
+    /// Values to read instead of random ones
+    reading: Option<(f64, f64)>,
+    fault: Option<SensorFault>,
+    /// Read at the next update, regardless of the sampling period
+    read_now: bool,
 }
 
-#[derive(Debug)]
-pub(crate) struct SensorState {
+#[derive(Debug, Clone)]
+pub struct SensorState {
     pub status: String,
     pub temperature: f64,
     pub humidity: f64,
 }
 
 impl Sensor {
-    fn new() -> Self {
+    fn new(seed: u64) -> Self {
         Self {
             status: "OK".into(),
             temperature: 20.0,
             humidity: 50.0,
-            last_update: time::Instant::now(),
+            last_update: Duration::ZERO,
+            rng: StdRng::seed_from_u64(seed),
+            reading: None,
+            fault: None,
+            read_now: false,
         }
     }
 
@@ -83,16 +130,28 @@ impl Sensor {
         }
     }
 
-    fn update(&mut self, now: &time::Instant) -> bool {
-        let elapsed = now.duration_since(self.last_update);
+    fn update(&mut self, now: Duration) -> bool {
+        let elapsed = now.saturating_sub(self.last_update);
 
         // Read temperature sensor every 2 seconds
-        if elapsed >= Duration::from_millis(2000) {
-            self.last_update = *now;
-
-            self.temperature = random::<f64>()*10.0 + 20.0; // random temp between 20 and 30 deg
-            self.humidity = random::<f64>()*40.0 + 30.0; // random humidity between 30 and 70
-            debug!("New sensor reading: temp={:.2}, hum={:.2}", self.temperature, self.humidity);
+        if elapsed >= Duration::from_millis(2000) || self.read_now {
+            self.last_update = now;
+            self.read_now = false;
+
+            let (temperature, humidity) = match (self.fault, self.reading) {
+                // Like the firmware, failed reads clear the values
+                (Some(_), _) => (0.0, 0.0),
+                (None, Some(reading)) => reading,
+                (None, None) => (
+                    self.rng.gen::<f64>()*10.0 + 20.0, // random temp between 20 and 30 deg
+                    self.rng.gen::<f64>()*40.0 + 30.0, // random humidity between 30 and 70
+                ),
+            };
+
+            self.status = self.fault.map_or("OK", <&str>::from).into();
+            self.temperature = temperature;
+            self.humidity = humidity;
+            debug!("New sensor reading: status={} temp={:.2}, hum={:.2}", self.status, self.temperature, self.humidity);
             true
         } else {
             false
@@ -106,7 +165,7 @@ enum SelfTestCmd {
     Def,
 }
 
-struct SelfTestStep([SelfTestCmd; 4], time::Duration);
+struct SelfTestStep([SelfTestCmd; 4], Duration);
 
 lazy_static! {
     static ref SELF_TEST: Vec<SelfTestStep> = vec![
@@ -119,14 +178,21 @@ lazy_static! {
     ];
 }
 
-#[derive(Debug)]
-pub(crate) struct SelfTestState {
+/// Something that happened while the test box ticked
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Event {
+    SelfTestStep(usize),
+    SensorRead,
+}
+
+#[derive(Debug, Clone)]
+pub struct SelfTestState {
     pub active: bool,
     pub progress: i64
 }
 
-#[derive(Debug)]
-pub(crate) struct TestBoxState {
+#[derive(Debug, Clone)]
+pub struct TestBoxState {
     pub red_led: PositionerState,
     pub yellow_led: PositionerState,
     pub green_led: PositionerState,
@@ -135,31 +201,32 @@ pub(crate) struct TestBoxState {
     pub self_test: SelfTestState
 }
 
-struct TestBox {
-    pub red_led: Positioner,
-    pub yellow_led: Positioner,
-    pub green_led: Positioner,
-    pub servo: Positioner,
-    pub sensor: Sensor,
+pub struct TestBox {
+    red_led: Positioner,
+    yellow_led: Positioner,
+    green_led: Positioner,
+    servo: Positioner,
+    sensor: Sensor,
 
-    next_self_test_step: time::Instant,
+    next_self_test_step: Duration,
     self_test_stage: usize,
 }
 
 impl TestBox {
-    fn new() -> Self {
+    /// Creates a test box as it is at boot. The seed drives the sensor readings.
+    pub fn new(seed: u64) -> Self {
         Self {
-            red_led: Positioner::new(0, 1023, 0),
-            yellow_led: Positioner::new(0, 1023, 0),
-            green_led: Positioner::new(0, 1023, 0),
-            servo: Positioner::new(0, 180, 90),
-            sensor: Sensor::new(),
-            next_self_test_step: time::Instant::now(),
+            red_led: Positioner::new(LED_RANGE),
+            yellow_led: Positioner::new(LED_RANGE),
+            green_led: Positioner::new(LED_RANGE),
+            servo: Positioner::new(SERVO_RANGE),
+            sensor: Sensor::new(seed),
+            next_self_test_step: Duration::ZERO,
             self_test_stage: SELF_TEST.len(),
         }
     }
 
-    fn get(&self) -> TestBoxState {
+    pub fn get(&self) -> TestBoxState {
         TestBoxState {
             red_led: self.red_led.get(),
             yellow_led: self.yellow_led.get(),
@@ -170,8 +237,8 @@ impl TestBox {
         }
     }
 
-    fn do_self_test_step(&mut self, now: &time::Instant) -> bool {
-        if self.self_test_stage < SELF_TEST.len() && *now > self.next_self_test_step {
+    fn do_self_test_step(&mut self, now: Duration) -> bool {
+        if self.self_test_stage < SELF_TEST.len() && now > self.next_self_test_step {
             debug!("Executing self test step {}", self.self_test_stage);
 
             let stage = &SELF_TEST[self.self_test_stage];
@@ -188,7 +255,7 @@ impl TestBox {
                 };
             }
 
-            self.next_self_test_step = *now + stage.1;
+            self.next_self_test_step = now + stage.1;
             self.self_test_stage += 1;
             true
         } else {
@@ -196,17 +263,24 @@ impl TestBox {
         }
     }
 
-    fn tick(&mut self) -> bool {
-        let now = time::Instant::now();
-        let sensor_changed = self.sensor.update(&now);
-        let self_test_changed = self.do_self_test_step(&now);
+    /// Runs the periodic work of the firmware's loop()
+    pub fn tick(&mut self, now: Duration) -> Vec<Event> {
+        let mut events = Vec::new();
 
-        sensor_changed || self_test_changed
+        // Same order as the firmware's loop()
+        let stage = self.self_test_stage;
+        if self.do_self_test_step(now) {
+            events.push(Event::SelfTestStep(stage));
+        }
+        if self.sensor.update(now) {
+            events.push(Event::SensorRead);
+        }
+
+        events
     }
 
-    fn start_self_test(&mut self) -> SelfTestState {
+    fn start_self_test(&mut self, now: Duration) -> SelfTestState {
         if self.self_test_stage == SELF_TEST.len() {
-            let now = time::Instant::now();
             self.self_test_stage = 0;
             self.next_self_test_step = now + SELF_TEST[0].1;
         }
@@ -218,87 +292,131 @@ impl TestBox {
         self.get_self_test()
     }
 
-    fn get_self_test(&self) -> SelfTestState {
-        let stage = self.self_test_stage;
-        let active = stage < SELF_TEST.len();
-        let progress = if active { (100*stage/SELF_TEST.len()) as i64 } else { 0 };
-        SelfTestState { active, progress }
+    fn read(&self, noun: RequestNoun) -> Response {
+        match noun {
+            RequestNoun::RedLed => Response::Value(self.red_led.get().value),
+            RequestNoun::YellowLed => Response::Value(self.yellow_led.get().value),
+            RequestNoun::GreenLed => Response::Value(self.green_led.get().value),
+            RequestNoun::Servo => Response::Value(self.servo.get().value),
+            RequestNoun::TempAndHum => {
+                let SensorState { status, temperature, humidity } = self.sensor.get();
+                Response::TempAndHum(status, temperature, humidity)
+            },
+            RequestNoun::SelfTest => {
+                let SelfTestState { active, progress } = self.get_self_test();
+                Response::SelfTest(active, progress)
+            },
+        }
     }
-}
 
-pub(crate) async fn testbox(
-    mut incoming_requests: mpsc::Receiver<Request>,
-    outgoing_responses: mpsc::Sender<Response>,
-    state_update_tx: mpsc::Sender<TestBoxState>
-) -> Result<(), Box<dyn Error>> {
+    /// Type, access and range of a noun, as reported by `DESCRIBE`
+    pub fn describe(noun: RequestNoun) -> Description {
+        match noun {
+            RequestNoun::RedLed | RequestNoun::YellowLed | RequestNoun::GreenLed => {
+                Description::new(noun, ValueKind::Int, Some(LED_RANGE))
+            }
+            RequestNoun::Servo => Description::new(noun, ValueKind::Int, Some(SERVO_RANGE)),
+            RequestNoun::TempAndHum => Description::new(noun, ValueKind::Sensor, None),
+            RequestNoun::SelfTest => Description::new(noun, ValueKind::Bool, Some((0, 1, 0))),
+        }
+    }
 
-    let mut tbox = TestBox::new();
-    let mut interval = time::interval(time::Duration::from_millis(100));
+    fn check_write(noun: RequestNoun, v: i64) -> Result<(), ResponseError> {
+        match (noun, v) {
+            (RequestNoun::TempAndHum, _) => Err(ResponseError::BadNoun),
+            (RequestNoun::SelfTest, 0 | 1) => Ok(()),
+            (RequestNoun::SelfTest, _) => Err(ResponseError::BadValue),
+            _ => Ok(())
+        }
+    }
 
-    // Send first update
-    state_update_tx.send(tbox.get()).await?;
+    fn write(&mut self, noun: RequestNoun, v: i64, now: Duration) -> Response {
+        if let Err(e) = Self::check_write(noun, v) {
+            return Response::Error(e);
+        }
 
-    while select! {
-        _ = interval.tick() => {
-            if tbox.tick() {
-                state_update_tx.send(tbox.get()).await?;
-            }
-            true
+        match noun {
+            RequestNoun::RedLed => Response::Value(self.red_led.set(v).value),
+            RequestNoun::YellowLed => Response::Value(self.yellow_led.set(v).value),
+            RequestNoun::GreenLed => Response::Value(self.green_led.set(v).value),
+            RequestNoun::Servo => Response::Value(self.servo.set(v).value),
+            RequestNoun::SelfTest => {
+                let SelfTestState { active, progress } = if v == 1 {
+                    self.start_self_test(now)
+                } else {
+                    self.stop_self_test()
+                };
+                Response::SelfTest(active, progress)
+            },
+            RequestNoun::TempAndHum => Response::Error(ResponseError::BadNoun),
         }
+    }
 
-        req = incoming_requests.recv() => {
-            match req {
-                Some(req) => {
-                    let response = match req {
-                        Request::Id => Response::Id("ESP8266_WEMOS_D1MINI".into()),
-
-                        Request::Get(RequestNoun::RedLed) => Response::Value(tbox.red_led.get().value),
-                        Request::Get(RequestNoun::YellowLed) => Response::Value(tbox.yellow_led.get().value),
-                        Request::Get(RequestNoun::GreenLed) => Response::Value(tbox.green_led.get().value),
-                        Request::Get(RequestNoun::Servo) => Response::Value(tbox.servo.get().value),
-                        Request::Get(RequestNoun::TempAndHum) => {
-                            let SensorState { status, temperature, humidity } = tbox.sensor.get();
-                            Response::TempAndHum(status, temperature, humidity)
-                        },
-                        Request::Get(RequestNoun::SelfTest) => {
-                            let SelfTestState { active, progress } = tbox.get_self_test();
-                            Response::SelfTest(active, progress)
-                        },
-
-                        Request::Set(RequestNoun::RedLed, v) => Response::Value(tbox.red_led.set(v).value),
-                        Request::Set(RequestNoun::YellowLed, v) => Response::Value(tbox.yellow_led.set(v).value),
-                        Request::Set(RequestNoun::GreenLed, v) => Response::Value(tbox.green_led.set(v).value),
-                        Request::Set(RequestNoun::Servo, v) => Response::Value(tbox.servo.set(v).value),
-                        Request::Set(RequestNoun::SelfTest, v) => {
-                            match v {
-                                0 | 1 => {
-                                    let SelfTestState { active, progress } = if v == 1 {
-                                        tbox.start_self_test()
-                                    } else {
-                                        tbox.stop_self_test()
-                                    };
-                                    Response::SelfTest(active, progress)
-                                },
-                                _ => {
-                                    Response::Error(ResponseError::BadValue)
-                                }
-                            }
-                        },
-                        Request::Set(RequestNoun::TempAndHum, _) => Response::Error(ResponseError::BadNoun),
-                    };
-
-                    outgoing_responses.send(response).await?;
-                    state_update_tx.send(tbox.get()).await?;
-                    true
+    pub fn handle(&mut self, request: Request, now: Duration) -> Response {
+        match request {
+            Request::Id => Response::Id("ESP8266_WEMOS_D1MINI".into()),
+            Request::Get(noun) => self.read(noun),
+            Request::Set(noun, v) => self.write(noun, v, now),
+            Request::GetMany(nouns) => Response::Many(nouns.iter().map(|noun| self.read(noun)).collect()),
+            Request::SetMany(items) => {
+                // Validate everything first, so the batch is applied either entirely or not at all
+                match items.iter().try_for_each(|(noun, v)| Self::check_write(noun, v)) {
+                    Ok(()) => Response::Many(items.iter().map(|(noun, v)| self.write(noun, v, now)).collect()),
+                    Err(e) => Response::Error(e)
                 }
+            },
+            // Echoed back so the parser switches framing in order with the responses
+            Request::Framing(f) => Response::Framing(f),
+            Request::Help => Response::Names(VERBS.iter().map(|&v| v.into()).collect()),
+            Request::List => Response::Names(RequestNoun::ALL.iter().map(|&n| <&str>::from(n).into()).collect()),
+            Request::Describe(noun) => Response::Description(Self::describe(noun)),
+            Request::Version => Response::Version(PROTOCOL_VERSION, format!("SIMULATOR-{}", env!("CARGO_PKG_VERSION"))),
+            // Only the device and the server know the clients apart
+            Request::Lease(_) => Response::Error(ResponseError::BadVerb),
+        }
+    }
 
-                None => {
-                    info!("Incoming requests channel closed, exiting");
-                    false
-                }
-            }
+    /// Puts the test box back as it is at boot, for a board reset at `now`.
+    /// What the sensor senses, fixed readings or faults, is not part of the
+    /// board and stays.
+    pub fn reset(&mut self, now: Duration) {
+        for positioner in [&mut self.red_led, &mut self.yellow_led, &mut self.green_led, &mut self.servo] {
+            positioner.reset();
         }
-    } {}
+        self.self_test_stage = SELF_TEST.len();
+
+        let sensor = &mut self.sensor;
+        sensor.status = "OK".into();
+        sensor.temperature = 20.0;
+        sensor.humidity = 50.0;
+        sensor.last_update = now;
+        sensor.read_now = false;
+    }
+
+    /// Makes the sensor read fixed values from the next tick on, or random
+    /// ones again with `None`
+    pub fn set_sensor(&mut self, reading: Option<(f64, f64)>) {
+        self.sensor.reading = reading;
+        self.sensor.read_now = true;
+    }
+
+    /// Makes the sensor reads fail from the next tick on, or succeed again with `None`
+    pub fn set_sensor_fault(&mut self, fault: Option<SensorFault>) {
+        self.sensor.fault = fault;
+        self.sensor.read_now = true;
+    }
+
+    /// Changes what the sensor senses without reading it, the change shows at
+    /// its next regular read
+    pub fn sense(&mut self, reading: Option<(f64, f64)>, fault: Option<SensorFault>) {
+        self.sensor.reading = reading;
+        self.sensor.fault = fault;
+    }
 
-    Ok(())
-}
\ No newline at end of file
+    fn get_self_test(&self) -> SelfTestState {
+        let stage = self.self_test_stage;
+        let active = stage < SELF_TEST.len();
+        let progress = if active { (100*stage/SELF_TEST.len()) as i64 } else { 0 };
+        SelfTestState { active, progress }
+    }
+}
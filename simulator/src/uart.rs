@@ -0,0 +1,224 @@
+//! Model of the board's serial receive path
+//!
+//! Bytes sent by the host travel over the wire at the serial baud rate and
+//! land in the UART RX FIFO. `loop()` drains the FIFO into the request line
+//! buffer once per iteration, and only until a request is pending. Bytes that
+//! arrive while the FIFO is full are silently dropped, as on the ESP8266.
+
+use std::{collections::VecDeque, time::Duration};
+
+/// Serial baud rate of the board
+pub const BAUD_RATE: u64 = 115200;
+
+/// Size of the ESP8266 core's serial RX buffer
+pub const RX_FIFO_SIZE: usize = 256;
+
+/// Size of the ESP8266 UART TX FIFO
+pub const TX_FIFO_SIZE: usize = 128;
+
+/// Bits on the wire per byte, with 8N1 framing
+const BITS_PER_BYTE: u64 = 10;
+
+/// Time needed to send one byte
+pub fn byte_time(baud_rate: u64) -> Duration {
+    Duration::from_nanos(1_000_000_000 * BITS_PER_BYTE / baud_rate)
+}
+
+/// Time a write of `len` bytes blocks the firmware: everything that doesn't
+/// fit in the TX FIFO has to go out on the wire first
+pub fn write_time(len: usize, baud_rate: u64) -> Duration {
+    byte_time(baud_rate) * len.saturating_sub(TX_FIFO_SIZE) as u32
+}
+
+pub struct Uart {
+    /// Bytes sent by the host that haven't reached the board yet, with the
+    /// time they arrive at
+    wire: VecDeque<(Duration, u8)>,
+    /// When the last byte on the wire has arrived, and the line is idle again
+    idle: Duration,
+    fifo: VecDeque<u8>,
+    capacity: usize,
+    byte_time: Duration,
+    dropped: u64,
+}
+
+impl Uart {
+    pub fn new(capacity: usize, baud_rate: u64) -> Self {
+        Self {
+            wire: VecDeque::new(),
+            idle: Duration::ZERO,
+            fifo: VecDeque::with_capacity(capacity),
+            capacity,
+            byte_time: byte_time(baud_rate),
+            dropped: 0,
+        }
+    }
+
+    /// Queues bytes sent by the host at `now`. They go out one after the
+    /// other, once the bytes sent before are through.
+    pub fn send(&mut self, bytes: &[u8], now: Duration) {
+        for &c in bytes {
+            self.idle = self.idle.max(now) + self.byte_time;
+            self.wire.push_back((self.idle, c));
+        }
+    }
+
+    /// Moves the bytes that arrived by `now` from the wire into the FIFO.
+    /// Returns the number of bytes dropped because the FIFO was full.
+    pub fn advance(&mut self, now: Duration) -> u64 {
+        let mut dropped = 0;
+
+        while let Some(&(_, c)) = self.wire.front().filter(|(arrival, _)| *arrival <= now) {
+            self.wire.pop_front();
+            if self.fifo.len() < self.capacity {
+                self.fifo.push_back(c);
+            } else {
+                dropped += 1;
+            }
+        }
+
+        self.dropped += dropped;
+        dropped
+    }
+
+    /// `Serial.read()`
+    pub fn read(&mut self) -> Option<u8> {
+        self.fifo.pop_front()
+    }
+
+    /// Bytes dropped since the UART was created
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    pub fn clear(&mut self) {
+        self.wire.clear();
+        self.idle = Duration::ZERO;
+        self.fifo.clear();
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Parity {
+    None,
+    Odd,
+    Even,
+    Mark,
+    Space,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum StopBits {
+    One,
+    OnePointFive,
+    Two,
+}
+
+/// Serial line configuration of one end of the wire
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct LineSettings {
+    pub baud_rate: u32,
+    /// 5 to 8
+    pub data_bits: u8,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+}
+
+impl LineSettings {
+    /// The board's serial port, 115200 8N1
+    pub const BOARD: Self = Self { baud_rate: BAUD_RATE as u32, data_bits: 8, parity: Parity::None, stop_bits: StopBits::One };
+
+    /// Duration of half a bit, in picoseconds
+    fn half_bit(&self) -> u64 {
+        1_000_000_000_000 / (2 * self.baud_rate.max(1) as u64)
+    }
+}
+
+/// Levels on the wire while sending `bytes` back to back, as the times at
+/// which each level ends
+fn waveform(bytes: &[u8], line: LineSettings) -> Vec<(bool, u64)> {
+    let half_bit = line.half_bit();
+    let mut levels: Vec<(bool, u64)> = Vec::new();
+    let mut t = 0;
+    let mut push = |level: bool, half_bits: u64| {
+        t += half_bits * half_bit;
+        match levels.last_mut() {
+            Some((last, end)) if *last == level => *end = t,
+            _ => levels.push((level, t)),
+        }
+    };
+
+    for &c in bytes {
+        let data = c & ((1u16 << line.data_bits) - 1) as u8;
+
+        push(false, 2);
+        for i in 0..line.data_bits {
+            push(data >> i & 1 == 1, 2);
+        }
+        let ones = data.count_ones() % 2 == 1;
+        match line.parity {
+            Parity::None => {}
+            Parity::Odd => push(!ones, 2),
+            Parity::Even => push(ones, 2),
+            Parity::Mark => push(true, 2),
+            Parity::Space => push(false, 2),
+        }
+        push(true, match line.stop_bits {
+            StopBits::One => 2,
+            StopBits::OnePointFive => 3,
+            StopBits::Two => 4,
+        });
+    }
+
+    levels
+}
+
+/// What a UART configured with `to` receives when `bytes` are sent with
+/// `from`. Mismatched settings garble the bytes, like on a real wire: the
+/// receiver samples the middle of its own bit times.
+pub fn transcode(bytes: &[u8], from: LineSettings, to: LineSettings) -> Vec<u8> {
+    if from == to {
+        return bytes.to_vec();
+    }
+
+    let levels = waveform(bytes, from);
+    let end = levels.last().map_or(0, |&(_, end)| end);
+    let index = |t: u64| levels.partition_point(|&(_, end)| end <= t);
+    // The line idles high
+    let level = |t: u64| levels.get(index(t)).is_none_or(|&(level, _)| level);
+
+    let bit = 2 * to.half_bit();
+    let frame_bits = 1 + to.data_bits as u64 + u64::from(to.parity != Parity::None);
+    let mut received = Vec::new();
+    let mut t = 0;
+
+    while t < end {
+        // Wait for a start bit
+        let start = match levels[index(t)] {
+            (false, _) => t,
+            _ => match levels[index(t)..].iter().position(|&(level, _)| !level) {
+                Some(i) => levels[index(t) + i - 1].1,
+                None => break,
+            }
+        };
+
+        // A start bit that is gone by its middle was a glitch
+        if level(start + bit / 2) {
+            t = start + bit / 2;
+            continue;
+        }
+
+        let mut c = 0u8;
+        for i in 0..to.data_bits as u64 {
+            if level(start + (i + 1) * bit + bit / 2) {
+                c |= 1 << i;
+            }
+        }
+        received.push(c);
+
+        // Look for the next start bit from the middle of the stop bit on
+        t = start + frame_bits * bit + bit / 2;
+    }
+
+    received
+}
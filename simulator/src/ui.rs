@@ -1,8 +1,9 @@
-use std::{error::Error, fmt, sync::Mutex};
+use std::{fmt, sync::Mutex};
 
 use status_line::StatusLine;
 use tokio::sync::mpsc;
 
+use crate::supervisor::TaskResult;
 use crate::testbox::TestBoxState;
 
 struct Status(Mutex<TestBoxState>);
@@ -20,9 +21,9 @@ impl fmt::Display for Status {
     }
 }
 
-pub(crate) async fn ui(
+pub async fn ui(
     mut state_update_rx: mpsc::Receiver<TestBoxState>
-) -> Result<(), Box<dyn Error>> {
+) -> TaskResult {
 
     let status = StatusLine::new(
         Status(
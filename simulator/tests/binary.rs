@@ -0,0 +1,84 @@
+//! COBS/CRC framing of the binary protocol, and sessions speaking it.
+
+use std::time::Duration;
+
+use simulator::{
+    binary::{Decode, Encode, FrameDecoder},
+    parser::{ParseMode, Request, RequestNoun, Response, ResponseError, Session, Tagged},
+    testbox::TestBox,
+};
+
+fn get_servo(tag: u32) -> Tagged<Request> {
+    Tagged { tag: Some(tag), inner: Request::Get(RequestNoun::Servo) }
+}
+
+/// Feeds bytes to a frame decoder, returns what came out of it
+fn decode(frame: &[u8]) -> Vec<Result<Vec<u8>, ResponseError>> {
+    let mut decoder = FrameDecoder::<16>::new();
+    frame.iter().filter_map(|&c| decoder.push(c)).collect()
+}
+
+#[test]
+fn frames_decode_to_what_was_encoded() {
+    let frame = get_servo(7).to_frame();
+    assert_eq!(frame.last(), Some(&0));
+    assert!(!frame[..frame.len() - 1].contains(&0));
+
+    let payloads = decode(&frame);
+    assert_eq!(payloads.len(), 1);
+    let request = Tagged::<Request>::decode(payloads[0].as_ref().unwrap()).unwrap();
+    assert_eq!((request.tag, request.inner), (Some(7), Request::Get(RequestNoun::Servo)));
+
+    // Empty frames between frames are skipped
+    assert_eq!(decode(&[&[0, 0][..], &frame, &[0], &frame].concat()).len(), 2);
+}
+
+#[test]
+fn corrupted_frames_are_rejected() {
+    let frame = get_servo(7).to_frame();
+
+    // A flipped bit fails the CRC
+    let mut flipped = frame.clone();
+    flipped[2] ^= 0x04;
+    assert_eq!(decode(&flipped), [Err(ResponseError::BadChecksum)]);
+
+    // A frame cut short, its COBS code points past the end
+    let truncated = [&frame[..frame.len() - 3], &[0]].concat();
+    assert_eq!(decode(&truncated), [Err(ResponseError::BadChecksum)]);
+
+    // Too short to hold a CRC
+    assert_eq!(decode(&[2, 0x42, 0]), [Err(ResponseError::BadChecksum)]);
+
+    // Longer than the buffer, the decoder picks up again at the next frame
+    let overlong = [&[0x42; 20][..], &[0], &frame].concat();
+    assert_eq!(decode(&overlong).len(), 2);
+    assert_eq!(decode(&overlong)[0], Err(ResponseError::BadSyntax));
+    assert!(decode(&overlong)[1].is_ok());
+}
+
+#[test]
+fn sessions_starting_with_a_zero_byte_speak_binary() {
+    let mut session = Session::<256>::new(ParseMode::Strict);
+    let mut tbox = TestBox::new(0);
+
+    let mut flipped = get_servo(1).to_frame();
+    flipped[2] ^= 0x04;
+    let input = [&[0][..], &flipped, &get_servo(2).to_frame()].concat();
+
+    let mut responses = Vec::new();
+    for c in input {
+        if let Some(request) = session.push(c) {
+            let response = request.map(|r| match r {
+                Ok(r) => tbox.handle(r, Duration::ZERO),
+                Err(e) => Response::Error(e),
+            });
+            responses.extend(decode(&session.encode(response)));
+        }
+    }
+
+    // As the text protocol would put them
+    let responses: Vec<_> = responses.iter()
+        .map(|payload| Vec::<u8>::from(Tagged::<Response>::decode(payload.as_ref().unwrap()).unwrap()))
+        .collect();
+    assert_eq!(responses, [&b"ERR BAD_CHECKSUM\r\n"[..], b"#2 OK 90\r\n"]);
+}
@@ -0,0 +1,226 @@
+//! Runs the bridge against a simulated board on the other end of a PTY pair.
+
+#![cfg(target_os = "linux")]
+
+use std::{
+    fs::File, io::{Read, Write}, os::fd::{AsFd, OwnedFd}, path::{Path, PathBuf},
+    sync::{atomic::{AtomicBool, Ordering}, Arc}, thread, time::Duration
+};
+
+use nix::{poll::{poll, PollFd, PollFlags, PollTimeout}, pty::openpty, sys::termios, unistd::ttyname};
+use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpStream, sync::{mpsc, watch}, task::JoinHandle};
+
+use simulator::{bridge, device::Device, listener::Listener, parser::ParseMode, server, supervisor::TaskResult, testbox::TestBox};
+
+/// A board on the master side of a PTY, reachable through `link` until unplugged
+struct Board {
+    link: PathBuf,
+    // Kept open so the PTY stays around while the bridge has the port closed
+    _slave: OwnedFd,
+    stop: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Board {
+    fn plug_in(link: &Path) -> Self {
+        Self::plug_in_parsing(link, ParseMode::Strict)
+    }
+
+    fn plug_in_parsing(link: &Path, mode: ParseMode) -> Self {
+        Self::plug_in_with(link, mode, Duration::ZERO)
+    }
+
+    /// A board that takes `delay` to send its first response
+    fn plug_in_slow(link: &Path, delay: Duration) -> Self {
+        Self::plug_in_with(link, ParseMode::Strict, delay)
+    }
+
+    fn plug_in_with(link: &Path, mode: ParseMode, delay: Duration) -> Self {
+        let pty = openpty(None, None).unwrap();
+        let mut settings = termios::tcgetattr(&pty.slave).unwrap();
+        termios::cfmakeraw(&mut settings);
+        termios::tcsetattr(&pty.slave, termios::SetArg::TCSANOW, &settings).unwrap();
+
+        let staging = link.with_extension("new");
+        std::os::unix::fs::symlink(ttyname(&pty.slave).unwrap(), &staging).unwrap();
+        std::fs::rename(&staging, link).unwrap();
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = thread::spawn({
+            let stop = stop.clone();
+            move || run(File::from(pty.master), mode, delay, &stop)
+        });
+        Self { link: link.to_owned(), _slave: pty.slave, stop, thread: Some(thread) }
+    }
+
+    /// The USB device goes away, taking its device node with it
+    fn unplug(mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        self.thread.take().unwrap().join().unwrap();
+        std::fs::remove_file(&self.link).unwrap();
+    }
+}
+
+fn run(mut master: File, mode: ParseMode, mut delay: Duration, stop: &AtomicBool) {
+    let mut device = Device::<256>::new(TestBox::new(1), mode, None, false);
+    let mut buffer = [0u8; 256];
+
+    while !stop.load(Ordering::Relaxed) {
+        let mut fds = [PollFd::new(master.as_fd(), PollFlags::POLLIN)];
+        if poll(&mut fds, PollTimeout::from(50u8)).unwrap() == 0 {
+            continue;
+        }
+        // Fails for a while after the bridge closes the port, as it hangs up
+        let Ok(n) = master.read(&mut buffer) else {
+            thread::sleep(Duration::from_millis(50));
+            continue;
+        };
+        device.receive(&buffer[..n], Duration::ZERO);
+        while let Some(bytes) = device.transmit() {
+            thread::sleep(std::mem::take(&mut delay));
+            // Unplugged before it could answer
+            if stop.load(Ordering::Relaxed) {
+                return;
+            }
+            master.write_all(&bytes).unwrap();
+        }
+    }
+}
+
+struct Bridge {
+    port: u16,
+    shutdown: watch::Sender<bool>,
+    tasks: Vec<JoinHandle<TaskResult>>,
+}
+
+impl Bridge {
+    async fn start(path: &Path) -> Self {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let listener = Listener::tcp(listener).unwrap();
+        let (exchanges_tx, exchanges_rx) = mpsc::channel(1);
+        let (shutdown, shutdown_rx) = watch::channel(false);
+
+        let tasks = vec![
+            tokio::spawn(server::shared::<256>(listener, server::Timeouts::default(), exchanges_tx, shutdown_rx.clone())),
+            tokio::spawn(bridge::bridge(bridge::Upstream::Serial(path.to_owned()), exchanges_rx, shutdown_rx)),
+        ];
+        Self { port, shutdown, tasks }
+    }
+
+    async fn stop(self) {
+        self.shutdown.send_replace(true);
+        tokio::time::timeout(Duration::from_secs(5), async {
+            for task in self.tasks {
+                task.await.unwrap().unwrap();
+            }
+        }).await.unwrap();
+    }
+}
+
+async fn exchange(port: u16, requests: String) -> String {
+    let mut client = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+    client.write_all(requests.as_bytes()).await.unwrap();
+    client.shutdown().await.unwrap();
+    let mut responses = String::new();
+    client.read_to_string(&mut responses).await.unwrap();
+    responses
+}
+
+fn link_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("simulator-{}-{}", name, std::process::id()))
+}
+
+#[tokio::test]
+async fn clients_get_their_own_responses() {
+    let link = link_path("arbitration");
+    let board = Board::plug_in(&link);
+    let bridge = Bridge::start(&link).await;
+
+    let servo = tokio::spawn(exchange(bridge.port, "SET SERVO 10\n".repeat(50)));
+    let noun = tokio::spawn(exchange(bridge.port, "GET FOO\n".repeat(50)));
+
+    assert_eq!(servo.await.unwrap(), "OK 10\r\n".repeat(50));
+    assert_eq!(noun.await.unwrap(), "ERR BAD_NOUN\r\n".repeat(50));
+
+    bridge.stop().await;
+    board.unplug();
+}
+
+#[tokio::test]
+async fn port_is_opened_again_when_the_board_is_back() {
+    let link = link_path("replug");
+    let board = Board::plug_in(&link);
+    let bridge = Bridge::start(&link).await;
+
+    assert_eq!(exchange(bridge.port, "SET SERVO 10\nGET SERVO\n".into()).await, "OK 10\r\nOK 10\r\n");
+
+    board.unplug();
+    // Waits for the board instead of failing
+    let waiting = tokio::spawn(exchange(bridge.port, "GET SERVO\n".into()));
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    assert!(!waiting.is_finished());
+
+    // A fresh board, with the servo back to its default
+    let board = Board::plug_in(&link);
+    assert_eq!(waiting.await.unwrap(), "OK 90\r\n");
+
+    bridge.stop().await;
+    board.unplug();
+}
+
+#[tokio::test]
+async fn leases_are_held_by_the_bridge() {
+    let link = link_path("lease");
+    let board = Board::plug_in_parsing(&link, ParseMode::Firmware);
+    let bridge = Bridge::start(&link).await;
+
+    // The firmware takes lines the bridge doesn't parse
+    assert_eq!(exchange(bridge.port, "SET  SERVO 90\n".into()).await, "OK 90\r\n");
+
+    let taken = exchange(bridge.port, "LEASE SHARED ci-1 60\nSET SERVO 10\n".into()).await;
+    assert!(taken.starts_with("OK SHARED ci-1 60 TOKEN "), "{:?}", taken);
+    assert!(taken.ends_with("\r\nOK 10\r\n"), "{:?}", taken);
+    // Answered by the bridge, in order with the board's responses
+    assert_eq!(
+        exchange(bridge.port, "GET SERVO\nSET SERVO 20\nLEASE\nGET SERVO\n".into()).await,
+        "OK 10\r\nERR LEASED\r\nOK SHARED ci-1 60\r\nOK 10\r\n"
+    );
+    // Lines the bridge can't tell apart are refused too
+    assert_eq!(
+        exchange(bridge.port, "SET  SERVO 90\nSET RED_LED 5x\nGET SERVO\n".into()).await,
+        "ERR LEASED\r\nERR LEASED\r\nOK 10\r\n"
+    );
+
+    bridge.stop().await;
+    board.unplug();
+}
+
+#[tokio::test]
+async fn late_responses_are_not_taken_for_the_next_ones() {
+    let link = link_path("slow");
+    let board = Board::plug_in_slow(&link, Duration::from_millis(1500));
+    let bridge = Bridge::start(&link).await;
+
+    assert_eq!(
+        exchange(bridge.port, "#1 GET SERVO\nGET RED_LED\n".into()).await,
+        "#1 ERR NO_RESPONSE\r\nOK 0\r\n"
+    );
+
+    bridge.stop().await;
+    board.unplug();
+}
+
+#[tokio::test]
+async fn requests_are_answered_when_the_port_is_lost() {
+    let link = link_path("lost");
+    let board = Board::plug_in_slow(&link, Duration::from_millis(500));
+    let bridge = Bridge::start(&link).await;
+
+    let waiting = tokio::spawn(exchange(bridge.port, "#1 GET SERVO\n".into()));
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    tokio::task::spawn_blocking(|| board.unplug()).await.unwrap();
+    assert_eq!(waiting.await.unwrap(), "#1 ERR NO_RESPONSE\r\n");
+
+    bridge.stop().await;
+}
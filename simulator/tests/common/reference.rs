@@ -0,0 +1,125 @@
+//! Regex based request parser, as it was before the hand-written one.
+//! Used as the reference the current parser must agree with.
+
+use std::convert::TryInto;
+
+use lazy_static::lazy_static;
+use regex::bytes::Regex;
+use simulator::parser::{Batch, Framing, Request, RequestNoun, ResponseError};
+
+fn gettable(_noun: RequestNoun) -> Result<(), ResponseError> {
+    Ok(())
+}
+
+fn settable(noun: RequestNoun) -> Result<(), ResponseError> {
+    match noun {
+        RequestNoun::TempAndHum => Err(ResponseError::BadNoun),
+        _ => Ok(())
+    }
+}
+
+lazy_static! {
+    // Compiled once here, the old parser compiled it on every call
+    static ref RE: Regex = Regex::new(r"([^ \r\n]+)( [^ \r\n]+)?( [^\r\n]+)?\r?\n")
+        .expect("Failed to create decoder regex");
+}
+
+pub fn parse(data: &[u8]) -> Result<Request, ResponseError> {
+    let caps = RE.captures(data).ok_or(ResponseError::BadSyntax)?;
+
+    let verb = caps.get(1).ok_or(ResponseError::BadSyntax)?;
+
+    match verb.as_bytes() {
+        b"ID" => caps.get(2).map_or(Ok(Request::Id), |_| Err(ResponseError::BadNoun)),
+
+        b"GET" if caps.get(2).is_some_and(|n| n.as_bytes().contains(&b',')) => {
+            // GET <NOUN>,<NOUN>,...
+            let mut nouns = Batch::new();
+
+            for noun in caps[2][1..].split(|&c| c == b',') { // skip leading space
+                let noun: RequestNoun = noun.try_into()?;
+                gettable(noun)?;
+
+                if nouns.iter().any(|n| n == noun) {
+                    return Err(ResponseError::BadNoun);
+                }
+                nouns.push(noun)?;
+            }
+
+            caps.get(3).map_or(Ok(Request::GetMany(nouns)), |_| Err(ResponseError::BadValue))
+        }
+
+        b"SET" if caps.get(2).is_some_and(|n| n.as_bytes().contains(&b'=')) => {
+            // SET <NOUN>=<VALUE>,<NOUN>=<VALUE>,...
+            let mut items = Batch::new();
+
+            for item in caps[2][1..].split(|&c| c == b',') { // skip leading space
+                let eq = item.iter().position(|&c| c == b'=').unwrap_or(item.len());
+                let noun: RequestNoun = item[..eq].try_into()?;
+                settable(noun)?;
+
+                let value = item.get(eq + 1..).ok_or(ResponseError::BadValue)?;
+                let value = String::from_utf8_lossy(value);
+                let value = value.parse::<i64>().map_err(|_| ResponseError::BadValue)?;
+
+                if items.iter().any(|(n, _)| n == noun) {
+                    return Err(ResponseError::BadNoun);
+                }
+                items.push((noun, value))?;
+            }
+
+            caps.get(3).map_or(Ok(Request::SetMany(items)), |_| Err(ResponseError::BadValue))
+        }
+
+        verb @ (b"GET" | b"SET") => {
+            let noun: RequestNoun = caps.get(2)
+                .ok_or(ResponseError::BadNoun)?
+                .as_bytes()[1..] // skip leading space
+                .try_into()?;
+
+            if verb == b"GET" {
+                gettable(noun)?;
+                caps.get(3).map_or(Ok(Request::Get(noun)), |_| Err(ResponseError::BadValue))
+            } else {
+                settable(noun)?;
+                let value = caps.get(3).ok_or(ResponseError::BadValue)?;
+                let value = String::from_utf8_lossy(&value.as_bytes()[1..]); // skip leading space
+                let value = value.parse::<i64>().map_err(|_| ResponseError::BadValue)?;
+
+                Ok(Request::Set(noun, value))
+            }
+        }
+
+        b"FRAMING" => {
+            let framing: Framing = caps.get(2)
+                .ok_or(ResponseError::BadNoun)?
+                .as_bytes()[1..] // skip leading space
+                .try_into()?;
+
+            caps.get(3).map_or(Ok(Request::Framing(framing)), |_| Err(ResponseError::BadValue))
+        }
+
+        verb @ (b"HELP" | b"LIST" | b"VERSION") => {
+            let request = match verb {
+                b"HELP" => Request::Help,
+                b"LIST" => Request::List,
+                _ => Request::Version
+            };
+
+            caps.get(2).map_or(Ok(request), |_| Err(ResponseError::BadNoun))
+        }
+
+        b"DESCRIBE" => {
+            let noun: RequestNoun = caps.get(2)
+                .ok_or(ResponseError::BadNoun)?
+                .as_bytes()[1..] // skip leading space
+                .try_into()?;
+
+            caps.get(3).map_or(Ok(Request::Describe(noun)), |_| Err(ResponseError::BadValue))
+        }
+
+        _ => {
+            Err(ResponseError::BadVerb)
+        }
+    }
+}
@@ -0,0 +1,131 @@
+//! Drives the device on virtual time, without any runtime.
+
+use std::time::Duration;
+
+use simulator::{device::Device, parser::ParseMode, testbox::{SensorFault, TestBox}};
+
+fn ms(ms: u64) -> Duration {
+    Duration::from_millis(ms)
+}
+
+fn device(loop_cadence: bool) -> Device<256> {
+    Device::new(TestBox::new(1), ParseMode::Strict, None, loop_cadence)
+}
+
+fn transmitted(device: &mut Device<256>) -> String {
+    std::iter::from_fn(|| device.transmit())
+        .map(|bytes| String::from_utf8(bytes).unwrap())
+        .collect()
+}
+
+#[test]
+fn answers_right_away() {
+    let mut device = device(false);
+
+    device.receive(b"GET SERVO\nSET RED_LED 5\nFOO\n", Duration::ZERO);
+    assert_eq!(transmitted(&mut device), "OK 90\r\nOK 5\r\nERR BAD_VERB\r\n");
+}
+
+#[test]
+fn loop_cadence_answers_one_request_per_iteration() {
+    let mut device = device(true);
+
+    device.receive(b"GET SERVO\nGET RED_LED\nID\n", Duration::ZERO);
+    assert_eq!(transmitted(&mut device), "");
+
+    device.advance(Duration::ZERO);
+    assert_eq!(transmitted(&mut device), "OK 90\r\n");
+    assert_eq!(device.next_deadline(), ms(20));
+
+    device.advance(ms(19));
+    assert_eq!(transmitted(&mut device), "");
+
+    device.advance(ms(40));
+    assert_eq!(transmitted(&mut device), "OK 0\r\nOK ESP8266_WEMOS_D1MINI\r\n");
+}
+
+#[test]
+fn pipelined_requests_wait_for_their_loop_iteration() {
+    let mut device = device(true);
+    device.advance(Duration::ZERO);
+
+    // Five requests at once, then one arriving between two iterations
+    device.receive(b"SET RED_LED 1\nSET RED_LED 2\nSET RED_LED 3\nSET RED_LED 4\nGET RED_LED\n", ms(5));
+    let mut answered = Vec::new();
+    for n in 1..=7 {
+        if n == 3 {
+            device.receive(b"ID\n", ms(50));
+        }
+        device.advance(ms(20*n));
+        answered.push(transmitted(&mut device));
+    }
+
+    assert_eq!(answered, [
+        "OK 1\r\n", "OK 2\r\n", "OK 3\r\n", "OK 4\r\n", "OK 4\r\n", "OK ESP8266_WEMOS_D1MINI\r\n", "",
+    ]);
+    assert_eq!(device.next_deadline(), ms(160));
+}
+
+#[test]
+fn self_test_runs_on_virtual_time() {
+    let mut device = device(false);
+
+    device.receive(b"SET SELF_TEST 1\n", Duration::ZERO);
+    device.advance(ms(1200));
+    device.receive(b"GET SELF_TEST\n", ms(1200));
+    device.advance(ms(3000));
+    device.receive(b"GET SELF_TEST\n", ms(3000));
+
+    assert_eq!(transmitted(&mut device), "OK ACTIVE 0\r\nOK ACTIVE 40\r\nOK INACTIVE 0\r\n");
+}
+
+#[test]
+fn sensor_readings_follow_the_seed() {
+    let read = |seed| {
+        let mut device = Device::<256>::new(TestBox::new(seed), ParseMode::Strict, None, false);
+        device.advance(ms(2000));
+        device.receive(b"GET TEMP_AND_HUM\n", ms(2000));
+        transmitted(&mut device)
+    };
+
+    assert_eq!(read(7), read(7));
+    assert_ne!(read(7), read(8));
+}
+
+#[test]
+fn sensor_controls_apply_at_the_next_tick() {
+    let mut device = device(false);
+    device.advance(Duration::ZERO);
+
+    device.testbox_mut().set_sensor(Some((21.5, 40.0)));
+    device.receive(b"GET TEMP_AND_HUM\n", ms(50));
+    device.advance(ms(100));
+    device.receive(b"GET TEMP_AND_HUM\n", ms(100));
+
+    device.testbox_mut().set_sensor_fault(Some(SensorFault::Checksum));
+    device.advance(ms(200));
+    device.receive(b"GET TEMP_AND_HUM\n", ms(200));
+
+    assert_eq!(
+        transmitted(&mut device),
+        "OK OK 20.00 50.00\r\nOK OK 21.50 40.00\r\nOK CHECKSUM 0.00 0.00\r\n"
+    );
+}
+
+#[test]
+fn disconnect_answers_what_was_received_then_marks_the_end() {
+    let mut device = device(true);
+
+    device.receive(b"SET SERVO 10\nGET SERVO\nGET SER", Duration::ZERO);
+    device.disconnect(Duration::ZERO);
+
+    assert_eq!(device.transmit().as_deref(), Some(&b"OK 10\r\n"[..]));
+    assert_eq!(device.transmit().as_deref(), Some(&b"OK 10\r\n"[..]));
+    assert_eq!(device.transmit(), Some(Vec::new()));
+    assert_eq!(device.transmit(), None);
+
+    // The partial line is gone with the previous host
+    device.receive(b"VO\n", ms(20));
+    device.advance(ms(20));
+    assert_eq!(transmitted(&mut device), "ERR BAD_VERB\r\n");
+}
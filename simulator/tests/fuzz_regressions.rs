@@ -0,0 +1,36 @@
+//! Replays the inputs that broke a fuzz target, kept in
+//! `fuzz/regressions/<target>/`.
+
+use std::{fs, panic, path::Path};
+
+mod common;
+
+#[path = "../fuzz/src/checks.rs"]
+mod checks;
+
+fn replay(target: &str, check: fn(&[u8])) {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("fuzz/regressions").join(target);
+
+    for entry in fs::read_dir(&dir).unwrap_or_else(|e| panic!("Failed to read {:?}: {}", dir, e)) {
+        let path = entry.expect("Failed to read regression entry").path();
+        let data = fs::read(&path).unwrap_or_else(|e| panic!("Failed to read {:?}: {}", path, e));
+
+        let result = panic::catch_unwind(|| check(&data));
+        assert!(result.is_ok(), "{:?} breaks the {} target again", path, target);
+    }
+}
+
+#[test]
+fn request() {
+    replay("request", checks::request);
+}
+
+#[test]
+fn session() {
+    replay("session", checks::session);
+}
+
+#[test]
+fn response() {
+    replay("response", checks::response);
+}
@@ -0,0 +1,152 @@
+//! Leases, as requested over the protocol and enforced by the device.
+
+use std::{convert::TryFrom, time::Duration};
+
+use simulator::{
+    binary::{Decode, Encode},
+    device::{Device, Input},
+    parser::{ParseMode, Request, Response, ResponseError, Tagged},
+    testbox::TestBox,
+};
+
+fn secs(secs: u64) -> Duration {
+    Duration::from_secs(secs)
+}
+
+/// Sends `requests` as client `client`, and returns the responses
+fn send(device: &mut Device<256>, client: u64, requests: &str, now: Duration) -> String {
+    device.input(Input::Connected(client), now);
+    device.receive(requests.as_bytes(), now);
+    device.disconnect(now);
+    std::iter::from_fn(|| device.transmit())
+        .map(|bytes| String::from_utf8(bytes).unwrap())
+        .collect()
+}
+
+/// Token of the lease taken by the first request sent
+fn token(responses: &str) -> &str {
+    let lease = responses.lines().next().unwrap();
+    lease.rsplit_once(" TOKEN ").unwrap().1
+}
+
+#[test]
+fn lease_requests_parse_and_print_back() {
+    for line in ["LEASE", "LEASE EXCLUSIVE ci-1234 600", "LEASE SHARED host:4.2 1", "LEASE AS ci-1234 00C0FFEE0000BEEF",
+                 "LEASE RELEASE ci-1234"] {
+        let request = Request::try_from(format!("{}\n", line).as_bytes()).unwrap();
+        assert_eq!(request.to_string(), line);
+
+        let tagged = Tagged { tag: Some(3), inner: request };
+        let mut payload = Vec::new();
+        tagged.encode(&mut payload);
+        assert_eq!(Tagged::<Request>::decode(&payload).unwrap().inner, request);
+    }
+
+    for (line, error) in [
+        ("LEASE EXCLUSIVE ci-1234", ResponseError::BadValue), ("LEASE EXCLUSIVE ci-1234 0", ResponseError::BadValue),
+        ("LEASE SHARED ci 1.5", ResponseError::BadValue), ("LEASE SHARED c/i 10", ResponseError::BadValue),
+        ("LEASE AS", ResponseError::BadValue), ("LEASE AS ci-1234", ResponseError::BadValue),
+        ("LEASE AS ci-1234 C0FFEE!", ResponseError::BadValue), ("LEASE FOREVER ci 10", ResponseError::BadNoun),
+        ("LEASE AS thirty-three-characters-long-name", ResponseError::BadValue),
+    ] {
+        assert_eq!(Request::try_from(format!("{}\n", line).as_bytes()), Err(error), "{:?}", line);
+    }
+}
+
+#[test]
+fn lease_responses_decode_to_what_was_encoded() {
+    let status = Request::try_from(&b"LEASE\n"[..]).unwrap();
+    for line in ["OK NONE\r\n", "OK EXCLUSIVE ci-1 10\r\n", "OK SHARED ci-1 10,ci-2 3\r\n", "ERR LEASED\r\n",
+                 "OK EXCLUSIVE ci-1 10 TOKEN 00C0FFEE0000BEEF\r\n", "OK EXCLUSIVE TOKEN 10\r\n",
+                 "OK SHARED TOKEN 1,ci-2 3 TOKEN 00C0FFEE0000BEEF\r\n"] {
+        let response = Response::decode_line(&status, line.as_bytes()).unwrap();
+
+        let tagged = Tagged { tag: None, inner: response.clone() };
+        let mut payload = Vec::new();
+        tagged.encode(&mut payload);
+        assert_eq!(Vec::<u8>::from(Tagged::<Response>::decode(&payload).unwrap()), line.as_bytes());
+        assert_eq!(Vec::<u8>::from(response), line.as_bytes());
+    }
+}
+
+#[test]
+fn exclusive_lease_refuses_other_clients_until_it_expires() {
+    let mut device = Device::<256>::new(TestBox::new(1), ParseMode::Strict, None, false);
+
+    let taken = send(&mut device, 1, "LEASE EXCLUSIVE ci-1 10\nSET SERVO 10\n", secs(0));
+    let token = token(&taken);
+    assert_eq!(taken, format!("OK EXCLUSIVE ci-1 10 TOKEN {}\r\nOK 10\r\n", token));
+    assert_eq!(
+        send(&mut device, 2, "GET SERVO\n#5 LEASE SHARED ci-2 10\nLEASE\n", secs(1)),
+        "ERR LEASED\r\n#5 ERR LEASED\r\nOK EXCLUSIVE ci-1 9\r\n"
+    );
+    // The lease outlives the connection that took it, its holder can come back
+    let join = format!("LEASE AS ci-1 {}\nGET SERVO\n", token);
+    assert_eq!(send(&mut device, 3, &join, secs(2)), "OK EXCLUSIVE ci-1 8\r\nOK 10\r\n");
+    assert_eq!(send(&mut device, 4, "LEASE AS ci-2 1\nGET SERVO\n", secs(3)), "ERR BAD_VALUE\r\nERR LEASED\r\n");
+
+    assert_eq!(send(&mut device, 5, "SET SERVO 20\nLEASE\n", secs(10)), "OK 20\r\nOK NONE\r\n");
+}
+
+#[test]
+fn shared_leases_only_refuse_changes_from_other_clients() {
+    let mut device = Device::<256>::new(TestBox::new(1), ParseMode::Strict, None, false);
+
+    let taken = send(&mut device, 1, "LEASE SHARED ci-1 10\n", secs(0));
+    let ci_1 = token(&taken).to_owned();
+    assert_eq!(taken, format!("OK SHARED ci-1 10 TOKEN {}\r\n", ci_1));
+    let taken = send(&mut device, 2, "LEASE SHARED ci-2 5\nSET SERVO 10\n", secs(0));
+    let ci_2 = token(&taken).to_owned();
+    assert_eq!(taken, format!("OK SHARED ci-1 10,ci-2 5 TOKEN {}\r\nOK 10\r\n", ci_2));
+    assert_eq!(send(&mut device, 3, "GET SERVO\nSET SERVO 20\nSET RED_LED=1\n", secs(1)), "OK 10\r\nERR LEASED\r\nERR LEASED\r\n");
+    // Nobody takes an exclusive lease while others hold theirs
+    let exclusive = format!("LEASE AS ci-1 {}\nLEASE EXCLUSIVE ci-1 10\n", ci_1);
+    assert_eq!(send(&mut device, 1, &exclusive, secs(1)), "OK SHARED ci-1 9,ci-2 4\r\nERR LEASED\r\n");
+
+    let release = format!("LEASE AS ci-2 {}\nLEASE RELEASE ci-2\n", ci_2);
+    assert_eq!(send(&mut device, 2, &release, secs(1)), "OK SHARED ci-1 9,ci-2 4\r\nOK SHARED ci-1 9\r\n");
+    let exclusive = format!("LEASE AS ci-1 {}\nLEASE EXCLUSIVE ci-1 10\n", ci_1);
+    assert_eq!(
+        send(&mut device, 1, &exclusive, secs(1)),
+        format!("OK SHARED ci-1 9\r\nOK EXCLUSIVE ci-1 10 TOKEN {}\r\n", ci_1)
+    );
+}
+
+#[test]
+fn only_clients_with_the_token_act_for_a_holder() {
+    let mut device = Device::<256>::new(TestBox::new(1), ParseMode::Strict, None, false);
+    device.seed_leases(7);
+
+    let taken = send(&mut device, 1, "LEASE EXCLUSIVE ci-1 10\n", secs(0));
+    let token = token(&taken).to_owned();
+
+    // The holder name, as told by LEASE, is not enough
+    assert_eq!(
+        send(&mut device, 2, "LEASE\nLEASE AS ci-1 0\nLEASE RELEASE ci-1\nLEASE EXCLUSIVE ci-1 60\nSET SERVO 10\n", secs(1)),
+        "OK EXCLUSIVE ci-1 9\r\nERR BAD_VALUE\r\nERR LEASED\r\nERR LEASED\r\nERR LEASED\r\n"
+    );
+    // Not even by the client that took the lease, once it reconnected
+    assert_eq!(send(&mut device, 1, "LEASE RELEASE ci-1\n", secs(1)), "ERR LEASED\r\n");
+
+    let renew = format!("LEASE AS ci-1 {}\nLEASE EXCLUSIVE ci-1 60\nLEASE RELEASE ci-1\n", token);
+    assert_eq!(
+        send(&mut device, 3, &renew, secs(2)),
+        format!("OK EXCLUSIVE ci-1 8\r\nOK EXCLUSIVE ci-1 60 TOKEN {}\r\nOK NONE\r\n", token)
+    );
+}
+
+#[test]
+fn refused_framing_leaves_the_session_framing_alone() {
+    let mut device = Device::<256>::new(TestBox::new(1), ParseMode::Strict, None, false);
+    let taken = send(&mut device, 1, "LEASE EXCLUSIVE ci-1 10\n", secs(0));
+
+    // Plain lines are still understood, and answered in plain framing
+    assert_eq!(
+        send(&mut device, 2, "FRAMING CHECKSUM\nLEASE\n", secs(1)),
+        "ERR LEASED\r\nOK EXCLUSIVE ci-1 9\r\n"
+    );
+
+    // The holder switches right away, lines sent after the request are framed
+    let join = format!("LEASE AS ci-1 {}\nFRAMING CHECKSUM\nGET SERVO*2B\n", token(&taken));
+    assert_eq!(send(&mut device, 3, &join, secs(2)), "OK EXCLUSIVE ci-1 8\r\nOK CHECKSUM\r\nOK 90*2D\r\n");
+}
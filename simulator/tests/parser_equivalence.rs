@@ -0,0 +1,91 @@
+//! The hand-written request parser must classify every input exactly like
+//! the regex based parser it replaced.
+
+use std::convert::TryFrom;
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use simulator::parser::Request;
+
+mod common;
+use common::reference;
+
+fn check(data: &[u8]) {
+    assert_eq!(
+        Request::try_from(data), reference::parse(data),
+        "parsers disagree on {:?}", String::from_utf8_lossy(data)
+    );
+}
+
+#[test]
+fn known_lines() {
+    let lines: &[&[u8]] = &[
+        b"ID\n", b"ID\r\n", b"ID RED_LED\n", b"ID \n", b"ID  \n",
+        b"GET SERVO\n", b"GET SERVO\r\n", b"GET SERVO \n", b"GET  SERVO\n", b" GET SERVO\n",
+        b"GET SERVO 1\n", b"GET\n", b"GET FOO\n", b"get servo\n",
+        b"SET RED_LED 1000\n", b"SET RED_LED -100\n", b"SET RED_LED +5\n", b"SET RED_LED 12abc\n",
+        b"SET RED_LED 1 2\n", b"SET RED_LED  1\n", b"SET RED_LED\n", b"SET TEMP_AND_HUM 1\n",
+        b"SET RED_LED 99999999999999999999\n", b"SET SELF_TEST 1\n",
+        b"GET RED_LED,SERVO,TEMP_AND_HUM\n", b"GET RED_LED,RED_LED\n", b"GET RED_LED,\n",
+        b"SET RED_LED=10,GREEN_LED=1023\n", b"SET RED_LED=10,SERVO\n", b"SET RED_LED=\n",
+        b"SET RED_LED=1,TEMP_AND_HUM=2\n", b"SET RED_LED=1 5\n",
+        b"FRAMING CHECKSUM\n", b"FRAMING\n", b"FRAMING FOO\n", b"FRAMING PLAIN 1\n",
+        b"HELP\n", b"LIST\n", b"VERSION\n", b"HELP ME\n", b"DESCRIBE SERVO\n", b"DESCRIBE SERVO 1\n",
+        b"\n", b"\r\n", b" \n", b"", b"GET SERVO", b"GET SERVO\r", b"GET SERVO\r\r\n",
+        b"GET SERVO\rID\n", b"\nGET SERVO\n", b"GET SERVO\nID\n", b"GET \xff\n",
+        b"SET RED_LED \xff\n", b"GET \xc3\xa9\n", b"GET \xc3\n", b"\xe2\x82\xac \xff\n", b"\xff\xc3\xa9 X\n",
+        b"GET\rSERVO\n", b"GET \r SERVO 1\n", b"\xffGET SERVO\n", b"GET \xff SERVO\n", b"\xe2\x82GET\n",
+        b"GET \nID\n", b"GET SERVO \n", b"  \xc3\xa9\xc3\xa9 \n", b"X \r\n  Y\r\n", b"\xc3\xa9\x82 ID\n",
+    ];
+
+    for line in lines {
+        check(line);
+    }
+}
+
+#[test]
+fn combinations() {
+    let prefixes = ["", " ", "\n", "X\r"];
+    let verbs = ["ID", "GET", "SET", "FRAMING", "HELP", "LIST", "DESCRIBE", "VERSION", "FOO", ""];
+    let nouns = [
+        "", "RED_LED", "SERVO", "TEMP_AND_HUM", "SELF_TEST", "FOO", "CHECKSUM",
+        "RED_LED,SERVO", "RED_LED=1,SERVO=2", "RED_LED=x", ",", "=",
+    ];
+    let values = ["", "5", "-5", "abc", "12abc", "99999999999999999999", "1 2"];
+    let separators = [" ", "  ", ""];
+    let endings = ["\n", "\r\n", "\r", "", " \n", "\r\r\n"];
+
+    for prefix in prefixes {
+        for verb in verbs {
+            for noun in nouns {
+                for value in values {
+                    for sep in separators {
+                        for ending in endings {
+                            let mut line = format!("{}{}", prefix, verb);
+                            if !noun.is_empty() {
+                                line += &format!("{}{}", sep, noun);
+                            }
+                            if !value.is_empty() {
+                                line += &format!("{}{}", sep, value);
+                            }
+                            line += ending;
+                            check(line.as_bytes());
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn random_lines() {
+    const ALPHABET: &[u8] = b"GETSIDRL_AOVNUX ,=019-\r\n\xff\xc3\xa9";
+
+    let mut rng = StdRng::seed_from_u64(0x7e57b0c5);
+
+    for _ in 0..100000 {
+        let len = rng.gen_range(0..32);
+        let line: Vec<u8> = (0..len).map(|_| ALPHABET[rng.gen_range(0..ALPHABET.len())]).collect();
+        check(&line);
+    }
+}
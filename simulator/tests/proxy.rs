@@ -0,0 +1,109 @@
+//! Decoding responses, and the faults the proxy injects into them.
+
+use std::{convert::TryFrom, time::Duration};
+
+use simulator::{
+    parser::{Request, Response, Tagged},
+    proxy::{decode_request, Faults, Rule},
+    testbox::TestBox,
+};
+
+fn apply(rules: &[&str], request: &str, response: &str) -> (Option<String>, Duration) {
+    let rules = rules.iter().map(|r| r.parse().unwrap()).collect();
+    let faulted = Faults::new(rules, 1).apply(&decode_request(request.as_bytes()), response.into());
+    (faulted.response.map(|r| String::from_utf8(r).unwrap()), faulted.delay)
+}
+
+#[test]
+fn responses_decode_to_what_was_encoded() {
+    let mut tbox = TestBox::new(1);
+    let lines = [
+        "ID\n", "GET SERVO\n", "SET RED_LED 5\n", "SET SERVO 500\n", "GET TEMP_AND_HUM\n", "GET SELF_TEST\n",
+        "GET RED_LED,TEMP_AND_HUM,SELF_TEST\n", "SET RED_LED=10,SERVO=20\n", "FRAMING PLAIN\n",
+        "HELP\n", "LIST\n", "DESCRIBE SERVO\n", "DESCRIBE TEMP_AND_HUM\n", "VERSION\n",
+    ];
+
+    for line in lines {
+        let request = Tagged::<Request>::try_from(format!("#3 {}", line).as_bytes()).unwrap();
+        let encoded: Vec<u8> = Tagged { tag: request.tag, inner: tbox.handle(request.inner, Duration::ZERO) }.into();
+
+        let decoded = Tagged::<Response>::decode_line(&request.inner, &encoded)
+            .unwrap_or_else(|| panic!("failed to decode {:?}", String::from_utf8_lossy(&encoded)));
+        assert_eq!(Vec::<u8>::from(decoded), encoded);
+    }
+}
+
+#[test]
+fn requests_print_back_as_their_line() {
+    for line in ["ID", "GET SERVO", "SET RED_LED -5", "GET RED_LED,TEMP_AND_HUM", "SET RED_LED=10,SERVO=20",
+                 "FRAMING CHECKSUM", "HELP", "LIST", "DESCRIBE SELF_TEST", "VERSION"] {
+        assert_eq!(Request::try_from(format!("{}\n", line).as_bytes()).unwrap().to_string(), line);
+    }
+}
+
+#[test]
+fn responses_that_dont_answer_the_request_are_not_decoded() {
+    let get = |noun: &str| Request::try_from(format!("GET {}\n", noun).as_bytes()).unwrap();
+
+    assert!(Response::decode_line(&get("SERVO"), b"OK ACTIVE 10\r\n").is_none());
+    assert!(Response::decode_line(&get("TEMP_AND_HUM"), b"OK 10\r\n").is_none());
+    assert!(Response::decode_line(&get("RED_LED,SERVO"), b"OK 1\r\n").is_none());
+    assert!(Response::decode_line(&get("SERVO"), b"ERR BUSY\r\n").is_none());
+    assert!(Response::decode_line(&get("SERVO"), b"Failed to find noun\r\n").is_none());
+}
+
+#[test]
+fn rules_parse_and_print_back() {
+    for rule in ["delay 200", "GET SERVO delay 200", "SET error BAD_VALUE 10%", "TEMP_AND_HUM corrupt CHECKSUM",
+                 "GET corrupt 85 99.9", "ID drop 50%"] {
+        assert_eq!(rule.parse::<Rule>().unwrap().to_string(), rule);
+    }
+
+    for rule in ["", "GET SERVO", "delay", "delay soon", "error bad", "SERVO corrupt CHECKSUM",
+                 "corrupt BROKEN", "drop now", "drop 101%", "explode"] {
+        assert!(rule.parse::<Rule>().is_err(), "{:?} was accepted", rule);
+    }
+}
+
+#[test]
+fn rules_apply_to_matching_requests_only() {
+    let rules = ["GET SERVO delay 200", "SET error BUSY"];
+
+    assert_eq!(apply(&rules, "GET SERVO\n", "OK 90\r\n"), (Some("OK 90\r\n".into()), Duration::from_millis(200)));
+    assert_eq!(apply(&rules, "GET RED_LED\n", "OK 0\r\n"), (Some("OK 0\r\n".into()), Duration::ZERO));
+    assert_eq!(apply(&rules, "#12 SET RED_LED 1\n", "#12 OK 1\r\n"), (Some("#12 ERR BUSY\r\n".into()), Duration::ZERO));
+    assert_eq!(apply(&["drop"], "GET FOO\n", "ERR BAD_NOUN\r\n"), (None, Duration::ZERO));
+    assert_eq!(apply(&["GET drop"], "GET FOO\n", "ERR BAD_NOUN\r\n"), (Some("ERR BAD_NOUN\r\n".into()), Duration::ZERO));
+}
+
+#[test]
+fn sensor_readings_are_corrupted_in_place() {
+    assert_eq!(
+        apply(&["corrupt TIMEOUT"], "GET TEMP_AND_HUM\n", "OK OK 21.50 40.00\r\n").0.unwrap(),
+        "OK TIMEOUT 0.00 0.00\r\n"
+    );
+    assert_eq!(
+        apply(&["TEMP_AND_HUM corrupt 85 99.9"], "#4 GET SERVO,TEMP_AND_HUM\n", "#4 OK 90,OK 21.50 40.00\r\n").0.unwrap(),
+        "#4 OK 90,OK 85.00 99.90\r\n"
+    );
+    // Diagnostics before the response are kept, and other responses are left alone
+    assert_eq!(
+        apply(&["corrupt CHECKSUM"], "GET TEMP_AND_HUM\n", "Reading sensor\r\nOK OK 21.50 40.00\r\n").0.unwrap(),
+        "Reading sensor\r\nOK CHECKSUM 0.00 0.00\r\n"
+    );
+    assert_eq!(apply(&["corrupt CHECKSUM"], "GET SERVO\n", "OK 90\r\n").0.unwrap(), "OK 90\r\n");
+}
+
+#[test]
+fn chances_are_reproducible_with_the_seed() {
+    let run = |seed| {
+        let mut faults = Faults::new(vec!["error BUSY 30%".parse().unwrap()], seed);
+        (0..100)
+            .map(|_| faults.apply(&decode_request(b"GET SERVO\n"), b"OK 90\r\n".to_vec()).applied.len())
+            .sum::<usize>()
+    };
+
+    let hits = run(7);
+    assert!((15..=45).contains(&hits), "{} hits out of 100", hits);
+    assert_eq!(run(7), hits);
+}
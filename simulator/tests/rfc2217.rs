@@ -0,0 +1,121 @@
+//! Telnet and RFC 2217 negotiation, and how the serial settings affect the device.
+
+use std::time::Duration;
+
+use simulator::{
+    device::{Device, Input},
+    parser::ParseMode,
+    rfc2217::Telnet,
+    testbox::TestBox,
+    uart::{self, LineSettings, Parity},
+};
+
+const IAC: u8 = 255;
+const SB: u8 = 250;
+const SE: u8 = 240;
+const WILL: u8 = 251;
+const WONT: u8 = 252;
+const DO: u8 = 253;
+
+fn subnegotiation(command: u8, value: &[u8]) -> Vec<u8> {
+    [&[IAC, SB, 44, command][..], value, &[IAC, SE]].concat()
+}
+
+fn transmitted(device: &mut Device<256>) -> Vec<u8> {
+    std::iter::from_fn(|| device.transmit()).flatten().collect()
+}
+
+#[test]
+fn negotiates_binary_and_com_port_control_only() {
+    let mut telnet = Telnet::new();
+
+    // Like pyserial: WILL/DO BINARY, WILL/DO SGA, WILL COM-PORT-OPTION, DO ECHO
+    let inputs = telnet.receive(&[IAC, WILL, 0, IAC, DO, 0, IAC, WILL, 3, IAC, DO, 3, IAC, WILL, 44, IAC, DO, 1]);
+    assert!(inputs.is_empty());
+    assert_eq!(telnet.replies(), [IAC, DO, 0, IAC, WILL, 0, IAC, DO, 3, IAC, WILL, 3, IAC, DO, 44, IAC, WONT, 1]);
+
+    // Requests that change nothing are not answered again
+    telnet.receive(&[IAC, WILL, 0, IAC, DO, 0]);
+    assert_eq!(telnet.replies(), []);
+}
+
+#[test]
+fn acknowledges_settings_and_passes_them_on() {
+    let mut telnet = Telnet::new();
+
+    let mut request = subnegotiation(1, &9600u32.to_be_bytes());
+    request.extend(subnegotiation(3, &[3]));
+    request.extend(b"ID\n");
+    request.extend(subnegotiation(5, &[9]));
+
+    let line = LineSettings { baud_rate: 9600, parity: Parity::Even, ..LineSettings::BOARD };
+    assert_eq!(telnet.receive(&request), [
+        Input::Line(LineSettings { baud_rate: 9600, ..LineSettings::BOARD }),
+        Input::Line(line),
+        Input::Bytes(b"ID\n".to_vec()),
+        Input::Modem { dtr: false, rts: true },
+    ]);
+
+    let mut replies = subnegotiation(101, &9600u32.to_be_bytes());
+    replies.extend(subnegotiation(103, &[3]));
+    replies.extend(subnegotiation(105, &[9]));
+    assert_eq!(telnet.replies(), replies);
+}
+
+#[test]
+fn refuses_flow_control() {
+    let mut telnet = Telnet::new();
+
+    // XON/XOFF, hardware, DSR, inbound XON/XOFF, inbound hardware, then asking for the settings
+    for value in [2, 3, 19, 15, 16, 0, 13, 1, 14] {
+        assert_eq!(telnet.receive(&subnegotiation(5, &[value])), []);
+    }
+    let replies: Vec<u8> = [1, 1, 1, 14, 14, 1, 14, 1, 14].into_iter()
+        .flat_map(|value| subnegotiation(105, &[value]))
+        .collect();
+    assert_eq!(telnet.replies(), replies);
+}
+
+#[test]
+fn escapes_iac_in_data() {
+    let mut telnet = Telnet::new();
+    assert_eq!(telnet.receive(&[b'A', IAC, IAC, b'B']), [Input::Bytes(vec![b'A', IAC, b'B'])]);
+    assert_eq!(Telnet::escape(&[b'A', IAC, b'B']), [b'A', IAC, IAC, b'B']);
+}
+
+#[test]
+fn mismatched_settings_garble_bytes() {
+    let slow = LineSettings { baud_rate: 9600, ..LineSettings::BOARD };
+    let seven_bits = LineSettings { data_bits: 7, parity: Parity::Even, ..LineSettings::BOARD };
+
+    assert_eq!(uart::transcode(b"GET SERVO\n", LineSettings::BOARD, LineSettings::BOARD), b"GET SERVO\n");
+    assert_ne!(uart::transcode(b"GET SERVO\n", slow, LineSettings::BOARD), b"GET SERVO\n");
+    assert_ne!(uart::transcode(b"GET SERVO\n", seven_bits, LineSettings::BOARD), b"GET SERVO\n");
+
+    let mut device = Device::<256>::new(TestBox::new(1), ParseMode::Strict, None, false);
+    device.input(Input::Bytes(b"ID\n".to_vec()), Duration::ZERO);
+    device.input(Input::Line(slow), Duration::ZERO);
+    device.input(Input::Bytes(b"SET SERVO 10\n".to_vec()), Duration::ZERO);
+    device.input(Input::Line(LineSettings::BOARD), Duration::ZERO);
+    device.input(Input::Bytes(b"\nGET SERVO\n".to_vec()), Duration::ZERO);
+
+    let transmitted = transmitted(&mut device);
+    assert!(transmitted.starts_with(b"OK ESP8266_WEMOS_D1MINI\r\n"));
+    // The servo was not set
+    assert!(transmitted.ends_with(b"OK 90\r\n"));
+}
+
+#[test]
+fn dtr_toggle_resets_the_board() {
+    let mut device = Device::<256>::new(TestBox::new(1), ParseMode::Strict, None, false);
+    device.receive(b"SET SERVO 10\nSET RED_LED 5\n", Duration::ZERO);
+    transmitted(&mut device);
+
+    device.input(Input::Modem { dtr: false, rts: true }, Duration::from_secs(1));
+    device.input(Input::Modem { dtr: true, rts: true }, Duration::from_secs(1));
+    // Boot ROM output, at the wrong baud rate for the host
+    assert!(!transmitted(&mut device).is_empty());
+
+    device.receive(b"GET SERVO\nGET RED_LED\n", Duration::from_secs(1));
+    assert_eq!(transmitted(&mut device), b"OK 90\r\nOK 0\r\n");
+}
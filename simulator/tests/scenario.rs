@@ -0,0 +1,84 @@
+//! Scenario files, played by the device on virtual time.
+
+use std::time::Duration;
+
+use simulator::{device::Device, parser::ParseMode, scenario::{Action, Scenario, Step}, testbox::{SensorFault, TestBox}};
+
+const HEAT_WAVE: &str = "
+    # Heat wave, the sensor drops out and the board reboots
+    0s    temperature 22
+    0s    temperature 35 over 1m
+    30s   fault TIMEOUT for 3 reads   # a loose wire
+    45s   reboot
+";
+
+fn secs(s: u64) -> Duration {
+    Duration::from_secs(s)
+}
+
+fn transmitted(device: &mut Device<256>) -> String {
+    std::iter::from_fn(|| device.transmit())
+        .map(|bytes| String::from_utf8_lossy(&bytes).into_owned())
+        .collect()
+}
+
+/// Lets the device run until `now`, then sends it a request
+fn request(device: &mut Device<256>, line: &str, now: Duration) -> String {
+    device.advance(now);
+    transmitted(device);
+    device.receive(format!("{}\n", line).as_bytes(), now);
+    transmitted(device)
+}
+
+#[test]
+fn steps_parse_and_print_back() {
+    let scenario: Scenario = HEAT_WAVE.parse().unwrap();
+    assert_eq!(scenario.steps, [
+        Step { at: secs(0), action: Action::Temperature(22.0, Duration::ZERO) },
+        Step { at: secs(0), action: Action::Temperature(35.0, secs(60)) },
+        Step { at: secs(30), action: Action::Fault(Some(SensorFault::Timeout), Some(3)) },
+        Step { at: secs(45), action: Action::Reboot },
+    ]);
+
+    for line in ["0.5s humidity 80 over 90s", "2s fault CHECKSUM", "3s fault none", "4s fault TIMEOUT for 1 read", "120s reboot"] {
+        let step: Step = line.parse().unwrap();
+        assert_eq!(step.to_string(), line);
+    }
+    assert_eq!("500ms humidity 60".parse::<Step>().unwrap().at, Duration::from_millis(500));
+    assert_eq!("90 reboot".parse::<Step>().unwrap().at, secs(90));
+
+    for (text, error) in [
+        ("10s rain", "line 1: invalid action 'rain'"),
+        ("\n10h reboot", "line 2: invalid time '10h'"),
+        ("5s humidity 120", "line 1: humidity 120% is not between 0% and 100%"),
+        ("5s fault TIMEOUT for 0 reads", "line 1: invalid number of reads '0'"),
+        ("5s fault LOOSE", "line 1: invalid sensor fault 'LOOSE'"),
+        ("5s reboot\n1s reboot", "line 2: steps must be in time order"),
+    ] {
+        let e = text.parse::<Scenario>().unwrap_err();
+        assert!(e.starts_with(error), "{:?} gave {:?}", text, e);
+    }
+}
+
+#[test]
+fn device_plays_the_world_on_its_clock() {
+    let mut device = Device::new(TestBox::new(1), ParseMode::Strict, None, false);
+    device.play(HEAT_WAVE.parse().unwrap(), Duration::ZERO);
+
+    // The sensor is read every 2 s, halfway through the ramp at 30 s
+    assert_eq!(request(&mut device, "GET TEMP_AND_HUM", secs(2)), "OK OK 22.43 50.00\r\n");
+    assert_eq!(request(&mut device, "SET SERVO 10", secs(29)), "OK 10\r\n");
+    assert_eq!(request(&mut device, "GET TEMP_AND_HUM", secs(30)), "OK TIMEOUT 0.00 0.00\r\n");
+    assert_eq!(request(&mut device, "GET TEMP_AND_HUM", secs(35)), "OK TIMEOUT 0.00 0.00\r\n");
+    assert_eq!(request(&mut device, "GET TEMP_AND_HUM", secs(36)), "OK OK 29.80 50.00\r\n");
+    assert!(!device.scenario().unwrap().finished());
+
+    // The reboot starts the board over, the world goes on
+    device.advance(secs(45));
+    // The boot ROM message, garbled at the host's baud rate
+    assert!(!transmitted(&mut device).is_empty());
+    assert!(device.scenario().unwrap().finished());
+    assert_eq!(request(&mut device, "GET SERVO", secs(46)), "OK 90\r\n");
+    assert_eq!(request(&mut device, "GET TEMP_AND_HUM", secs(47)), "OK OK 32.18 50.00\r\n");
+    assert_eq!(request(&mut device, "GET TEMP_AND_HUM", secs(100)), "OK OK 35.00 50.00\r\n");
+}
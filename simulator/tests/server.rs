@@ -0,0 +1,107 @@
+//! Runs the server and device tasks over real sockets.
+
+#![cfg(unix)]
+
+use std::{path::PathBuf, time::Duration};
+
+use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::UnixStream, sync::{mpsc, watch}};
+
+use simulator::{device::{self, Device}, listener::Address, parser::ParseMode, server, testbox::TestBox};
+
+fn socket_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("simulator-{}-{}.sock", name, std::process::id()))
+}
+
+#[tokio::test]
+async fn unix_socket_serves_clients_and_is_removed_on_shutdown() {
+    let path = socket_path("serve");
+    let address = Address::Unix { path: path.clone(), mode: Some(0o600) };
+    let listener = address.bind().await.unwrap();
+
+    let (incoming_tx, incoming_rx) = mpsc::channel(10);
+    let (outgoing_tx, outgoing_rx) = mpsc::channel(10);
+    let (state_tx, mut state_rx) = mpsc::channel(10);
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+
+    let device = Device::<256>::new(TestBox::new(1), ParseMode::Strict, None, false);
+    let server = tokio::spawn(server::server::<256>(
+        listener, server::Protocol::Raw, server::Timeouts::default(), incoming_tx, outgoing_rx, shutdown_rx.clone()
+    ));
+    let device = tokio::spawn(device::device(device, incoming_rx, outgoing_tx, state_tx, None, shutdown_rx));
+    tokio::spawn(async move { while state_rx.recv().await.is_some() {} });
+
+    // A second simulator can't take over a socket in use
+    assert!(address.bind().await.is_err());
+
+    let mut client = UnixStream::connect(&path).await.unwrap();
+    client.write_all(b"SET SERVO 10\nGET SERVO\n").await.unwrap();
+    client.shutdown().await.unwrap();
+    let mut responses = String::new();
+    client.read_to_string(&mut responses).await.unwrap();
+    assert_eq!(responses, "OK 10\r\nOK 10\r\n");
+
+    shutdown_tx.send_replace(true);
+    tokio::time::timeout(Duration::from_secs(5), async {
+        server.await.unwrap().unwrap();
+        device.await.unwrap().unwrap();
+    }).await.unwrap();
+
+    assert!(!path.exists());
+}
+
+#[tokio::test]
+async fn stale_unix_socket_is_replaced() {
+    let path = socket_path("stale");
+    drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+    assert!(path.exists());
+
+    let listener = Address::Unix { path: path.clone(), mode: None }.bind().await.unwrap();
+    assert!(UnixStream::connect(&path).await.is_ok());
+
+    drop(listener);
+    assert!(!path.exists());
+}
+
+async fn exchange(path: &PathBuf, requests: &str) -> String {
+    let mut client = UnixStream::connect(path).await.unwrap();
+    client.write_all(requests.as_bytes()).await.unwrap();
+    client.shutdown().await.unwrap();
+    let mut responses = String::new();
+    client.read_to_string(&mut responses).await.unwrap();
+    responses
+}
+
+#[tokio::test]
+async fn leases_tell_connections_apart() {
+    let path = socket_path("lease");
+    let listener = Address::Unix { path: path.clone(), mode: None }.bind().await.unwrap();
+
+    let (incoming_tx, incoming_rx) = mpsc::channel(10);
+    let (outgoing_tx, outgoing_rx) = mpsc::channel(10);
+    let (state_tx, mut state_rx) = mpsc::channel(10);
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+
+    let device = Device::<256>::new(TestBox::new(1), ParseMode::Strict, None, false);
+    let server = tokio::spawn(server::server::<256>(
+        listener, server::Protocol::Raw, server::Timeouts::default(), incoming_tx, outgoing_rx, shutdown_rx.clone()
+    ));
+    let device = tokio::spawn(device::device(device, incoming_rx, outgoing_tx, state_tx, None, shutdown_rx));
+    tokio::spawn(async move { while state_rx.recv().await.is_some() {} });
+
+    let taken = exchange(&path, "LEASE EXCLUSIVE ci-1 60\nSET SERVO 10\n").await;
+    let (lease, set) = taken.split_once("\r\n").unwrap();
+    let token = lease.strip_prefix("OK EXCLUSIVE ci-1 60 TOKEN ").unwrap();
+    assert_eq!(set, "OK 10\r\n");
+
+    assert_eq!(exchange(&path, "SET SERVO 20\n").await, "ERR LEASED\r\n");
+    assert_eq!(
+        exchange(&path, &format!("LEASE AS ci-1 {}\nGET SERVO\n", token)).await,
+        "OK EXCLUSIVE ci-1 60\r\nOK 10\r\n"
+    );
+
+    shutdown_tx.send_replace(true);
+    tokio::time::timeout(Duration::from_secs(5), async {
+        server.await.unwrap().unwrap();
+        device.await.unwrap().unwrap();
+    }).await.unwrap();
+}
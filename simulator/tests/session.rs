@@ -0,0 +1,177 @@
+//! Line buffering and parsing of a session, byte by byte.
+
+use std::time::Duration;
+
+use simulator::{parser::{ParseMode, Response, Session}, testbox::TestBox};
+
+/// Number of bytes of a line without terminator a session takes before handling it
+fn max_line_len<const LEN: usize>(mode: ParseMode) -> usize {
+    let mut session = Session::<LEN>::new(mode);
+    (1..=LEN).find(|_| session.push(b'X').is_some()).expect("the line was never handled")
+}
+
+#[test]
+fn maximum_line_length_depends_on_the_mode() {
+    // The firmware keeps the last byte of its buffer for the string terminator
+    assert_eq!(max_line_len::<256>(ParseMode::Firmware), 255);
+    assert_eq!(max_line_len::<256>(ParseMode::Strict), 256);
+    assert_eq!(max_line_len::<16>(ParseMode::Firmware), 15);
+    assert_eq!(max_line_len::<16>(ParseMode::Strict), 16);
+}
+
+/// Feeds a line to a session, returns everything sent back
+fn exchange(session: &mut Session<256>, tbox: &mut TestBox, line: &str) -> String {
+    let mut out = Vec::new();
+    for &c in line.as_bytes() {
+        if let Some(request) = session.push(c) {
+            let response = request.map(|r| match r {
+                Ok(r) => tbox.handle(r, Duration::ZERO),
+                Err(e) => Response::Error(e),
+            });
+            out.extend(session.encode(response));
+        }
+    }
+    String::from_utf8(out).unwrap()
+}
+
+/// Answers each line on a freshly booted board
+fn check_each(table: &[(&str, &str)]) {
+    for (line, expected) in table {
+        let mut session = Session::new(ParseMode::Firmware);
+        let mut tbox = TestBox::new(0);
+        assert_eq!(exchange(&mut session, &mut tbox, line), *expected, "answer to {:?}", line);
+    }
+}
+
+#[test]
+fn tokens_are_split_like_strtok() {
+    // testbox.ino:296, 313, 331: strtok(" \r\n") skips any number of delimiters,
+    // and a line without tokens leaves VERB_EMPTY (299-301), answered at 384-386
+    check_each(&[
+        ("GET SERVO\n", "OK 90\r\n"),
+        ("  GET   SERVO  \n", "OK 90\r\n"),
+        ("GET SERVO\r\r\n", "OK 90\r\n"),
+        ("SET  SERVO   45\n", "OK 45\r\n"),
+        ("\n", "ERR BAD_VERB\r\n"),
+        ("  \r\n", "ERR BAD_VERB\r\n"),
+        // Tabs are no delimiter, the verb is "GET\tSERVO" (304-307)
+        ("GET\tSERVO\n", "ERR BAD_VERB\r\n"),
+        // Verbs and nouns are looked up as they are (280-293)
+        ("get SERVO\n", "ERR BAD_VERB\r\n"),
+    ]);
+}
+
+#[test]
+fn values_are_parsed_like_strtol() {
+    check_each(&[
+        // testbox.ino:338-344: strtol takes a sign and stops at the first non-digit
+        ("SET RED_LED +5\n", "OK 5\r\n"),
+        ("SET RED_LED 12abc\n", "OK 12\r\n"),
+        ("SET RED_LED 0x10\n", "OK 0\r\n"),
+        ("SET RED_LED abc\n", "ERR BAD_VALUE\r\n"),
+        ("SET RED_LED -\n", "ERR BAD_VALUE\r\n"),
+        // ClampedInt::set, testbox.ino:32, clamps to the range of the output
+        ("SET RED_LED -5\n", "OK 0\r\n"),
+        ("SET SERVO 500\n", "OK 180\r\n"),
+        // strtol saturates to the 32-bit LONG_MAX and LONG_MIN instead of wrapping
+        ("SET RED_LED 99999999999999999999\n", "OK 1023\r\n"),
+        ("SET SERVO -4294967206\n", "OK 0\r\n"),
+        ("SET SELF_TEST 4294967296\n", "ERR BAD_VALUE\r\n"),
+        // testbox.ino:347-350: more tokens make the value empty again, which
+        // SET refuses (451) and GET wants (401)
+        ("SET RED_LED 1 2\n", "ERR BAD_VALUE\r\n"),
+        ("GET SERVO 1\n", "ERR BAD_VALUE\r\n"),
+        ("GET SERVO 1 2\n", "OK 90\r\n"),
+    ]);
+}
+
+#[test]
+fn request_fields_carry_over_between_lines() {
+    // testbox.ino:517: the request is a static, and parse_into returns as soon
+    // as a token is missing (314-317, 332-335), keeping the fields of older lines
+    let mut session = Session::new(ParseMode::Firmware);
+    let mut tbox = TestBox::new(0);
+    for (line, expected) in [
+        ("SET RED_LED 5\n", "OK 5\r\n"),
+        // VALUE_OK is left over, GET wants no value (401-404)
+        ("GET\n", "ERR BAD_VALUE\r\n"),
+        // The noun is now empty, the value still OK (451, 501-503)
+        ("SET\n", "ERR BAD_NOUN\r\n"),
+        // An unknown noun returns before the value is parsed (319-326)
+        ("GET FOO\n", "Failed to find noun [FOO]\r\nERR BAD_VALUE\r\n"),
+        // An unknown verb leaves everything else (305-308)
+        ("FOO\n", "ERR BAD_VERB\r\n"),
+        ("GET SERVO\n", "OK 90\r\n"),
+        // GET SERVO left the value empty
+        ("SET\n", "ERR BAD_VALUE\r\n"),
+        ("SET GREEN_LED x\n", "ERR BAD_VALUE\r\n"),
+        ("SET\n", "ERR BAD_VALUE\r\n"),
+        ("GET RED_LED\n", "OK 5\r\n"),
+    ] {
+        assert_eq!(exchange(&mut session, &mut tbox, line), expected, "answer to {:?}", line);
+    }
+}
+
+#[test]
+fn unknown_nouns_are_reported_before_the_error() {
+    // testbox.ino:321-323 prints the diagnostic, NOUN_INVALID is then refused
+    // by handle_request (391-392, 443-444, 501-502)
+    check_each(&[
+        ("GET FOO\n", "Failed to find noun [FOO]\r\nERR BAD_NOUN\r\n"),
+        ("GET red_led\n", "Failed to find noun [red_led]\r\nERR BAD_NOUN\r\n"),
+        ("ID FOO\n", "Failed to find noun [FOO]\r\nERR BAD_NOUN\r\n"),
+        // The value is never parsed, and was empty since boot (237)
+        ("SET FOO 1\n", "Failed to find noun [FOO]\r\nERR BAD_VALUE\r\n"),
+        ("GET RED_LED,SERVO\n", "Failed to find noun [RED_LED,SERVO]\r\nERR BAD_NOUN\r\n"),
+        // The verb is checked first
+        ("FOO BAR\n", "ERR BAD_VERB\r\n"),
+    ]);
+}
+
+#[test]
+fn tags_are_echoed_on_responses() {
+    let mut session = Session::new(ParseMode::Strict);
+    let mut tbox = TestBox::new(0);
+    for (line, expected) in [
+        ("#17 GET SERVO\n", "#17 OK 90\r\n"),
+        ("#18 GET FOO\n", "#18 ERR BAD_NOUN\r\n"),
+        ("#0 SET RED_LED 5\n#4294967295 GET RED_LED\n", "#0 OK 5\r\n#4294967295 OK 5\r\n"),
+        ("GET RED_LED\n", "OK 5\r\n"),
+        ("#x GET SERVO\n", "ERR BAD_SYNTAX\r\n"),
+        ("#4294967296 GET SERVO\n", "ERR BAD_SYNTAX\r\n"),
+        ("# GET SERVO\n", "ERR BAD_SYNTAX\r\n"),
+    ] {
+        assert_eq!(exchange(&mut session, &mut tbox, line), expected, "answer to {:?}", line);
+    }
+
+    // The firmware has no tags
+    check_each(&[("#17 GET SERVO\n", "ERR BAD_VERB\r\n")]);
+}
+
+/// Appends the checksum of checksummed framing to a line
+fn checksummed(line: &str) -> String {
+    format!("{}*{:02X}\n", line, line.bytes().fold(0, |sum, c| sum ^ c))
+}
+
+#[test]
+fn checksummed_lines_are_checked() {
+    let mut session = Session::new(ParseMode::Strict);
+    let mut tbox = TestBox::new(0);
+    for (line, expected) in [
+        ("FRAMING CHECKSUM\n".to_owned(), "OK CHECKSUM\r\n"),
+        ("GET SERVO*2B\n".to_owned(), "OK 90*2D\r\n"),
+        (checksummed("SET RED_LED 1000"), "OK 1000*25\r\n"),
+        // One bit flipped on the way
+        (checksummed("SET RED_LED 1000").replace("1000", "1001"), "ERR BAD_CHECKSUM*70\r\n"),
+        ("GET SERVO*00\n".to_owned(), "ERR BAD_CHECKSUM*70\r\n"),
+        ("GET SERVO*2b\n".to_owned(), "OK 90*2D\r\n"),
+        ("GET SERVO\n".to_owned(), "ERR BAD_CHECKSUM*70\r\n"),
+        ("GET SERVO*2\n".to_owned(), "ERR BAD_CHECKSUM*70\r\n"),
+        // Lines with a bad checksum are not acted on
+        (checksummed("GET RED_LED"), "OK 1000*25\r\n"),
+        ("FRAMING PLAIN*22\n".to_owned(), "OK PLAIN*7E\r\n"),
+        ("GET SERVO\n".to_owned(), "OK 90\r\n"),
+    ] {
+        assert_eq!(exchange(&mut session, &mut tbox, &line), expected, "answer to {:?}", line);
+    }
+}
@@ -0,0 +1,76 @@
+//! Comparing a board's responses with those of the shadow simulator.
+
+use std::time::Duration;
+
+use simulator::{
+    device::Device,
+    parser::ParseMode,
+    shadow::{Difference, Shadow, Tolerance},
+    testbox::TestBox,
+};
+
+fn shadow(tolerances: &[&str]) -> Shadow<256> {
+    let tolerances: Vec<Tolerance> = tolerances.iter().map(|t| t.parse().unwrap()).collect();
+    Shadow::new(Device::new(TestBox::new(1), ParseMode::Strict, None, false), &tolerances)
+}
+
+fn difference(shadow: &mut Shadow<256>, request: &str, hardware: &str) -> Option<Difference> {
+    shadow.compare(request.as_bytes(), hardware.as_bytes(), Duration::ZERO).map(|d| d.difference)
+}
+
+#[test]
+fn same_answers_dont_diverge() {
+    let mut shadow = shadow(&[]);
+
+    assert_eq!(difference(&mut shadow, "GET SERVO\n", "OK 90\r\n"), None);
+    assert_eq!(difference(&mut shadow, "#5 SET SERVO 200\n", "#5 OK 180\r\n"), None);
+    assert_eq!(difference(&mut shadow, "GET FOO\n", "ERR BAD_NOUN\r\n"), None);
+    // Sensor readings differ between any two boards
+    assert_eq!(difference(&mut shadow, "GET TEMP_AND_HUM\n", "OK OK 23.10 61.00\r\n"), None);
+    assert_eq!(difference(&mut shadow, "GET RED_LED,TEMP_AND_HUM\n", "OK 0,OK 23.10 61.00\r\n"), None);
+    assert_eq!(shadow.counts(), (5, 0));
+}
+
+#[test]
+fn divergences_are_classified() {
+    let mut shadow = shadow(&[]);
+
+    assert_eq!(difference(&mut shadow, "GET SERVO\n", "OK 91\r\n"), Some(Difference::Value));
+    assert_eq!(difference(&mut shadow, "SET RED_LED 12abc\n", "OK 12\r\n"), Some(Difference::Status));
+    assert_eq!(difference(&mut shadow, "VERSION\n", "ERR BAD_VERB\r\n"), Some(Difference::Status));
+    assert_eq!(difference(&mut shadow, "GET FOO\n", "ERR BAD_VERB\r\n"), Some(Difference::ErrorCode));
+    assert_eq!(difference(&mut shadow, "GET FOO\n", "ERR BUSY\r\n"), Some(Difference::ErrorCode));
+    assert_eq!(
+        difference(&mut shadow, "GET FOO\n", "Failed to find noun [FOO]\r\nERR BAD_NOUN\r\n"),
+        Some(Difference::Diagnostics)
+    );
+    // The sensor status is still compared
+    assert_eq!(difference(&mut shadow, "GET TEMP_AND_HUM\n", "OK TIMEOUT 0.00 0.00\r\n"), Some(Difference::Value));
+    assert_eq!(shadow.counts(), (7, 7));
+}
+
+#[test]
+fn tolerances_allow_values_to_differ() {
+    let mut shadow = shadow(&["SERVO 2", "TEMP_AND_HUM 1.5"]);
+
+    assert_eq!(difference(&mut shadow, "GET SERVO\n", "OK 92\r\n"), None);
+    assert_eq!(difference(&mut shadow, "GET SERVO\n", "OK 93\r\n"), Some(Difference::Value));
+    assert_eq!(difference(&mut shadow, "GET RED_LED\n", "OK 1\r\n"), Some(Difference::Value));
+
+    // Overrides the default of any reading, from the shadow's first one
+    let reading = Device::<256>::new(TestBox::new(1), ParseMode::Strict, None, false).testbox().get().sensor;
+    let close = format!("OK OK {:.2} {:.2}\r\n", reading.temperature + 1.0, reading.humidity - 1.0);
+    let far = format!("OK OK {:.2} {:.2}\r\n", reading.temperature + 2.0, reading.humidity);
+    assert_eq!(difference(&mut shadow, "GET TEMP_AND_HUM\n", &close), None);
+    assert_eq!(difference(&mut shadow, "GET TEMP_AND_HUM\n", &far), Some(Difference::Value));
+}
+
+#[test]
+fn tolerances_parse_and_print_back() {
+    for tolerance in ["SERVO 2", "TEMP_AND_HUM any", "SELF_TEST 0.5"] {
+        assert_eq!(tolerance.parse::<Tolerance>().unwrap().to_string(), tolerance);
+    }
+    for tolerance in ["SERVO", "FOO 1", "SERVO -1", "SERVO some"] {
+        assert!(tolerance.parse::<Tolerance>().is_err(), "{:?} was accepted", tolerance);
+    }
+}
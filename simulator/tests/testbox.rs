@@ -0,0 +1,61 @@
+//! Requests handled by the test box, answered as the text protocol puts them.
+
+use std::{convert::TryFrom, time::Duration};
+
+use simulator::{parser::{Request, Response, Tagged}, testbox::TestBox};
+
+fn handle(tbox: &mut TestBox, line: &str) -> String {
+    let response = match Request::try_from(line.as_bytes()) {
+        Ok(request) => tbox.handle(request, Duration::ZERO),
+        Err(e) => Response::Error(e),
+    };
+    String::from_utf8(Tagged { tag: None, inner: response }.into()).unwrap()
+}
+
+#[test]
+fn batch_set_is_applied_entirely_or_not_at_all() {
+    let mut tbox = TestBox::new(0);
+
+    for (line, expected) in [
+        ("SET RED_LED=10,GREEN_LED=1023\n", "OK 10,1023\r\n"),
+        // Out of range LED and servo values are clamped, like single SETs
+        ("SET YELLOW_LED=2000,SERVO=-5\n", "OK 1023,0\r\n"),
+        // SELF_TEST takes 0 or 1 only, so nothing is set
+        ("SET RED_LED=20,SERVO=45,GREEN_LED=0,SELF_TEST=2\n", "ERR BAD_VALUE\r\n"),
+        ("SET SERVO=45,TEMP_AND_HUM=1\n", "ERR BAD_NOUN\r\n"),
+        ("GET RED_LED,YELLOW_LED,GREEN_LED,SERVO,SELF_TEST\n", "OK 10,1023,1023,0,INACTIVE 0\r\n"),
+        ("SET RED_LED=1,RED_LED=2\n", "ERR BAD_NOUN\r\n"),
+        ("GET RED_LED\n", "OK 10\r\n"),
+    ] {
+        assert_eq!(handle(&mut tbox, line), expected, "answer to {:?}", line);
+    }
+}
+
+#[test]
+fn batch_get_answers_in_request_order() {
+    let mut tbox = TestBox::new(0);
+    tbox.set_sensor(Some((21.5, 40.0)));
+    tbox.tick(Duration::ZERO);
+
+    assert_eq!(handle(&mut tbox, "GET TEMP_AND_HUM,SERVO,RED_LED\n"), "OK OK 21.50 40.00,90,0\r\n");
+    assert_eq!(handle(&mut tbox, "GET RED_LED,\n"), "ERR BAD_NOUN\r\n");
+}
+
+#[test]
+fn introspection_describes_the_nouns() {
+    let mut tbox = TestBox::new(0);
+
+    for (line, expected) in [
+        ("HELP\n", "OK ID,GET,SET,FRAMING,HELP,LIST,DESCRIBE,VERSION,LEASE\r\n"),
+        ("LIST\n", "OK RED_LED,YELLOW_LED,GREEN_LED,SERVO,TEMP_AND_HUM,SELF_TEST\r\n"),
+        ("DESCRIBE RED_LED\n", "OK INT RW 0 1023 0\r\n"),
+        ("DESCRIBE SERVO\n", "OK INT RW 0 180 90\r\n"),
+        ("DESCRIBE TEMP_AND_HUM\n", "OK SENSOR R\r\n"),
+        ("DESCRIBE SELF_TEST\n", "OK BOOL RW 0 1 0\r\n"),
+        ("DESCRIBE FOO\n", "ERR BAD_NOUN\r\n"),
+        ("DESCRIBE\n", "ERR BAD_NOUN\r\n"),
+        ("VERSION\n", &format!("OK 1 SIMULATOR-{}\r\n", env!("CARGO_PKG_VERSION"))),
+    ] {
+        assert_eq!(handle(&mut tbox, line), expected, "answer to {:?}", line);
+    }
+}
@@ -0,0 +1,40 @@
+//! Bytes travelling to the board at the serial baud rate, into its RX FIFO.
+
+use std::time::Duration;
+
+use simulator::uart::{self, Uart};
+
+fn ms(ms: u64) -> Duration {
+    Duration::from_millis(ms)
+}
+
+fn read_all(uart: &mut Uart) -> usize {
+    std::iter::from_fn(|| uart.read()).count()
+}
+
+#[test]
+fn bytes_arrive_at_line_rate_from_when_they_are_sent() {
+    let byte_time = uart::byte_time(uart::BAUD_RATE);
+    let mut uart = Uart::new(256, uart::BAUD_RATE);
+    uart.advance(ms(0));
+
+    // A burst sent 1 ms before a loop iteration: the wire was idle until then
+    uart.send(&[b'X'; 300], ms(19));
+    assert_eq!(uart.advance(ms(20)), 0);
+    assert_eq!(read_all(&mut uart), 11);
+
+    // The rest arrives into a FIFO that isn't read in time
+    let done = ms(19) + byte_time*300;
+    assert_eq!(uart.advance(done - byte_time), 32);
+    assert_eq!(uart.advance(done), 1);
+    assert_eq!(uart.dropped(), 33);
+    assert_eq!(read_all(&mut uart), 256);
+
+    // Bytes sent while others are on the wire queue up behind them
+    uart.send(b"AB", done);
+    uart.send(b"C", done);
+    assert_eq!(uart.advance(done + byte_time*2), 0);
+    assert_eq!(read_all(&mut uart), 2);
+    assert_eq!(uart.advance(done + byte_time*3), 0);
+    assert_eq!(read_all(&mut uart), 1);
+}
@@ -0,0 +1,173 @@
+//! Browser build of the simulator: the test box and its protocol handling
+//! behind a line based API, for a web terminal.
+//!
+//! Time is passed in by the caller, in milliseconds, typically from
+//! `performance.now()`. Negative or non-finite times are refused. `advance`
+//! must be called again once `nextDeadline` is reached for the self test and
+//! the sensor to make progress.
+
+use std::{collections::VecDeque, time::Duration};
+
+use wasm_bindgen::prelude::*;
+
+use simulator::{
+    device::Device,
+    parser::ParseMode,
+    testbox::{TestBox, TestBoxState},
+};
+
+/// Size of the request line buffer, as on the board
+const LEN: usize = 256;
+
+/// Refuses times that are negative, not finite or too large, like the CLI does
+fn duration(ms: f64) -> Result<Duration, JsError> {
+    Duration::try_from_secs_f64(ms / 1000.0).map_err(|_| JsError::new(&format!("invalid time {} ms", ms)))
+}
+
+/// Snapshot of the test box, as handed to subscribers
+#[wasm_bindgen]
+pub struct State(TestBoxState);
+
+#[wasm_bindgen]
+impl State {
+    #[wasm_bindgen(getter, js_name = redLed)]
+    pub fn red_led(&self) -> f64 {
+        self.0.red_led.value as f64
+    }
+
+    #[wasm_bindgen(getter, js_name = yellowLed)]
+    pub fn yellow_led(&self) -> f64 {
+        self.0.yellow_led.value as f64
+    }
+
+    #[wasm_bindgen(getter, js_name = greenLed)]
+    pub fn green_led(&self) -> f64 {
+        self.0.green_led.value as f64
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn servo(&self) -> f64 {
+        self.0.servo.value as f64
+    }
+
+    #[wasm_bindgen(getter, js_name = sensorStatus)]
+    pub fn sensor_status(&self) -> String {
+        self.0.sensor.status.clone()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn temperature(&self) -> f64 {
+        self.0.sensor.temperature
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn humidity(&self) -> f64 {
+        self.0.sensor.humidity
+    }
+
+    #[wasm_bindgen(getter, js_name = selfTestActive)]
+    pub fn self_test_active(&self) -> bool {
+        self.0.self_test.active
+    }
+
+    #[wasm_bindgen(getter, js_name = selfTestProgress)]
+    pub fn self_test_progress(&self) -> f64 {
+        self.0.self_test.progress as f64
+    }
+}
+
+/// A simulated test box, talked to one line at a time
+#[wasm_bindgen(js_name = TestBox)]
+pub struct WebTestBox {
+    device: Device<LEN>,
+    /// Received bytes that don't make a complete line yet
+    partial: Vec<u8>,
+    lines: VecDeque<String>,
+    state: TestBoxState,
+    subscribers: Vec<js_sys::Function>,
+}
+
+#[wasm_bindgen(js_class = TestBox)]
+impl WebTestBox {
+    /// `seed` drives the sensor readings, random by default. `parseMode` is
+    /// `strict` (default) or `firmware`. With `loopCadence`, requests are
+    /// answered like the firmware's main loop does, at most one per 20 ms.
+    #[wasm_bindgen(constructor)]
+    pub fn new(seed: Option<f64>, parse_mode: Option<String>, loop_cadence: Option<bool>) -> Result<WebTestBox, JsError> {
+        let seed = seed.unwrap_or_else(|| js_sys::Math::random() * u32::MAX as f64) as u64;
+        let mode = match parse_mode {
+            Some(mode) => mode.parse::<ParseMode>().map_err(|e| JsError::new(&e))?,
+            None => ParseMode::Strict,
+        };
+
+        let tbox = TestBox::new(seed);
+        let state = tbox.get();
+
+        Ok(Self {
+            device: Device::new(tbox, mode, None, loop_cadence.unwrap_or(false)),
+            partial: Vec::new(),
+            lines: VecDeque::new(),
+            state,
+            subscribers: Vec::new(),
+        })
+    }
+
+    /// Sends a request line, without its line ending
+    #[wasm_bindgen(js_name = sendLine)]
+    pub fn send_line(&mut self, line: &str, now: f64) -> Result<(), JsValue> {
+        self.device.receive(format!("{}\n", line).as_bytes(), duration(now)?);
+        self.flush()
+    }
+
+    /// Next line sent by the test box, without its line ending
+    #[wasm_bindgen(js_name = receiveLine)]
+    pub fn receive_line(&mut self) -> Option<String> {
+        self.lines.pop_front()
+    }
+
+    /// Runs everything due by `now`
+    pub fn advance(&mut self, now: f64) -> Result<(), JsValue> {
+        self.device.advance(duration(now)?);
+        self.flush()
+    }
+
+    /// Time at which `advance` has work to do
+    #[wasm_bindgen(js_name = nextDeadline)]
+    pub fn next_deadline(&self) -> f64 {
+        self.device.next_deadline().as_secs_f64() * 1000.0
+    }
+
+    /// Current state of the test box
+    pub fn state(&self) -> State {
+        State(self.state.clone())
+    }
+
+    /// Calls `callback` with the current state, then on every change
+    pub fn subscribe(&mut self, callback: js_sys::Function) -> Result<(), JsValue> {
+        callback.call1(&JsValue::NULL, &self.state().into())?;
+        self.subscribers.push(callback);
+        Ok(())
+    }
+
+    /// Splits the device output into lines and notifies subscribers of state changes
+    fn flush(&mut self) -> Result<(), JsValue> {
+        while let Some(bytes) = self.device.transmit() {
+            self.partial.extend(bytes);
+
+            while let Some(end) = self.partial.iter().position(|&c| c == b'\n') {
+                let line: Vec<u8> = self.partial.drain(..=end).collect();
+                let line = line.strip_suffix(b"\r\n").or_else(|| line.strip_suffix(b"\n")).unwrap_or(&line);
+                self.lines.push_back(String::from_utf8_lossy(line).into_owned());
+            }
+        }
+
+        if let Some(state) = self.device.state() {
+            self.state = state;
+            for callback in &self.subscribers {
+                callback.call1(&JsValue::NULL, &self.state().into())?;
+            }
+        }
+
+        Ok(())
+    }
+}